@@ -0,0 +1,81 @@
+//! Closed-loop throughput of one-shot and edge-triggered epoll registrations.
+//!
+//! Every client thread opens a connection per request, as the server closes
+//! it after each response:
+//!
+//!     cargo bench --bench edge_triggered -- [SECONDS]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use http_lolo::{HttpServer, Request, ResponseWriter};
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: bench\r\n\r\n";
+
+fn serve(edge_triggered: bool) -> u16 {
+    HttpServer::set_edge_triggered(edge_triggered);
+    let server = HttpServer::new();
+    server.handle_route(
+        "/",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| w.write_string("hello")),
+    );
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    server.listen_on(&format!("127.0.0.1:{}", port));
+    port
+}
+
+/// Requests per second answered on `port` with `clients` threads.
+fn measure(port: u16, clients: usize, duration: Duration) -> f64 {
+    let done = Arc::new(AtomicU64::new(0));
+    let deadline = Instant::now() + duration;
+    let threads: Vec<_> = (0..clients)
+        .map(|_| {
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut response = Vec::with_capacity(512);
+                while Instant::now() < deadline {
+                    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+                    stream.write_all(REQUEST).unwrap();
+                    response.clear();
+                    stream.read_to_end(&mut response).unwrap();
+                    assert!(response.starts_with(b"HTTP/1.1 200"));
+                    done.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    done.load(Ordering::Relaxed) as f64 / duration.as_secs_f64()
+}
+
+fn main() {
+    let seconds = std::env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(3);
+    let duration = Duration::from_secs(seconds);
+
+    let one_shot = serve(false);
+    let edge_triggered = serve(true);
+    thread::spawn(HttpServer::run_all);
+
+    for clients in [4, 32] {
+        // warm the pools and the listen backlog up
+        measure(one_shot, clients, Duration::from_millis(200));
+        measure(edge_triggered, clients, Duration::from_millis(200));
+        for (mode, port) in [("one-shot", one_shot), ("edge-triggered", edge_triggered)] {
+            let rate = measure(port, clients, duration);
+            println!("{:>14}, {:>2} clients: {:>8.0} req/s", mode, clients, rate);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+// buffers are recycled up to these limits, anything bigger goes back to the allocator
+const MAX_POOLED: usize = 256;
+const MAX_RETAINED_CAPACITY: usize = 256 * 1024;
+pub(crate) const READ_CHUNK: usize = 4096;
+
+lazy_static! {
+    static ref POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+}
+
+/// A byte buffer borrowed from the connection buffer pool, handed back to it
+/// when dropped.
+#[derive(Debug, Default)]
+pub struct PooledBuf {
+    buf: Vec<u8>,
+}
+
+impl PooledBuf {
+    pub fn take() -> Self {
+        let buf = POOL.lock().unwrap().pop().unwrap_or_default();
+        PooledBuf { buf }
+    }
+
+    /// Detaches the underlying vector, it will not return to the pool unless
+    /// given back through `recycle`.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+
+    /// Reads from `reader` straight into the spare capacity of the buffer,
+    /// without going through an intermediate stack array.
+    pub fn read_from<R: std::io::Read>(&mut self, reader: &mut R) -> std::io::Result<usize> {
+        read_into(&mut self.buf, reader)
+    }
+}
+
+pub(crate) fn read_into<R: std::io::Read>(
+    buf: &mut Vec<u8>,
+    reader: &mut R,
+) -> std::io::Result<usize> {
+    let len = buf.len();
+    buf.resize(len + READ_CHUNK, 0);
+    let res = reader.read(&mut buf[len..]);
+    buf.truncate(len + *res.as_ref().unwrap_or(&0));
+    res
+}
+
+/// Gives a vector back to the pool.
+pub fn recycle(mut buf: Vec<u8>) {
+    if buf.capacity() == 0 || buf.capacity() > MAX_RETAINED_CAPACITY {
+        return;
+    }
+    buf.clear();
+    let mut pool = POOL.lock().unwrap();
+    if pool.len() < MAX_POOLED {
+        pool.push(buf);
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        recycle(std::mem::take(&mut self.buf));
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
@@ -0,0 +1,236 @@
+use std::io::{self, Read, Write};
+
+use flate2::read::{DeflateDecoder, MultiGzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+
+use crate::utils::encoding_qvalue;
+use crate::{Request, ResponseWriter, ServerConfig};
+
+/// Settings of `HttpServer::enable_compression`.
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    /// Bodies smaller than this are sent as they are.
+    pub min_size: usize,
+    /// Content types worth compressing, `type/*` matches a whole family.
+    pub content_types: Vec<String>,
+    /// 0 to 9 for gzip and deflate, also used as the brotli quality.
+    pub level: u32,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        CompressionOptions {
+            min_size: 1024,
+            content_types: [
+                "text/*",
+                "application/json",
+                "application/javascript",
+                "application/xml",
+                "application/wasm",
+                "image/svg+xml",
+            ]
+            .iter()
+            .map(|t| t.to_string())
+            .collect(),
+            level: 6,
+        }
+    }
+}
+
+/// Content codings the server can produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Coding {
+    #[cfg(feature = "brotli")]
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Coding {
+    // in order of preference when the client weighs them equally
+    const ALL: &'static [Coding] = &[
+        #[cfg(feature = "brotli")]
+        Coding::Brotli,
+        Coding::Gzip,
+        Coding::Deflate,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            #[cfg(feature = "brotli")]
+            Coding::Brotli => "br",
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<Coding> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            #[cfg(feature = "brotli")]
+            "br" => Some(Coding::Brotli),
+            "gzip" | "x-gzip" => Some(Coding::Gzip),
+            "deflate" => Some(Coding::Deflate),
+            _ => None,
+        }
+    }
+
+    /// Codings accepted in request bodies, as listed in the `Accept-Encoding`
+    /// of a `415` response.
+    pub(crate) fn accepted() -> String {
+        Coding::ALL
+            .iter()
+            .map(|coding| coding.name())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// The coding with the highest weight in an `Accept-Encoding` header.
+    pub(crate) fn negotiate(accept_encoding: &str) -> Option<Coding> {
+        let mut best: Option<(f32, Coding)> = None;
+        for &coding in Coding::ALL {
+            let q = encoding_qvalue(accept_encoding, coding.name());
+            if q > 0.0 && best.is_none_or(|(best_q, _)| q > best_q) {
+                best = Some((q, coding));
+            }
+        }
+        best.map(|(_, coding)| coding)
+    }
+}
+
+/// Encodes a whole body. Only responses buffered in memory are compressed,
+/// event streams and file bodies are sent as they are.
+pub(crate) fn compress(coding: Coding, level: u32, data: &[u8]) -> io::Result<Vec<u8>> {
+    let level = level.min(9);
+    match coding {
+        #[cfg(feature = "brotli")]
+        Coding::Brotli => {
+            let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, level, 22);
+            encoder.write_all(data)?;
+            Ok(encoder.into_inner())
+        }
+        Coding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(level));
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Coding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// Picks the coding of a response whose body is in memory and tags it
+/// accordingly. The body itself is only encoded by `encode`, once the
+/// preconditions have been evaluated against the tagged response.
+pub(crate) fn negotiate(req: &Request, w: &mut ResponseWriter, config: &ServerConfig) -> Option<Coding> {
+    let options = config.compression.as_ref()?;
+    if w.status_code.unwrap_or(200) != 200
+        || w.file_len().is_some()
+        || w.headers.contains_key("Content-Encoding")
+        || w.body.len() < options.min_size
+        || !content_type_allowed(w, options)
+        || header_has(w, "Cache-Control", "no-transform")
+    {
+        return None;
+    }
+
+    // the representation depends on Accept-Encoding even when it is sent as is
+    if !header_has(w, "Vary", "Accept-Encoding") && !header_has(w, "Vary", "*") {
+        match w.headers.get_mut("Vary").and_then(|values| values.first_mut()) {
+            Some(vary) => vary.push_str(", Accept-Encoding"),
+            None => {
+                w.headers
+                    .insert("Vary".to_string(), vec!["Accept-Encoding".to_string()]);
+            }
+        }
+    }
+
+    let coding = Coding::negotiate(req.header("Accept-Encoding")?)?;
+    w.headers
+        .insert("Content-Encoding".to_string(), vec![coding.name().to_string()]);
+    // a strong tag must differ between the encoded and the original bytes
+    if let Some(etag) = w.headers.get_mut("ETag").and_then(|values| values.first_mut()) {
+        if !etag.starts_with("W/") && etag.ends_with('"') {
+            etag.insert_str(etag.len() - 1, &format!("-{}", coding.name()));
+        }
+    }
+    Some(coding)
+}
+
+/// Replaces the body with its encoded form.
+pub(crate) fn encode(w: &mut ResponseWriter, coding: Coding, config: &ServerConfig) {
+    let level = config
+        .compression
+        .as_ref()
+        .map(|options| options.level)
+        .unwrap_or(6);
+    match compress(coding, level, &w.body) {
+        Ok(encoded) => {
+            w.remove_header("Content-Length");
+            w.replace_body(&encoded);
+        }
+        Err(e) => {
+            // unreachable in practice, the encoders write to memory
+            eprintln!("couldn't compress response {}: {}", w.event_id, e);
+            w.headers.remove("Content-Encoding");
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum DecodeError {
+    TooLarge,
+    Invalid,
+}
+
+/// Decodes `data` without producing more than `limit` bytes, so that a
+/// small compressed body cannot expand into an arbitrary amount of memory.
+pub(crate) fn decode(coding: Coding, data: &[u8], limit: usize) -> Result<Vec<u8>, DecodeError> {
+    let decoded = match coding {
+        #[cfg(feature = "brotli")]
+        Coding::Brotli => read_limited(brotli::Decompressor::new(data, 4096), limit),
+        Coding::Gzip => read_limited(MultiGzDecoder::new(data), limit),
+        // "deflate" is meant to be zlib-wrapped, some clients send raw deflate
+        Coding::Deflate => read_limited(ZlibDecoder::new(data), limit)
+            .or_else(|_| read_limited(DeflateDecoder::new(data), limit)),
+    };
+    match decoded {
+        Ok(decoded) if decoded.len() > limit => Err(DecodeError::TooLarge),
+        Ok(decoded) => Ok(decoded),
+        Err(_) => Err(DecodeError::Invalid),
+    }
+}
+
+fn read_limited<R: Read>(reader: R, limit: usize) -> io::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    reader
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+fn content_type_allowed(w: &ResponseWriter, options: &CompressionOptions) -> bool {
+    let content_type = match w.headers.get("Content-Type").and_then(|values| values.first()) {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    options.content_types.iter().any(|allowed| match allowed.strip_suffix("/*") {
+        Some(family) => mime
+            .split_once('/')
+            .is_some_and(|(t, _)| t.eq_ignore_ascii_case(family)),
+        None => mime.eq_ignore_ascii_case(allowed),
+    })
+}
+
+fn header_has(w: &ResponseWriter, name: &str, token: &str) -> bool {
+    w.headers.get(name).is_some_and(|values| {
+        values
+            .iter()
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    })
+}
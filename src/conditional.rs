@@ -0,0 +1,186 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::utils::{fmt_http_date, parse_http_date};
+use crate::{HttpStatus, Request, ResponseWriter, ServerConfig};
+
+// headers a 304 keeps, the ones describing the representation are dropped
+const NOT_MODIFIED_HEADERS: [&str; 7] = [
+    "ETag",
+    "Last-Modified",
+    "Cache-Control",
+    "Content-Location",
+    "Expires",
+    "Vary",
+    "Set-Cookie",
+];
+
+/// Evaluates the preconditions of a `GET` or `HEAD` request against the
+/// validators of a `200 OK` response, answering `304 Not Modified` or
+/// `412 Precondition Failed` instead when they say so.
+///
+/// Other methods are left alone: their handler already made its change, it
+/// checks the preconditions beforehand with `Request::preconditions_hold`.
+pub(crate) fn evaluate(req: &Request, w: &mut ResponseWriter) {
+    if w.status_code.unwrap_or(200) != 200 || !(req.method == "GET" || req.method == "HEAD") {
+        return;
+    }
+    let etag = header(w, "ETag").map(str::to_string);
+    let last_modified = header(w, "Last-Modified").and_then(parse_http_date);
+
+    // RFC 9110 section 13.2.2
+    if !state_matches(req, etag.as_deref(), last_modified) {
+        return precondition_failed(w);
+    }
+    if let Some(if_none_match) = req.header("If-None-Match") {
+        if etag_list_matches(if_none_match, etag.as_deref(), false) {
+            not_modified(w);
+        }
+    } else {
+        let since = req.header("If-Modified-Since").and_then(parse_http_date);
+        if let (Some(since), Some(modified)) = (since, last_modified) {
+            if modified <= since {
+                not_modified(w);
+            }
+        }
+    }
+}
+
+/// Whether the preconditions of a request changing a resource hold for its
+/// current validators, see `Request::preconditions_hold`.
+pub(crate) fn preconditions_hold(
+    req: &Request,
+    etag: Option<&str>,
+    last_modified: Option<SystemTime>,
+) -> bool {
+    state_matches(req, etag, last_modified)
+        && !req
+            .header("If-None-Match")
+            .is_some_and(|if_none_match| etag_list_matches(if_none_match, etag, false))
+}
+
+/// `If-Match`, or `If-Unmodified-Since` without it.
+fn state_matches(req: &Request, etag: Option<&str>, last_modified: Option<SystemTime>) -> bool {
+    if let Some(if_match) = req.header("If-Match") {
+        return etag_list_matches(if_match, etag, true);
+    }
+    match req.header("If-Unmodified-Since").and_then(parse_http_date) {
+        // HTTP dates are to the second
+        Some(since) => last_modified.is_none_or(|modified| secs(modified) <= secs(since)),
+        None => true,
+    }
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Adds `Last-Modified` and `ETag` to a `200 OK` response when they can be
+/// derived from the body.
+pub(crate) fn add_validators(w: &mut ResponseWriter, config: &ServerConfig) {
+    if w.status_code.unwrap_or(200) != 200 {
+        return;
+    }
+    if let Some(modified) = w.file_modified() {
+        if !w.headers.contains_key("Last-Modified") {
+            w.headers
+                .insert("Last-Modified".to_string(), vec![fmt_http_date(modified)]);
+        }
+        if !w.headers.contains_key("ETag") {
+            let len = w.file_len().unwrap_or(0);
+            w.headers.insert(
+                "ETag".to_string(),
+                vec![format!("\"{:x}-{:x}\"", secs(modified), len)],
+            );
+        }
+    } else if config.body_etags && w.file_len().is_none() && !w.headers.contains_key("ETag") {
+        w.headers
+            .insert("ETag".to_string(), vec![format!("\"{:016x}\"", fnv1a(&w.body))]);
+    }
+}
+
+/// Whether `etag` is in a `If-Match` or `If-None-Match` list, with the strong
+/// comparison for the former and the weak one for the latter.
+fn etag_list_matches(list: &str, etag: Option<&str>, strong: bool) -> bool {
+    let etag = match etag {
+        Some(etag) => etag,
+        // `*` matches any current representation, there is always one here
+        None => return list.trim() == "*",
+    };
+    if list.trim() == "*" {
+        return true;
+    }
+    let (etag_weak, etag_opaque) = split_etag(etag);
+    if strong && etag_weak {
+        return false;
+    }
+    parse_etags(list).into_iter().any(|(weak, opaque)| {
+        opaque == etag_opaque && !(strong && weak)
+    })
+}
+
+fn split_etag(etag: &str) -> (bool, &str) {
+    match etag.strip_prefix("W/") {
+        Some(opaque) => (true, opaque),
+        None => (false, etag),
+    }
+}
+
+/// Splits a list of entity tags, which may contain commas inside quotes.
+fn parse_etags(list: &str) -> Vec<(bool, &str)> {
+    let mut etags = Vec::new();
+    let mut rest = list;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_ascii_whitespace());
+        if rest.is_empty() {
+            break;
+        }
+        let (weak, tag) = split_etag(rest);
+        if !tag.starts_with('"') {
+            // not an entity tag, skip to the next element
+            match rest.find(',') {
+                Some(i) => rest = &rest[i..],
+                None => break,
+            }
+            continue;
+        }
+        match tag[1..].find('"') {
+            Some(end) => {
+                etags.push((weak, &tag[..end + 2]));
+                rest = &tag[end + 2..];
+            }
+            None => break,
+        }
+    }
+    etags
+}
+
+fn not_modified(w: &mut ResponseWriter) {
+    w.headers
+        .retain(|name, _| NOT_MODIFIED_HEADERS.iter().any(|kept| kept.eq_ignore_ascii_case(name)));
+    w.set_status(HttpStatus::NotModified.code());
+    w.replace_body(b"");
+}
+
+fn precondition_failed(w: &mut ResponseWriter) {
+    w.headers.clear();
+    w.set_status(HttpStatus::PreconditionFailed.code());
+    w.replace_body(HttpStatus::PreconditionFailed.reason_phrase().as_bytes());
+}
+
+fn header<'a>(w: &'a ResponseWriter, name: &str) -> Option<&'a str> {
+    w.headers
+        .get(name)
+        .and_then(|values| values.first())
+        .map(|value| value.as_str())
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
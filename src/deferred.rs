@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+use crate::{hub, timer, wake_loop, EventId, HttpServer, HttpStatus, ResponseWriter, TimerId, WRITE_CTX};
+
+lazy_static! {
+    static ref DEFERRED: Mutex<HashMap<EventId, TimerId>> = Mutex::new(HashMap::new());
+    static ref COMPLETED: Mutex<Vec<EventId>> = Mutex::new(Vec::new());
+    static ref EXPIRED: Mutex<Vec<EventId>> = Mutex::new(Vec::new());
+}
+
+/// A response parked by `ResponseWriter::defer`.
+///
+/// The handle is `Send`, so it can be moved to another thread and completed
+/// once the result is known. If the deadline given to `defer` passes first,
+/// the server answers `503 Service Unavailable` and the completion is ignored.
+#[derive(Debug)]
+pub struct DeferredResponse {
+    writer: ResponseWriter,
+}
+
+impl DeferredResponse {
+    pub(crate) fn new(writer: ResponseWriter, timeout: Duration) -> Self {
+        let event_id = writer.event_id;
+        let mut deferred = DEFERRED.lock().unwrap();
+        let timer_id = HttpServer::set_timeout(timeout, move || {
+            if DEFERRED.lock().unwrap().remove(&event_id).is_some() {
+                EXPIRED.lock().unwrap().push(event_id);
+                // a long-poll on a topic nothing was published to
+                hub::connection_closed(event_id);
+            }
+        });
+        deferred.insert(event_id, timer_id);
+        DeferredResponse { writer }
+    }
+
+    /// Writes the response with `f` and hands it back to the event loop. If
+    /// `f` writes nothing the client gets a `500`.
+    ///
+    /// Returns `false` when the request already timed out.
+    pub fn complete<F: FnOnce(&mut ResponseWriter)>(mut self, f: F) -> bool {
+        let event_id = self.writer.event_id;
+        match DEFERRED.lock().unwrap().remove(&event_id) {
+            Some(timer_id) => timer::cancel(timer_id),
+            None => return false,
+        };
+        // only what `f` writes is the response, not what the handler wrote
+        // through its own writer
+        WRITE_CTX.lock().unwrap().remove(&event_id);
+        f(&mut self.writer);
+        if !WRITE_CTX.lock().unwrap().contains_key(&event_id) {
+            self.writer.write_status(HttpStatus::InternalServerError);
+        }
+        COMPLETED.lock().unwrap().push(event_id);
+        wake_loop();
+        true
+    }
+
+    pub(crate) fn event_id(&self) -> EventId {
+        self.writer.event_id
+    }
+
+    pub fn is_expired(&self) -> bool {
+        !DEFERRED.lock().unwrap().contains_key(&self.writer.event_id)
+    }
+}
+
+pub(crate) fn take_completed() -> Vec<EventId> {
+    std::mem::take(&mut *COMPLETED.lock().unwrap())
+}
+
+pub(crate) fn take_expired() -> Vec<EventId> {
+    std::mem::take(&mut *EXPIRED.lock().unwrap())
+}
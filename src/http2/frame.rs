@@ -0,0 +1,87 @@
+//! Frame layout (RFC 9113, section 4).
+
+pub(crate) const HEADER_LEN: usize = 9;
+
+pub(crate) const DATA: u8 = 0x0;
+pub(crate) const HEADERS: u8 = 0x1;
+pub(crate) const PRIORITY: u8 = 0x2;
+pub(crate) const RST_STREAM: u8 = 0x3;
+pub(crate) const SETTINGS: u8 = 0x4;
+pub(crate) const PUSH_PROMISE: u8 = 0x5;
+pub(crate) const PING: u8 = 0x6;
+pub(crate) const GOAWAY: u8 = 0x7;
+pub(crate) const WINDOW_UPDATE: u8 = 0x8;
+pub(crate) const CONTINUATION: u8 = 0x9;
+
+pub(crate) const END_STREAM: u8 = 0x1;
+pub(crate) const ACK: u8 = 0x1;
+pub(crate) const END_HEADERS: u8 = 0x4;
+pub(crate) const PADDED: u8 = 0x8;
+pub(crate) const PRIORITY_FLAG: u8 = 0x20;
+
+pub(crate) const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+pub(crate) const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+pub(crate) const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+pub(crate) const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+pub(crate) const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+pub(crate) const NO_ERROR: u32 = 0x0;
+pub(crate) const PROTOCOL_ERROR: u32 = 0x1;
+pub(crate) const INTERNAL_ERROR: u32 = 0x2;
+pub(crate) const FLOW_CONTROL_ERROR: u32 = 0x3;
+pub(crate) const STREAM_CLOSED: u32 = 0x5;
+pub(crate) const FRAME_SIZE_ERROR: u32 = 0x6;
+pub(crate) const REFUSED_STREAM: u32 = 0x7;
+pub(crate) const COMPRESSION_ERROR: u32 = 0x9;
+
+// frame payloads and flow-control windows before SETTINGS say otherwise
+pub(crate) const DEFAULT_MAX_FRAME_SIZE: usize = 16_384;
+pub(crate) const DEFAULT_WINDOW: i64 = 65_535;
+pub(crate) const MAX_WINDOW: i64 = (1 << 31) - 1;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FrameHeader {
+    pub(crate) len: usize,
+    pub(crate) kind: u8,
+    pub(crate) flags: u8,
+    pub(crate) stream_id: u32,
+}
+
+impl FrameHeader {
+    /// Reads the header at the start of `buf`, which holds at least
+    /// `HEADER_LEN` bytes.
+    pub(crate) fn parse(buf: &[u8]) -> Self {
+        FrameHeader {
+            len: (buf[0] as usize) << 16 | (buf[1] as usize) << 8 | buf[2] as usize,
+            kind: buf[3],
+            flags: buf[4],
+            stream_id: read_u32(&buf[5..9]) & 0x7fff_ffff,
+        }
+    }
+}
+
+pub(crate) fn write_header(out: &mut Vec<u8>, len: usize, kind: u8, flags: u8, stream_id: u32) {
+    out.extend_from_slice(&(len as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&stream_id.to_be_bytes());
+}
+
+pub(crate) fn write_frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    write_header(out, payload.len(), kind, flags, stream_id);
+    out.extend_from_slice(payload);
+}
+
+pub(crate) fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+/// Strips the padding of a `PADDED` frame, `None` if it is longer than the
+/// payload.
+pub(crate) fn unpad(payload: &[u8], flags: u8) -> Option<&[u8]> {
+    if flags & PADDED == 0 {
+        return Some(payload);
+    }
+    let (&pad, rest) = payload.split_first()?;
+    rest.get(..rest.len().checked_sub(pad as usize)?)
+}
@@ -0,0 +1,451 @@
+//! HPACK header compression (RFC 7541).
+//!
+//! Responses are encoded without the dynamic table: fields are indexed from
+//! the static table or sent as literals that are never added to it, so the
+//! peer's table size has no effect on the encoder.
+
+use std::collections::VecDeque;
+
+use lazy_static::lazy_static;
+
+// the size of the decoder's table, never changed in our settings
+const DEFAULT_TABLE_SIZE: usize = 4096;
+// overhead counted for each entry of the dynamic table, and for each field
+// of a header list
+const ENTRY_OVERHEAD: usize = 32;
+// decoded size of one header block, advertised in our SETTINGS; indexed
+// fields would otherwise let a small block expand without bound
+pub(crate) const MAX_HEADER_LIST_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct HpackError;
+
+static STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// (code, length in bits) of each byte, then of EOS
+static HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28),
+    (0xfffffe4, 28), (0xfffffe5, 28), (0xfffffe6, 28), (0xfffffe7, 28),
+    (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28),
+    (0xfffffed, 28), (0xfffffee, 28), (0xfffffef, 28), (0xffffff0, 28),
+    (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28),
+    (0xffffff8, 28), (0xffffff9, 28), (0xffffffa, 28), (0xffffffb, 28),
+    (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11),
+    (0x3fa, 10), (0x3fb, 10), (0xf9, 8), (0x7fb, 11),
+    (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6),
+    (0x1a, 6), (0x1b, 6), (0x1c, 6), (0x1d, 6),
+    (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10),
+    (0x1ffa, 13), (0x21, 6), (0x5d, 7), (0x5e, 7),
+    (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7),
+    (0x67, 7), (0x68, 7), (0x69, 7), (0x6a, 7),
+    (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7),
+    (0xfc, 8), (0x73, 7), (0xfd, 8), (0x1ffb, 13),
+    (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5),
+    (0x24, 6), (0x5, 5), (0x25, 6), (0x26, 6),
+    (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5),
+    (0x2b, 6), (0x76, 7), (0x2c, 6), (0x8, 5),
+    (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15),
+    (0x7fc, 11), (0x3ffd, 14), (0x1ffd, 13), (0xffffffc, 28),
+    (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23),
+    (0x3fffd6, 22), (0x7fffda, 23), (0x7fffdb, 23), (0x7fffdc, 23),
+    (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23),
+    (0xffffee, 24), (0x7fffe1, 23), (0x7fffe2, 23), (0x7fffe3, 23),
+    (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24),
+    (0x3fffda, 22), (0x1fffdd, 21), (0xfffe9, 20), (0x3fffdb, 22),
+    (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24),
+    (0x1fffdf, 21), (0x3fffdf, 22), (0x7fffeb, 23), (0x7fffec, 23),
+    (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23),
+    (0xfffea, 20), (0x3fffe2, 22), (0x3fffe3, 22), (0x3fffe4, 22),
+    (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19),
+    (0x3fffe7, 22), (0x7ffff2, 23), (0x3fffe8, 22), (0x1ffffec, 25),
+    (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25),
+    (0x7fff2, 19), (0x1fffe3, 21), (0x3ffffe6, 26), (0x7ffffe0, 27),
+    (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26),
+    (0xffffffd, 28), (0x7ffffe3, 27), (0x7ffffe4, 27), (0x7ffffe5, 27),
+    (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23),
+    (0x3fffea, 22), (0x3fffeb, 22), (0x1ffffee, 25), (0x1ffffef, 25),
+    (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26),
+    (0x7ffffe7, 27), (0x7ffffe8, 27), (0x7ffffe9, 27), (0x7ffffea, 27),
+    (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+
+const EOS: usize = 256;
+
+lazy_static! {
+    // binary tree walked bit by bit: children are node indexes, leaves hold
+    // `-(symbol + 1)` and missing branches 0
+    static ref HUFFMAN_TREE: Vec<[i32; 2]> = {
+        let mut tree = vec![[0i32; 2]];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for i in (0..len).rev() {
+                let bit = ((code >> i) & 1) as usize;
+                if i == 0 {
+                    tree[node][bit] = -(symbol as i32 + 1);
+                } else {
+                    if tree[node][bit] == 0 {
+                        tree.push([0; 2]);
+                        tree[node][bit] = tree.len() as i32 - 1;
+                    }
+                    node = tree[node][bit] as usize;
+                }
+            }
+        }
+        tree
+    };
+}
+
+struct Entry {
+    name: String,
+    value: String,
+    // from the encoded lengths, which lossy conversion may change
+    size: usize,
+}
+
+/// Decodes the header blocks of one connection, keeping the dynamic table
+/// between them.
+pub(crate) struct Decoder {
+    // newest entry first
+    table: VecDeque<Entry>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new() -> Self {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+
+    pub(crate) fn decode(&mut self, mut block: &[u8]) -> Result<Vec<(String, String)>, HpackError> {
+        let mut fields = Vec::new();
+        let mut list_size = 0;
+        // size updates are only allowed before the first field
+        let mut first = true;
+        while let Some(&byte) = block.first() {
+            if byte & 0x80 != 0 {
+                let index = decode_int(&mut block, 7)?;
+                fields.push(self.get(index)?);
+            } else if byte & 0xc0 == 0x40 {
+                let field = self.literal(&mut block, 6)?;
+                let size = field.2;
+                self.insert(Entry {
+                    name: field.0.clone(),
+                    value: field.1.clone(),
+                    size,
+                });
+                fields.push((field.0, field.1));
+            } else if byte & 0xe0 == 0x20 {
+                let size = decode_int(&mut block, 5)?;
+                // we never advertise a larger table than the default
+                if !first || size > DEFAULT_TABLE_SIZE {
+                    return Err(HpackError);
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // without indexing or never indexed, same 4-bit prefix
+                let field = self.literal(&mut block, 4)?;
+                fields.push((field.0, field.1));
+            }
+            if let Some((name, value)) = fields.last() {
+                list_size += name.len() + value.len() + ENTRY_OVERHEAD;
+                if list_size > MAX_HEADER_LIST_SIZE {
+                    return Err(HpackError);
+                }
+            }
+            first = false;
+        }
+        Ok(fields)
+    }
+
+    fn get(&self, index: usize) -> Result<(String, String), HpackError> {
+        match index {
+            0 => Err(HpackError),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.to_string(), value.to_string()))
+            }
+            _ => self
+                .table
+                .get(index - 62)
+                .map(|entry| (entry.name.clone(), entry.value.clone()))
+                .ok_or(HpackError),
+        }
+    }
+
+    /// Reads a literal field, returns it with its size in the table.
+    fn literal(&self, block: &mut &[u8], prefix: u8) -> Result<(String, String, usize), HpackError> {
+        let index = decode_int(block, prefix)?;
+        let (name, name_len) = if index == 0 {
+            let raw = decode_string(block)?;
+            (String::from_utf8_lossy(&raw).into_owned(), raw.len())
+        } else {
+            let name = self.get(index)?.0;
+            let len = name.len();
+            (name, len)
+        };
+        let raw = decode_string(block)?;
+        let value = String::from_utf8_lossy(&raw).into_owned();
+        Ok((name, value, name_len + raw.len() + ENTRY_OVERHEAD))
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        // an entry larger than the table empties it and is dropped
+        if entry.size > self.max_size {
+            self.table.clear();
+            self.size = 0;
+            return;
+        }
+        self.evict(entry.size);
+        self.size += entry.size;
+        self.table.push_front(entry);
+    }
+
+    /// Drops the oldest entries until `room` more bytes fit.
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            match self.table.pop_back() {
+                Some(entry) => self.size -= entry.size,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Appends the header block of `fields` to `out`.
+pub(crate) fn encode(fields: &[(String, String)], out: &mut Vec<u8>) {
+    for (name, value) in fields {
+        let mut name_index = 0;
+        let mut exact = 0;
+        for (i, (static_name, static_value)) in STATIC_TABLE.iter().enumerate() {
+            if static_name == name {
+                if name_index == 0 {
+                    name_index = i + 1;
+                }
+                if static_value == value {
+                    exact = i + 1;
+                    break;
+                }
+            }
+        }
+        if exact != 0 {
+            encode_int(out, exact, 7, 0x80);
+            continue;
+        }
+        // literal header field without indexing
+        encode_int(out, name_index, 4, 0x00);
+        if name_index == 0 {
+            encode_string(out, name.as_bytes());
+        }
+        encode_string(out, value.as_bytes());
+    }
+}
+
+fn decode_int(block: &mut &[u8], prefix: u8) -> Result<usize, HpackError> {
+    let (&first, rest) = block.split_first().ok_or(HpackError)?;
+    *block = rest;
+    let max = (1usize << prefix) - 1;
+    let mut value = first as usize & max;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or(HpackError)?;
+        *block = rest;
+        // anything past 28 bits is larger than the frames we accept
+        if shift > 21 {
+            return Err(HpackError);
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_int(out: &mut Vec<u8>, mut value: usize, prefix: u8, flags: u8) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        out.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn decode_string(block: &mut &[u8]) -> Result<Vec<u8>, HpackError> {
+    let huffman = block.first().ok_or(HpackError)? & 0x80 != 0;
+    let len = decode_int(block, 7)?;
+    if len > block.len() {
+        return Err(HpackError);
+    }
+    let (raw, rest) = block.split_at(len);
+    *block = rest;
+    if huffman {
+        huffman_decode(raw)
+    } else {
+        Ok(raw.to_vec())
+    }
+}
+
+fn encode_string(out: &mut Vec<u8>, data: &[u8]) {
+    let bits: usize = data
+        .iter()
+        .map(|&byte| HUFFMAN_CODES[byte as usize].1 as usize)
+        .sum();
+    let huffman_len = bits.div_ceil(8);
+    if huffman_len >= data.len() {
+        encode_int(out, data.len(), 7, 0x00);
+        out.extend_from_slice(data);
+        return;
+    }
+    encode_int(out, huffman_len, 7, 0x80);
+    let mut acc: u64 = 0;
+    let mut pending = 0;
+    for &byte in data {
+        let (code, len) = HUFFMAN_CODES[byte as usize];
+        acc = (acc << len) | code as u64;
+        pending += len as u32;
+        while pending >= 8 {
+            pending -= 8;
+            out.push((acc >> pending) as u8);
+        }
+    }
+    if pending > 0 {
+        // padded with the most significant bits of EOS, all ones
+        out.push(((acc << (8 - pending)) | (0xff >> pending)) as u8);
+    }
+}
+
+fn huffman_decode(raw: &[u8]) -> Result<Vec<u8>, HpackError> {
+    let tree = &*HUFFMAN_TREE;
+    let mut out = Vec::with_capacity(raw.len() * 8 / 5);
+    let mut node = 0;
+    // bits read since the last symbol, and whether they were all ones
+    let mut depth = 0;
+    let mut ones = true;
+    for &byte in raw {
+        for i in (0..8).rev() {
+            let bit = (byte >> i) & 1;
+            match tree[node][bit as usize] {
+                0 => return Err(HpackError),
+                next if next < 0 => {
+                    let symbol = (-next - 1) as usize;
+                    if symbol == EOS {
+                        return Err(HpackError);
+                    }
+                    out.push(symbol as u8);
+                    node = 0;
+                    depth = 0;
+                    ones = true;
+                }
+                next => {
+                    node = next as usize;
+                    depth += 1;
+                    ones &= bit == 1;
+                }
+            }
+        }
+    }
+    // the padding is a prefix of EOS shorter than a byte
+    if depth > 7 || !ones {
+        return Err(HpackError);
+    }
+    Ok(out)
+}
@@ -0,0 +1,828 @@
+//! HTTP/2 over cleartext TCP (h2c), started by a client sending the
+//! connection preface right away or asking for `Upgrade: h2c`.
+//!
+//! Each stream is turned into a `Request` and answered by the same handlers
+//! as HTTP/1 requests; the responses they write are framed here.
+
+mod frame;
+mod hpack;
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, ErrorKind};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use base64::Engine;
+use lazy_static::lazy_static;
+
+use crate::request::ConnectionInfo;
+use crate::poller::{poller, Event};
+use crate::response_writer::BodyReader;
+use crate::transport::Transport;
+use crate::websocket::has_token;
+use crate::{server_config, sse, upgrade, EventId, HttpStatus, Interest, Request, ResponseWriter};
+use crate::{ServerId, REQUEST_CTX, SERVER_ID, WRITE_CTX};
+use frame::*;
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Answer to a request asking for `Upgrade: h2c`.
+pub(crate) const SWITCHING_PROTOCOLS: &[u8] =
+    b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+
+// streams a client may have open at once, advertised in our settings
+const MAX_CONCURRENT_STREAMS: usize = 100;
+// header blocks spread over CONTINUATION frames are refused past this size
+const MAX_HEADER_BLOCK: usize = 64 * 1024;
+// bytes read per readiness event before yielding to other connections
+const READ_BUDGET: usize = 1024 * 1024;
+// unsent bytes above which response bodies wait for the socket to drain
+const HIGH_WATER: usize = 256 * 1024;
+// body bytes framed per readiness event before yielding to other connections
+const SEND_BUDGET: usize = 1024 * 1024;
+/// Body a stream may buffer when no limit is configured. Handlers only run
+/// once the whole body is in, so its window is returned as it arrives and
+/// this is what bounds it.
+pub(crate) const DEFAULT_MAX_BODY: usize = 16 * 1024 * 1024;
+
+/// The connection and stream a `Request` arrived on.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StreamRef {
+    pub(crate) conn: EventId,
+    stream_id: u32,
+}
+
+struct Stream {
+    // the request being received, until it is handed to a handler
+    fields: Vec<(String, String)>,
+    body: Vec<u8>,
+    // DATA bytes received, also counted once the body is no longer kept
+    body_len: usize,
+    remote_closed: bool,
+    started: bool,
+    send_window: i64,
+    // the response body, once its HEADERS frame is sent
+    response: Option<BodyReader>,
+}
+
+struct Connection {
+    id: EventId,
+    socket: TcpStream,
+    server_id: ServerId,
+    // handed to every stream
+    info: ConnectionInfo,
+    // requests started so far
+    requests: u64,
+    max_body: usize,
+    incoming: Vec<u8>,
+    preface_received: bool,
+    outgoing: Vec<u8>,
+    written: usize,
+    decoder: hpack::Decoder,
+    streams: BTreeMap<u32, Stream>,
+    last_stream_id: u32,
+    // stream, flags and fragment of a header block continued in
+    // CONTINUATION frames
+    continuation: Option<(u32, u8, Vec<u8>)>,
+    // from the peer's settings
+    max_frame_size: usize,
+    initial_window: i64,
+    send_window: i64,
+    // no new streams are accepted, closed once the open ones are answered
+    going_away: bool,
+    // a connection error was sent, closed once it is flushed
+    failed: bool,
+}
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<HashMap<EventId, Connection>> = Mutex::new(HashMap::new());
+    // responses written by handlers, waiting for the loop to frame them
+    static ref RESPONSES: Mutex<HashMap<EventId, Vec<(u32, ResponseWriter)>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Matches the start of a connection against the HTTP/2 preface: `None` if
+/// it is something else, `Some(false)` while it may still become one.
+pub(crate) fn preface(head: &[u8]) -> Option<bool> {
+    let len = head.len().min(PREFACE.len());
+    if head[..len] != PREFACE[..len] {
+        return None;
+    }
+    Some(len == PREFACE.len())
+}
+
+pub(crate) fn enabled(server_id: ServerId) -> bool {
+    !server_config(server_id).http2_disabled
+}
+
+/// Whether `request` asks to continue the connection in HTTP/2, see
+/// RFC 7540, section 3.2.
+pub(crate) fn wants_upgrade(request: &Request) -> bool {
+    request.protocol == "HTTP/1.1"
+        && request.header("HTTP2-Settings").is_some()
+        && request
+            .header("Upgrade")
+            .is_some_and(|upgrade| has_token(upgrade, "h2c"))
+        && request
+            .header("Connection")
+            .is_some_and(|connection| has_token(connection, "upgrade"))
+        && enabled(request.server_id())
+}
+
+/// Takes over the socket of a connection that sent the preface, or was
+/// answered `101` for `Upgrade: h2c`. The upgrade request becomes stream 1.
+pub(crate) fn adopt(id: EventId, request: Request) {
+    let server_id = request.server_id();
+    let info = request.connection;
+    let settings = request
+        .header("HTTP2-Settings")
+        .and_then(|settings| BASE64_URL.decode(settings.trim().trim_end_matches('=')).ok());
+    let (socket, incoming, upgraded) = match request.into_http2().and_then(|parts| {
+        poller().add(parts.0.as_raw_fd(), id as u64, Interest::Read)?;
+        Ok(parts)
+    }) {
+        Ok(parts) => parts,
+        Err(e) => {
+            eprintln!("couldn't switch connection {} to HTTP/2: {}", id, e);
+            return;
+        }
+    };
+
+    let mut conn = Connection {
+        id,
+        socket,
+        server_id,
+        info,
+        requests: 0,
+        max_body: server_config(server_id).max_body_size.unwrap_or(DEFAULT_MAX_BODY),
+        incoming,
+        preface_received: false,
+        outgoing: Vec::new(),
+        written: 0,
+        decoder: hpack::Decoder::new(),
+        streams: BTreeMap::new(),
+        last_stream_id: 0,
+        continuation: None,
+        max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        initial_window: DEFAULT_WINDOW,
+        send_window: DEFAULT_WINDOW,
+        going_away: false,
+        failed: false,
+    };
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes());
+    payload.extend_from_slice(&(MAX_CONCURRENT_STREAMS as u32).to_be_bytes());
+    payload.extend_from_slice(&SETTINGS_MAX_HEADER_LIST_SIZE.to_be_bytes());
+    payload.extend_from_slice(&(hpack::MAX_HEADER_LIST_SIZE as u32).to_be_bytes());
+    write_frame(&mut conn.outgoing, SETTINGS, 0, 0, &payload);
+
+    if let Some(mut request) = upgraded {
+        if let Some(settings) = settings.filter(|settings| settings.len().is_multiple_of(6)) {
+            if let Err(code) = conn.apply_settings(&settings) {
+                conn.fail(code);
+            }
+        }
+        conn.last_stream_id = 1;
+        let mut stream = conn.new_stream(Vec::new(), true);
+        stream.started = true;
+        conn.streams.insert(1, stream);
+        request.http2 = Some(StreamRef {
+            conn: id,
+            stream_id: 1,
+        });
+        conn.run(request, 1);
+    }
+    // frames past the preface may be held by the TLS session
+    let buffered = Transport::new(id, &conn.socket).has_buffered();
+    CONNECTIONS.lock().unwrap().insert(id, conn);
+    service(id, buffered);
+}
+
+/// Queues the response stored for `event_id` on its stream. Returns `true`,
+/// the request is done with once its response is handed over.
+pub(crate) fn respond(stream: StreamRef, event_id: EventId) -> bool {
+    let mut writer = match WRITE_CTX.lock().unwrap().remove(&event_id) {
+        Some(writer) => writer,
+        None => return true,
+    };
+    if writer.is_upgraded() {
+        // event streams and protocol switches need a connection of their own
+        sse::abandon(event_id);
+        upgrade::abandon(event_id);
+        let status = HttpStatus::HttpVersionNotSupported;
+        writer.headers.clear();
+        writer.replace_body(status.reason_phrase().as_bytes());
+        writer.set_status(status.code());
+    }
+    RESPONSES
+        .lock()
+        .unwrap()
+        .entry(stream.conn)
+        .or_default()
+        .push((stream.stream_id, writer));
+    true
+}
+
+/// Handles readiness of an HTTP/2 connection, returns `false` if the key is
+/// not one.
+pub(crate) fn dispatch(ev: &Event) -> bool {
+    let id = ev.key as EventId;
+    if !CONNECTIONS.lock().unwrap().contains_key(&id) {
+        return false;
+    }
+    service(id, ev.readable || ev.hangup || ev.error);
+    true
+}
+
+/// Frames the responses of deferred requests completed since the last
+/// iteration.
+pub(crate) fn flush_pending() {
+    let ids: Vec<EventId> = RESPONSES.lock().unwrap().keys().copied().collect();
+    for id in ids {
+        if CONNECTIONS.lock().unwrap().contains_key(&id) {
+            service(id, false);
+        } else {
+            // the connection closed while the handler was busy
+            RESPONSES.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+fn service(id: EventId, readable: bool) {
+    // taken out of the map so handlers run without the lock
+    let mut conn = match CONNECTIONS.lock().unwrap().remove(&id) {
+        Some(conn) => conn,
+        None => return,
+    };
+
+    if readable {
+        match conn.read() {
+            Ok(false) => {}
+            Ok(true) => return teardown(conn),
+            Err(ref e) if e.kind() == ErrorKind::ConnectionReset => return teardown(conn),
+            Err(e) => {
+                eprintln!("error reading HTTP/2 connection {}: {}", id, e);
+                return teardown(conn);
+            }
+        }
+    }
+    conn.parse(id);
+
+    let responses = RESPONSES.lock().unwrap().remove(&id).unwrap_or_default();
+    for (stream_id, writer) in responses {
+        conn.answer(stream_id, writer);
+    }
+
+    let mut budget = SEND_BUDGET;
+    let mut more = true;
+    // frames more once the socket took what was there, until the budget is spent
+    while more && budget >= HIGH_WATER {
+        more = conn.pump();
+        budget -= HIGH_WATER;
+        if let Err(e) = conn.write() {
+            eprintln!("error writing HTTP/2 connection {}: {}", id, e);
+            return teardown(conn);
+        }
+        if !conn.is_flushed() {
+            break;
+        }
+    }
+    let flushed = conn.is_flushed();
+    if flushed && (conn.failed || (conn.going_away && conn.streams.is_empty())) {
+        return teardown(conn);
+    }
+    let interest = if flushed && !more {
+        Interest::Read
+    } else {
+        Interest::ReadWrite
+    };
+    if let Err(e) = poller().modify(conn.socket.as_raw_fd(), id as u64, interest) {
+        eprintln!("couldn't re-arm HTTP/2 connection {}: {}", id, e);
+        return teardown(conn);
+    }
+    CONNECTIONS.lock().unwrap().insert(id, conn);
+}
+
+fn teardown(conn: Connection) {
+    let _ = poller().remove(conn.socket.as_raw_fd());
+    Transport::new(conn.id, &conn.socket).close();
+    let _ = conn.socket.shutdown(Shutdown::Both);
+}
+
+impl Connection {
+    fn new_stream(&self, fields: Vec<(String, String)>, remote_closed: bool) -> Stream {
+        Stream {
+            fields,
+            body: Vec::new(),
+            body_len: 0,
+            remote_closed,
+            started: false,
+            send_window: self.initial_window,
+            response: None,
+        }
+    }
+
+    /// Returns `true` when the peer closed the TCP connection.
+    fn read(&mut self) -> io::Result<bool> {
+        Transport::new(self.id, &self.socket).read_budgeted(&mut self.incoming, READ_BUDGET)
+    }
+
+    fn write(&mut self) -> io::Result<()> {
+        Transport::new(self.id, &self.socket).write_buffered(&mut self.outgoing, &mut self.written)
+    }
+
+    fn is_flushed(&self) -> bool {
+        Transport::new(self.id, &self.socket).is_flushed(&self.outgoing, self.written)
+    }
+
+    /// Consumes the complete frames of `incoming`.
+    fn parse(&mut self, id: EventId) {
+        let mut incoming = std::mem::take(&mut self.incoming);
+        let mut consumed = 0;
+        if !self.preface_received {
+            if incoming.len() < PREFACE.len() {
+                if !PREFACE.starts_with(&incoming) {
+                    self.fail(PROTOCOL_ERROR);
+                }
+                self.incoming = incoming;
+                return;
+            }
+            if !incoming.starts_with(PREFACE) {
+                return self.fail(PROTOCOL_ERROR);
+            }
+            consumed = PREFACE.len();
+            self.preface_received = true;
+        }
+        while !self.failed && incoming.len() - consumed >= HEADER_LEN {
+            let header = FrameHeader::parse(&incoming[consumed..]);
+            // we never advertise a larger frame size
+            if header.len > DEFAULT_MAX_FRAME_SIZE {
+                self.fail(FRAME_SIZE_ERROR);
+                break;
+            }
+            let end = consumed + HEADER_LEN + header.len;
+            if incoming.len() < end {
+                break;
+            }
+            if let Err(code) = self.handle_frame(id, header, &incoming[consumed + HEADER_LEN..end]) {
+                self.fail(code);
+            }
+            consumed = end;
+        }
+        if self.failed {
+            // nothing is read after a connection error
+            return;
+        }
+        incoming.drain(..consumed);
+        self.incoming = incoming;
+    }
+
+    /// Returns the code of a connection error, stream errors are answered
+    /// with `RST_STREAM` here.
+    fn handle_frame(&mut self, id: EventId, header: FrameHeader, payload: &[u8]) -> Result<(), u32> {
+        if let Some((stream_id, _, _)) = self.continuation {
+            if header.kind != CONTINUATION || header.stream_id != stream_id {
+                return Err(PROTOCOL_ERROR);
+            }
+        }
+        match header.kind {
+            DATA => self.handle_data(id, header, payload),
+            HEADERS => {
+                if header.stream_id == 0 || header.stream_id.is_multiple_of(2) {
+                    return Err(PROTOCOL_ERROR);
+                }
+                let mut block = unpad(payload, header.flags).ok_or(PROTOCOL_ERROR)?;
+                if header.flags & PRIORITY_FLAG != 0 {
+                    block = block.get(5..).ok_or(PROTOCOL_ERROR)?;
+                }
+                if header.flags & END_HEADERS == 0 {
+                    self.continuation = Some((header.stream_id, header.flags, block.to_vec()));
+                    return Ok(());
+                }
+                self.handle_headers(id, header.stream_id, header.flags, block)
+            }
+            CONTINUATION => {
+                let (stream_id, flags, mut block) = self.continuation.take().ok_or(PROTOCOL_ERROR)?;
+                block.extend_from_slice(payload);
+                if block.len() > MAX_HEADER_BLOCK {
+                    return Err(PROTOCOL_ERROR);
+                }
+                if header.flags & END_HEADERS == 0 {
+                    self.continuation = Some((stream_id, flags, block));
+                    return Ok(());
+                }
+                self.handle_headers(id, stream_id, flags, &block)
+            }
+            PRIORITY => {
+                if header.stream_id == 0 {
+                    return Err(PROTOCOL_ERROR);
+                }
+                if header.len != 5 {
+                    self.reset(header.stream_id, FRAME_SIZE_ERROR);
+                }
+                Ok(())
+            }
+            RST_STREAM => {
+                if header.len != 4 {
+                    return Err(FRAME_SIZE_ERROR);
+                }
+                if header.stream_id == 0 || header.stream_id > self.last_stream_id {
+                    return Err(PROTOCOL_ERROR);
+                }
+                // a deferred handler may still answer, its response is dropped
+                self.streams.remove(&header.stream_id);
+                Ok(())
+            }
+            SETTINGS => {
+                if header.stream_id != 0 {
+                    return Err(PROTOCOL_ERROR);
+                }
+                if header.flags & ACK != 0 {
+                    return if header.len == 0 { Ok(()) } else { Err(FRAME_SIZE_ERROR) };
+                }
+                if !header.len.is_multiple_of(6) {
+                    return Err(FRAME_SIZE_ERROR);
+                }
+                self.apply_settings(payload)?;
+                write_frame(&mut self.outgoing, SETTINGS, ACK, 0, &[]);
+                Ok(())
+            }
+            PUSH_PROMISE => Err(PROTOCOL_ERROR),
+            PING => {
+                if header.len != 8 {
+                    return Err(FRAME_SIZE_ERROR);
+                }
+                if header.stream_id != 0 {
+                    return Err(PROTOCOL_ERROR);
+                }
+                if header.flags & ACK == 0 {
+                    write_frame(&mut self.outgoing, PING, ACK, 0, payload);
+                }
+                Ok(())
+            }
+            GOAWAY => {
+                if header.stream_id != 0 {
+                    return Err(PROTOCOL_ERROR);
+                }
+                self.going_away = true;
+                Ok(())
+            }
+            WINDOW_UPDATE => {
+                if header.len != 4 {
+                    return Err(FRAME_SIZE_ERROR);
+                }
+                let increment = (read_u32(payload) & 0x7fff_ffff) as i64;
+                if header.stream_id == 0 {
+                    if increment == 0 {
+                        return Err(PROTOCOL_ERROR);
+                    }
+                    self.send_window += increment;
+                    if self.send_window > MAX_WINDOW {
+                        return Err(FLOW_CONTROL_ERROR);
+                    }
+                    return Ok(());
+                }
+                let stream = match self.streams.get_mut(&header.stream_id) {
+                    Some(stream) => stream,
+                    None => return Ok(()),
+                };
+                stream.send_window += increment;
+                if increment == 0 {
+                    self.reset(header.stream_id, PROTOCOL_ERROR);
+                } else if stream.send_window > MAX_WINDOW {
+                    self.reset(header.stream_id, FLOW_CONTROL_ERROR);
+                }
+                Ok(())
+            }
+            // unknown frame types are ignored
+            _ => Ok(()),
+        }
+    }
+
+    fn handle_data(&mut self, id: EventId, header: FrameHeader, payload: &[u8]) -> Result<(), u32> {
+        if header.stream_id == 0 {
+            return Err(PROTOCOL_ERROR);
+        }
+        // the whole frame counts against the window, padding included
+        if header.len > 0 {
+            write_frame(&mut self.outgoing, WINDOW_UPDATE, 0, 0, &(header.len as u32).to_be_bytes());
+        }
+        let data = unpad(payload, header.flags).ok_or(PROTOCOL_ERROR)?;
+        let end_stream = header.flags & END_STREAM != 0;
+        let max_body = self.max_body;
+        let stream = match self.streams.get_mut(&header.stream_id) {
+            Some(stream) if !stream.remote_closed => stream,
+            _ if header.stream_id > self.last_stream_id => return Err(PROTOCOL_ERROR),
+            _ => {
+                self.reset(header.stream_id, STREAM_CLOSED);
+                return Ok(());
+            }
+        };
+        stream.body_len += data.len();
+        let too_large = stream.body_len > max_body;
+        if !stream.started && !too_large {
+            stream.body.extend_from_slice(data);
+        }
+        if end_stream {
+            stream.remote_closed = true;
+        } else if header.len > 0 && !stream.started && !too_large {
+            // a refused body gets no more room, the client stops sending it
+            write_frame(
+                &mut self.outgoing,
+                WINDOW_UPDATE,
+                0,
+                header.stream_id,
+                &(header.len as u32).to_be_bytes(),
+            );
+        }
+        // an oversized body is refused without waiting for the rest
+        if !stream.started && (end_stream || too_large) {
+            self.start(id, header.stream_id);
+        }
+        Ok(())
+    }
+
+    fn handle_headers(&mut self, id: EventId, stream_id: u32, flags: u8, block: &[u8]) -> Result<(), u32> {
+        // decoded even for refused streams, to keep the table in sync
+        let fields = self.decoder.decode(block).map_err(|_| COMPRESSION_ERROR)?;
+        let end_stream = flags & END_STREAM != 0;
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            // trailers, which are dropped
+            if !end_stream || stream.remote_closed {
+                return Err(PROTOCOL_ERROR);
+            }
+            stream.remote_closed = true;
+            if !stream.started {
+                self.start(id, stream_id);
+            }
+            return Ok(());
+        }
+        if stream_id <= self.last_stream_id {
+            return Err(STREAM_CLOSED);
+        }
+        self.last_stream_id = stream_id;
+        if self.going_away {
+            return Ok(());
+        }
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS {
+            self.reset(stream_id, REFUSED_STREAM);
+            return Ok(());
+        }
+        if is_malformed(&fields) {
+            self.reset(stream_id, PROTOCOL_ERROR);
+            return Ok(());
+        }
+        let declared = fields
+            .iter()
+            .find(|(name, _)| name == "content-length")
+            .and_then(|(_, value)| value.parse::<usize>().ok());
+        let mut stream = self.new_stream(fields, end_stream);
+        let too_large = match declared {
+            Some(declared) if declared > self.max_body => {
+                stream.body_len = declared;
+                true
+            }
+            _ => false,
+        };
+        self.streams.insert(stream_id, stream);
+        if end_stream || too_large {
+            self.start(id, stream_id);
+        }
+        Ok(())
+    }
+
+    fn apply_settings(&mut self, payload: &[u8]) -> Result<(), u32> {
+        for setting in payload.chunks_exact(6) {
+            let value = read_u32(&setting[2..]);
+            match u16::from_be_bytes([setting[0], setting[1]]) {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(PROTOCOL_ERROR),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    if value as i64 > MAX_WINDOW {
+                        return Err(FLOW_CONTROL_ERROR);
+                    }
+                    let delta = value as i64 - self.initial_window;
+                    self.initial_window = value as i64;
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += delta;
+                        if stream.send_window > MAX_WINDOW {
+                            return Err(FLOW_CONTROL_ERROR);
+                        }
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(DEFAULT_MAX_FRAME_SIZE as u32..=0xff_ffff).contains(&value) {
+                        return Err(PROTOCOL_ERROR);
+                    }
+                    self.max_frame_size = value as usize;
+                }
+                // the encoder doesn't use the dynamic table and nothing is
+                // pushed, the other settings don't change what we send
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Hands the request received on `stream_id` to its handler.
+    fn start(&mut self, id: EventId, stream_id: u32) {
+        let stream = match self.streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return,
+        };
+        stream.started = true;
+        let fields = std::mem::take(&mut stream.fields);
+        let body = std::mem::take(&mut stream.body);
+        let body_len = stream.body_len;
+        let socket = match self.socket.try_clone() {
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!("couldn't start HTTP/2 stream {}: {}", stream_id, e);
+                return self.reset(stream_id, INTERNAL_ERROR);
+            }
+        };
+        let stream_ref = StreamRef {
+            conn: id,
+            stream_id,
+        };
+        let request = Request::from_http2(socket, self.server_id, stream_ref, fields, body, body_len);
+        self.run(request, stream_id);
+    }
+
+    fn run(&mut self, mut request: Request, stream_id: u32) {
+        self.requests += 1;
+        request.connection = self.info;
+        request.sequence_number = self.requests;
+        let event_id = {
+            let mut next_id = SERVER_ID.lock().unwrap();
+            *next_id += 1;
+            *next_id - 1
+        };
+        match request.handle_complete_request(event_id) {
+            Ok(true) => {}
+            // answered through `respond` once the deferred response completes
+            Ok(false) => {
+                REQUEST_CTX.lock().unwrap().insert(event_id, request);
+            }
+            Err(e) => {
+                eprintln!("error handling HTTP/2 stream {}: {}", stream_id, e);
+                self.reset(stream_id, INTERNAL_ERROR);
+            }
+        }
+    }
+
+    /// Sends the head of a response, the body follows as the windows allow.
+    fn answer(&mut self, stream_id: u32, mut writer: ResponseWriter) {
+        if !self.streams.contains_key(&stream_id) {
+            return;
+        }
+        let (status, headers, body) = writer.http2_parts();
+        let mut fields = Vec::with_capacity(headers.len() + 1);
+        fields.push((":status".to_string(), status.to_string()));
+        fields.extend(headers);
+        let mut block = Vec::new();
+        hpack::encode(&fields, &mut block);
+
+        let end_stream = body.is_done();
+        // blocks larger than a frame go on in CONTINUATION frames
+        let chunks: Vec<&[u8]> = block.chunks(self.max_frame_size).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let (kind, mut flags) = match i {
+                0 if end_stream => (HEADERS, END_STREAM),
+                0 => (HEADERS, 0),
+                _ => (CONTINUATION, 0),
+            };
+            if i + 1 == chunks.len() {
+                flags |= END_HEADERS;
+            }
+            write_frame(&mut self.outgoing, kind, flags, stream_id, chunk);
+        }
+        if end_stream {
+            self.finish(stream_id);
+        } else if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.response = Some(body);
+        }
+    }
+
+    /// Frames response bodies as far as the flow-control windows allow,
+    /// a frame per stream in turn. Returns `true` when it stopped because
+    /// too much is waiting to be written.
+    fn pump(&mut self) -> bool {
+        loop {
+            let mut progress = false;
+            let ids: Vec<u32> = self
+                .streams
+                .iter()
+                .filter(|(_, stream)| stream.response.is_some())
+                .map(|(&stream_id, _)| stream_id)
+                .collect();
+            for stream_id in ids {
+                if self.outgoing.len() - self.written >= HIGH_WATER {
+                    return true;
+                }
+                let stream = self.streams.get_mut(&stream_id).unwrap();
+                let window = stream.send_window.min(self.send_window).min(self.max_frame_size as i64);
+                let reader = stream.response.as_mut().unwrap();
+                let start = self.outgoing.len();
+                write_header(&mut self.outgoing, 0, DATA, 0, stream_id);
+                let n = match reader.read(&mut self.outgoing, window.max(0) as usize) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        eprintln!("error reading body of HTTP/2 stream {}: {}", stream_id, e);
+                        self.outgoing.truncate(start);
+                        self.reset(stream_id, INTERNAL_ERROR);
+                        continue;
+                    }
+                };
+                let end_stream = reader.is_done();
+                if n == 0 && !end_stream {
+                    self.outgoing.truncate(start);
+                    continue;
+                }
+                // the header was written before the length was known
+                self.outgoing[start..start + 3].copy_from_slice(&(n as u32).to_be_bytes()[1..]);
+                if end_stream {
+                    self.outgoing[start + 4] = END_STREAM;
+                }
+                progress = true;
+                stream.send_window -= n as i64;
+                self.send_window -= n as i64;
+                if end_stream {
+                    self.finish(stream_id);
+                }
+            }
+            if !progress {
+                return false;
+            }
+        }
+    }
+
+    /// Forgets a stream whose response is sent entirely.
+    fn finish(&mut self, stream_id: u32) {
+        if let Some(stream) = self.streams.remove(&stream_id) {
+            // the client doesn't need to send the rest of its body
+            if !stream.remote_closed {
+                write_frame(&mut self.outgoing, RST_STREAM, 0, stream_id, &NO_ERROR.to_be_bytes());
+            }
+        }
+    }
+
+    fn reset(&mut self, stream_id: u32, code: u32) {
+        self.streams.remove(&stream_id);
+        write_frame(&mut self.outgoing, RST_STREAM, 0, stream_id, &code.to_be_bytes());
+    }
+
+    /// Sends `GOAWAY` for a connection error, the connection is closed once
+    /// it is flushed.
+    fn fail(&mut self, code: u32) {
+        if self.failed {
+            return;
+        }
+        let mut payload = Vec::with_capacity(8);
+        payload.extend_from_slice(&self.last_stream_id.to_be_bytes());
+        payload.extend_from_slice(&code.to_be_bytes());
+        write_frame(&mut self.outgoing, GOAWAY, 0, 0, &payload);
+        self.failed = true;
+        self.streams.clear();
+    }
+}
+
+/// Checks the rules of RFC 9113, section 8.3.1, on request fields.
+fn is_malformed(fields: &[(String, String)]) -> bool {
+    let mut method = None;
+    let mut has_path = false;
+    let mut has_scheme = false;
+    let mut regular = false;
+    for (name, value) in fields {
+        if name.starts_with(':') {
+            // pseudo-headers come first, once each
+            if regular {
+                return true;
+            }
+            let seen = match name.as_str() {
+                ":method" => method.replace(value.as_str()).is_some(),
+                ":path" => std::mem::replace(&mut has_path, !value.is_empty()),
+                ":scheme" => std::mem::replace(&mut has_scheme, true),
+                ":authority" => false,
+                _ => return true,
+            };
+            if seen {
+                return true;
+            }
+            continue;
+        }
+        regular = true;
+        if name.bytes().any(|b| b.is_ascii_uppercase())
+            || matches!(
+                name.as_str(),
+                "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade"
+            )
+            || (name == "te" && value != "trailers")
+        {
+            return true;
+        }
+    }
+    // CONNECT is not supported, it would need the socket
+    method.is_none_or(|method| method == "CONNECT") || !has_path || !has_scheme
+}
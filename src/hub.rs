@@ -0,0 +1,234 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+
+use crate::{sse, wake_loop, websocket, DeferredResponse, EventId, EventStream, WebSocket};
+
+pub type SubscriptionId = usize;
+
+// messages waiting for one subscriber before it is evicted
+const DEFAULT_QUEUE_LIMIT: usize = 256;
+// unsent bytes a connection may hold before the hub stops feeding it
+const HIGH_WATER: usize = 64 * 1024;
+
+/// A connection receiving the messages published on a topic, see
+/// `HttpServer::subscribe`.
+#[derive(Debug)]
+pub enum Subscriber {
+    /// Sent as text messages, or binary ones when not UTF-8.
+    WebSocket(WebSocket),
+    /// Sent as the data of `message` events.
+    EventStream(EventStream),
+    /// Completed with the first message as body, then unsubscribed.
+    LongPoll(Box<DeferredResponse>),
+}
+
+impl From<WebSocket> for Subscriber {
+    fn from(ws: WebSocket) -> Self {
+        Subscriber::WebSocket(ws)
+    }
+}
+
+impl From<EventStream> for Subscriber {
+    fn from(stream: EventStream) -> Self {
+        Subscriber::EventStream(stream)
+    }
+}
+
+impl From<DeferredResponse> for Subscriber {
+    fn from(deferred: DeferredResponse) -> Self {
+        Subscriber::LongPoll(Box::new(deferred))
+    }
+}
+
+struct Subscription {
+    topic: String,
+    // taken when a long-poll is completed
+    subscriber: Option<Subscriber>,
+    queue: VecDeque<Arc<[u8]>>,
+}
+
+#[derive(Default)]
+struct Hub {
+    topics: HashMap<String, Vec<SubscriptionId>>,
+    subscriptions: HashMap<SubscriptionId, Subscription>,
+    // subscriptions with queued messages
+    pending: Vec<SubscriptionId>,
+    next_id: SubscriptionId,
+    queue_limit: Option<usize>,
+}
+
+lazy_static! {
+    static ref HUB: Mutex<Hub> = Mutex::new(Hub::default());
+    // connections gone since the last `deliver`, queued rather than removed
+    // right away since they are reported with the stream locks held
+    static ref CLOSED: Mutex<Vec<EventId>> = Mutex::new(Vec::new());
+}
+
+pub(crate) fn subscribe(topic: &str, subscriber: Subscriber) -> SubscriptionId {
+    let mut hub = HUB.lock().unwrap();
+    hub.next_id += 1;
+    let id = hub.next_id;
+    hub.topics.entry(topic.to_string()).or_default().push(id);
+    hub.subscriptions.insert(
+        id,
+        Subscription {
+            topic: topic.to_string(),
+            subscriber: Some(subscriber),
+            queue: VecDeque::new(),
+        },
+    );
+    id
+}
+
+pub(crate) fn unsubscribe(id: SubscriptionId) -> bool {
+    HUB.lock().unwrap().remove(id).is_some()
+}
+
+/// Queues `data` for every subscriber of `topic`, returns how many there
+/// are. Subscribers whose queue is full are evicted.
+pub(crate) fn publish(topic: &str, data: &[u8]) -> usize {
+    let mut hub = HUB.lock().unwrap();
+    let ids = match hub.topics.get(topic) {
+        Some(ids) => ids.clone(),
+        None => return 0,
+    };
+    let limit = hub.queue_limit.unwrap_or(DEFAULT_QUEUE_LIMIT);
+    let data: Arc<[u8]> = Arc::from(data);
+    let mut queued = 0;
+    for id in ids {
+        let subscription = match hub.subscriptions.get_mut(&id) {
+            Some(subscription) => subscription,
+            None => continue,
+        };
+        if subscription.queue.len() >= limit {
+            evict(&mut hub, id);
+            continue;
+        }
+        subscription.queue.push_back(data.clone());
+        hub.pending.push(id);
+        queued += 1;
+    }
+    drop(hub);
+    if queued > 0 {
+        wake_loop();
+    }
+    queued
+}
+
+pub(crate) fn set_queue_limit(limit: usize) {
+    HUB.lock().unwrap().queue_limit = Some(limit);
+}
+
+/// Drops the subscriptions of a WebSocket or event stream connection once
+/// it is closed, or of a long-poll once it expired.
+pub(crate) fn connection_closed(id: EventId) {
+    CLOSED.lock().unwrap().push(id);
+}
+
+/// Hands queued messages over to the connections that keep up with them.
+pub(crate) fn deliver() {
+    let closed = std::mem::take(&mut *CLOSED.lock().unwrap());
+    let mut hub = HUB.lock().unwrap();
+    if !closed.is_empty() {
+        let gone: Vec<SubscriptionId> = hub
+            .subscriptions
+            .iter()
+            .filter(|(_, subscription)| match &subscription.subscriber {
+                Some(Subscriber::WebSocket(ws)) => closed.contains(&ws.id()),
+                Some(Subscriber::EventStream(stream)) => closed.contains(&stream.id()),
+                Some(Subscriber::LongPoll(deferred)) => closed.contains(&deferred.event_id()),
+                None => false,
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        for id in gone {
+            hub.remove(id);
+        }
+    }
+    let mut pending = std::mem::take(&mut hub.pending);
+    pending.sort_unstable();
+    pending.dedup();
+    for id in pending {
+        let subscription = match hub.subscriptions.get_mut(&id) {
+            Some(subscription) => subscription,
+            None => continue,
+        };
+        match drain(subscription) {
+            Some(true) => {}
+            // retried on the next iteration, once the socket made progress
+            Some(false) => hub.pending.push(id),
+            None => {
+                hub.remove(id);
+            }
+        }
+    }
+}
+
+/// Returns `Some(true)` once the queue is empty, `Some(false)` when the
+/// connection is too far behind, `None` when the subscriber is gone.
+fn drain(subscription: &mut Subscription) -> Option<bool> {
+    while let Some(data) = subscription.queue.front() {
+        let sent = match subscription.subscriber.as_ref()? {
+            Subscriber::WebSocket(ws) => {
+                if websocket::backlog(ws.id())? >= HIGH_WATER {
+                    return Some(false);
+                }
+                match std::str::from_utf8(data) {
+                    Ok(text) => ws.send_text(text),
+                    Err(_) => ws.send_binary(data),
+                }
+            }
+            Subscriber::EventStream(stream) => {
+                if sse::backlog(stream.id())? >= HIGH_WATER {
+                    return Some(false);
+                }
+                stream.send_data(&String::from_utf8_lossy(data))
+            }
+            Subscriber::LongPoll(_) => {
+                let deferred = match subscription.subscriber.take() {
+                    Some(Subscriber::LongPoll(deferred)) => deferred,
+                    _ => unreachable!(),
+                };
+                let body = data.to_vec();
+                deferred.complete(|w| {
+                    w.body = body;
+                    w.write_string("");
+                });
+                // one message per long-poll, the client subscribes again
+                return None;
+            }
+        };
+        if !sent {
+            return None;
+        }
+        subscription.queue.pop_front();
+    }
+    Some(true)
+}
+
+/// Drops a subscriber that cannot keep up, closing its connection.
+fn evict(hub: &mut Hub, id: SubscriptionId) {
+    if let Some(subscription) = hub.remove(id) {
+        match subscription.subscriber {
+            Some(Subscriber::WebSocket(ws)) => websocket::abort(ws.id()),
+            Some(Subscriber::EventStream(stream)) => sse::abort(stream.id()),
+            // a long-poll is completed by its first message, it never lags
+            _ => {}
+        }
+    }
+}
+
+impl Hub {
+    fn remove(&mut self, id: SubscriptionId) -> Option<Subscription> {
+        let subscription = self.subscriptions.remove(&id)?;
+        if let Some(ids) = self.topics.get_mut(&subscription.topic) {
+            ids.retain(|&other| other != id);
+            if ids.is_empty() {
+                self.topics.remove(&subscription.topic);
+            }
+        }
+        Some(subscription)
+    }
+}
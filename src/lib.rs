@@ -1,6 +1,18 @@
+macro_rules! syscall {
+    ($fn: ident ( $($arg: expr),* $(,)* ) ) => {{
+        let res = unsafe { libc::$fn($($arg, )*) };
+        if res == -1 {
+            Err(std::io::Error::last_os_error())
+        } else {
+            Ok(res)
+        }
+    }};
+}
+
 use std::collections::HashMap;
 use std::net::TcpListener;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 mod http_status;
 pub use http_status::*;
@@ -9,21 +21,50 @@ use lazy_static::lazy_static;
 use std::os::unix::io::AsRawFd;
 pub mod request;
 pub use request::Request;
+use request::ConnectionInfo;
 mod utils;
 use utils::*;
 pub mod response_writer;
 pub use response_writer::ResponseWriter;
-
-macro_rules! syscall {
-    ($fn: ident ( $($arg: expr),* $(,)* ) ) => {{
-        let res = unsafe { libc::$fn($($arg, )*) };
-        if res == -1 {
-            Err(std::io::Error::last_os_error())
-        } else {
-            Ok(res)
-        }
-    }};
-}
+mod buffer_pool;
+mod deferred;
+pub use deferred::DeferredResponse;
+mod timer;
+pub use timer::TimerId;
+mod compression;
+pub use compression::CompressionOptions;
+mod conditional;
+mod http2;
+mod hub;
+pub use hub::{Subscriber, SubscriptionId};
+mod mime;
+mod proxy_protocol;
+pub use proxy_protocol::{ProxyAddresses, ProxyProtocol};
+mod range;
+mod sse;
+pub use sse::{EventStream, ServerEvent};
+mod static_files;
+pub use static_files::StaticOptions;
+#[cfg(feature = "tls")]
+mod tls;
+mod transport;
+#[cfg(feature = "tls")]
+mod x509;
+#[cfg(feature = "tls")]
+pub use x509::{ClientCertificate, SubjectAltName};
+mod upgrade;
+pub use upgrade::Upgraded;
+mod watch;
+pub use watch::{FdEvent, WatchId};
+mod websocket;
+pub use websocket::{Message, WebSocket, WebSocketEvent, WebSocketId};
+use websocket::WebSocketHandler;
+pub mod poller;
+pub use poller::{Interest, Poller, PollerKind};
+use poller::poller;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 pub struct HttpServer {
@@ -32,118 +73,650 @@ pub struct HttpServer {
 pub type EventId = usize;
 pub type ServerId = usize;
 
+/// Per server settings changed through `HttpServer` methods.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ServerConfig {
+    pub(crate) body_etags: bool,
+    pub(crate) max_body_size: Option<usize>,
+    pub(crate) compression: Option<std::sync::Arc<CompressionOptions>>,
+    pub(crate) http2_disabled: bool,
+    pub(crate) proxy_protocol: ProxyProtocol,
+}
+
+// Server and connection ids start at 100, keys below are reserved for
+// internal file descriptors registered with the poller.
+const WAKER_KEY: u64 = 1;
+
 lazy_static! {
-    static ref ROUTES: Mutex<HashMap<String, Handler>> = Mutex::new(HashMap::new());
+    static ref ROUTES: Mutex<HashMap<String, Route>> = Mutex::new(HashMap::new());
     static ref REQUEST_CTX: Mutex<HashMap<EventId, Request>> = Mutex::new(HashMap::new());
     static ref WRITE_CTX: Mutex<HashMap<EventId, ResponseWriter>> = Mutex::new(HashMap::new());
     static ref SERVER_ID: Mutex<EventId> = Mutex::new(100);
-    static ref SERVER_CTX: Mutex<HashMap<ServerId, (TcpListener, i32)>> =
-        Mutex::new(HashMap::new());
-    static ref EPOLL_FD: i32 = epoll_create().expect("can create epoll queue");
-    static ref EVENTS: Mutex<Vec<libc::epoll_event>> = Mutex::new(Vec::with_capacity(1024));
+    static ref SERVER_CTX: Mutex<HashMap<ServerId, Listener>> = Mutex::new(HashMap::new());
+    static ref SERVER_CONFIG: Mutex<HashMap<ServerId, ServerConfig>> = Mutex::new(HashMap::new());
+    // edge-triggered listeners whose last accept failed, retried every turn
+    // of the loop since no new edge may come for the connections waiting
+    static ref ACCEPT_RETRY: Mutex<Vec<ServerId>> = Mutex::new(Vec::new());
+    static ref WAKER_FD: i32 = {
+        let fd = eventfd_create().expect("can create waker eventfd");
+        poller().add(fd, WAKER_KEY, Interest::Read).expect("can register waker");
+        fd
+    };
+}
+
+pub(crate) fn server_config(server_id: ServerId) -> ServerConfig {
+    SERVER_CONFIG
+        .lock()
+        .unwrap()
+        .get(&server_id)
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Interrupts a pending `Poller::wait` so the loop picks up work queued from
+/// other threads.
+pub(crate) fn wake_loop() {
+    if let Err(e) = eventfd_signal(*WAKER_FD) {
+        eprintln!("couldn't wake event loop: {}", e);
+    }
+}
+
+struct Listener {
+    listener: TcpListener,
+    fd: i32,
+    edge_triggered: bool,
+    #[cfg(feature = "tls")]
+    tls: Option<std::sync::Arc<rustls::ServerConfig>>,
 }
 
+static EDGE_TRIGGERED: AtomicBool = AtomicBool::new(false);
+
 type Handler = Box<dyn Fn(&mut Request, &mut ResponseWriter) + Send + Sync>;
 
+/// Handlers registered for one path of a server.
+#[derive(Default)]
+struct Route {
+    // from `handle_route`, serves every method without a handler of its own
+    any: Option<Handler>,
+    methods: Vec<(String, Handler)>,
+    // from `handle_websocket`, takes `GET` requests asking for an upgrade
+    websocket: Option<std::sync::Arc<WebSocketHandler>>,
+}
+
+impl Route {
+    /// `HEAD` falls back to the `GET` handler. `OPTIONS` goes to the
+    /// `handle_route` handler like any other method, and is only answered by
+    /// the server on routes made of method handlers.
+    fn handler(&self, method: &str) -> Option<&Handler> {
+        let explicit = |method: &str| {
+            self.methods
+                .iter()
+                .find(|(m, _)| m == method)
+                .map(|(_, handler)| handler)
+        };
+        explicit(method)
+            .or_else(|| if method == "HEAD" { explicit("GET") } else { None })
+            .or(self.any.as_ref())
+    }
+
+    /// The methods registered with `handle_method`, only complete for routes
+    /// without a `handle_route` handler.
+    fn allow(&self) -> String {
+        let mut allow: Vec<&str> = self.methods.iter().map(|(m, _)| m.as_str()).collect();
+        if allow.contains(&"GET") && !allow.contains(&"HEAD") {
+            allow.push("HEAD");
+        }
+        if self.websocket.is_some() && !allow.contains(&"GET") {
+            allow.insert(0, "GET");
+        }
+        if !allow.contains(&"OPTIONS") {
+            allow.push("OPTIONS");
+        }
+        allow.join(", ")
+    }
+}
+
+/// Methods accepted somewhere on `server_id`, the `Allow` of `OPTIONS *`.
+fn server_allow(routes: &HashMap<String, Route>, server_id: ServerId) -> String {
+    let suffix = format!(".{}", server_id);
+    let mut allow: Vec<String> = vec!["GET".to_string(), "HEAD".to_string()];
+    // what a `handle_route` handler accepts is up to the handler
+    let known = routes
+        .iter()
+        .filter(|(key, route)| key.ends_with(&suffix) && route.any.is_none());
+    for (_, route) in known {
+        for method in route.allow().split(", ") {
+            if !allow.iter().any(|m| m == method) {
+                allow.push(method.to_string());
+            }
+        }
+    }
+    if !allow.iter().any(|m| m == "OPTIONS") {
+        allow.push("OPTIONS".to_string());
+    }
+    allow.join(", ")
+}
+
+impl Default for HttpServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl HttpServer {
     pub fn new() -> Self {
         let mut server_id_guard = SERVER_ID.lock().unwrap();
-        let server_id = server_id_guard.clone();
+        let server_id = *server_id_guard;
         *server_id_guard += 1;
         HttpServer { server_id }
     }
 
+    /// Tells the requests of this server apart, see `Request::server_id`.
+    pub fn server_id(&self) -> ServerId {
+        self.server_id
+    }
+
     pub fn run_all() {
+        lazy_static::initialize(&WAKER_FD);
+        lazy_static::initialize(&timer::TIMER_FD);
+        let mut events = Vec::with_capacity(1024);
         loop {
-            EVENTS.lock().unwrap().clear();
-            let res = match syscall!(epoll_wait(
-                *EPOLL_FD,
-                EVENTS.lock().unwrap().as_mut_ptr() as *mut libc::epoll_event,
-                1024,
-                1000 as libc::c_int,
-            )) {
-                Ok(v) => v,
-                Err(e) => panic!("error during epoll wait: {}", e),
-            };
+            if let Err(e) = poller().wait(&mut events, Some(Duration::from_millis(1000))) {
+                panic!("error during poller wait: {}", e);
+            }
 
-            // safe  as long as the kernel does nothing wrong - copied from mio
-            unsafe { EVENTS.lock().unwrap().set_len(res as usize) };
-
-            for ev in EVENTS.lock().unwrap().iter() {
-                match SERVER_CTX.lock().unwrap().get(&(ev.u64 as usize)) {
-                    Some((listerner, listener_fd)) => {
-                        match listerner.accept() {
-                            Ok((stream, _)) => {
-                                stream.set_nonblocking(true).unwrap();
-                                let mut request_contexts = REQUEST_CTX.lock().unwrap();
-                                let key = SERVER_ID.lock().unwrap().clone();
-                                add_interest(
-                                    *EPOLL_FD,
-                                    stream.as_raw_fd(),
-                                    listener_read_event(key as u64),
-                                )
+            for ev in events.iter() {
+                if ev.key == WAKER_KEY {
+                    let _ = drain_counter_fd(*WAKER_FD);
+                    poller().modify(*WAKER_FD, WAKER_KEY, Interest::Read).unwrap();
+                    continue;
+                }
+                if ev.key == timer::TIMER_KEY {
+                    timer::run_expired();
+                    continue;
+                }
+                if watch::dispatch(ev)
+                    || websocket::dispatch(ev)
+                    || sse::dispatch(ev)
+                    || http2::dispatch(ev)
+                {
+                    continue;
+                }
+                match SERVER_CTX.lock().unwrap().get(&(ev.key as usize)) {
+                    Some(listener) => {
+                        Self::accept_connections(listener, ev.key as usize);
+                        if !listener.edge_triggered {
+                            poller()
+                                .modify(listener.fd, ev.key, Interest::Read)
                                 .unwrap();
-
-                                request_contexts.insert(key, Request::new(stream, ev.u64 as usize));
-                                *SERVER_ID.lock().unwrap() += 1;
-                            }
-                            Err(e) => {
-                                eprintln!("couldn't accept on server {}: {}", ev.u64 as usize, e)
-                            }
-                        };
-                        modify_interest(
-                            *EPOLL_FD,
-                            listener_fd.clone(),
-                            listener_read_event(ev.u64 as u64),
-                        )
-                        .unwrap();
+                        }
                     }
                     None => {
-                        let key = ev.u64 as usize;
-                        let mut to_delete = None;
+                        let key = ev.key as usize;
+                        let mut to_delete = false;
                         if let Some(context) = REQUEST_CTX.lock().unwrap().get_mut(&key) {
-                            let events: u32 = ev.events;
-                            match events {
-                                v if v as i32 & libc::EPOLLIN == libc::EPOLLIN => {
-                                    context.read_cb(key, *EPOLL_FD).unwrap();
+                            // a hangup or an error alone shows up as the end
+                            // of the stream or a failed read
+                            let readable = ev.readable || (!ev.writable && (ev.hangup || ev.error));
+                            if readable || (ev.writable && context.is_reading()) {
+                                match context.read_cb(key) {
+                                    Ok(done) => to_delete = done,
+                                    Err(e) => {
+                                        if e.kind() != io::ErrorKind::UnexpectedEof {
+                                            eprintln!("error reading request {}: {}", key, e);
+                                        }
+                                        let _ = poller().remove(context.stream.as_raw_fd());
+                                        context.abandon_upgrade(key);
+                                        to_delete = true;
+                                    }
                                 }
-                                v if v as i32 & libc::EPOLLOUT == libc::EPOLLOUT => {
-                                    context.write_cb(key);
-                                    to_delete = Some(key);
+                            } else {
+                                // edge-triggered sockets report writability
+                                // as soon as they are registered
+                                if !context.edge_triggered {
+                                    to_delete = context.write_cb(key);
+                                    if !to_delete {
+                                        if let Err(e) = poller().modify(
+                                            context.stream.as_raw_fd(),
+                                            ev.key,
+                                            Interest::Write,
+                                        ) {
+                                            eprintln!("couldn't re-arm response {}: {}", key, e);
+                                            to_delete = true;
+                                        }
+                                    }
                                 }
-                                v => println!("unexpected events: {}", v),
-                            };
+                            }
                         }
-                        if let Some(key) = to_delete {
-                            REQUEST_CTX.lock().unwrap().remove(&key);
+                        if to_delete {
+                            let request = REQUEST_CTX.lock().unwrap().remove(&key);
+                            if let Some(request) = request {
+                                request.hand_over(key);
+                            }
                         }
                         continue;
                     }
                 }
             }
+
+            #[cfg(feature = "tls")]
+            tls::reload_if_requested();
+            hub::deliver();
+            Self::retry_accepts();
+            Self::resume_deferred();
+            websocket::flush_pending();
+            sse::flush_pending();
+            http2::flush_pending();
+        }
+    }
+
+    /// Accepts one pending connection, or all of them on an edge-triggered
+    /// listener.
+    fn accept_connections(listener: &Listener, server_id: ServerId) {
+        let edge_triggered = listener.edge_triggered;
+        let proxy_protocol = server_config(server_id).proxy_protocol;
+        loop {
+            match listener.listener.accept() {
+                Ok((stream, remote_addr)) => {
+                    stream.set_nonblocking(true).unwrap();
+                    let mut request_contexts = REQUEST_CTX.lock().unwrap();
+                    let key = *SERVER_ID.lock().unwrap();
+                    let fd = stream.as_raw_fd();
+                    #[cfg(feature = "tls")]
+                    if let Some(config) = &listener.tls {
+                        if let Err(e) = tls::accept(key, config) {
+                            eprintln!("couldn't start TLS on server {}: {}", server_id, e);
+                            if edge_triggered {
+                                ACCEPT_RETRY.lock().unwrap().push(server_id);
+                            }
+                            break;
+                        }
+                    }
+                    let mut request = Request::new(stream, server_id);
+                    request.proxy_protocol = proxy_protocol;
+                    request.connection = ConnectionInfo {
+                        id: key,
+                        remote_addr: Some(remote_addr),
+                        local_addr: request.stream.local_addr().ok(),
+                        proxy_addresses: None,
+                    };
+                    request.edge_triggered = edge_triggered
+                        && poller()
+                            .add_edge(fd, key as u64, Interest::Read)
+                            .unwrap();
+                    if !request.edge_triggered {
+                        poller().add(fd, key as u64, Interest::Read).unwrap();
+                    }
+
+                    request_contexts.insert(key, request);
+                    *SERVER_ID.lock().unwrap() += 1;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    eprintln!("couldn't accept on server {}: {}", server_id, e);
+                    if edge_triggered {
+                        ACCEPT_RETRY.lock().unwrap().push(server_id);
+                    }
+                    break;
+                }
+            };
+            if !edge_triggered {
+                break;
+            }
         }
     }
+
+    /// Accepts again on edge-triggered listeners that stopped on an error
+    /// such as `EMFILE`.
+    fn retry_accepts() {
+        let retry = std::mem::take(&mut *ACCEPT_RETRY.lock().unwrap());
+        if retry.is_empty() {
+            return;
+        }
+        let listeners = SERVER_CTX.lock().unwrap();
+        for server_id in retry {
+            if let Some(listener) = listeners.get(&server_id) {
+                Self::accept_connections(listener, server_id);
+            }
+        }
+    }
+
+    fn resume_deferred() {
+        let mut request_contexts = REQUEST_CTX.lock().unwrap();
+        let mut ready = deferred::take_completed();
+        for event_id in deferred::take_expired() {
+            if let Some(context) = request_contexts.get(&event_id) {
+                match context.stream.try_clone() {
+                    Ok(stream) => {
+                        ResponseWriter::new(stream, event_id)
+                            .write_status(HttpStatus::ServiceUnavailable);
+                        ready.push(event_id);
+                    }
+                    Err(e) => eprintln!("couldn't answer expired request {}: {}", event_id, e),
+                }
+            }
+        }
+        for event_id in ready {
+            let done = match request_contexts.get_mut(&event_id) {
+                Some(context) => {
+                    context.finalize(event_id);
+                    context.respond(event_id).unwrap()
+                }
+                // the client went away meanwhile, which edge-triggered
+                // connections notice while the response is deferred
+                None => {
+                    WRITE_CTX.lock().unwrap().remove(&event_id);
+                    false
+                }
+            };
+            if done {
+                request_contexts.remove(&event_id);
+            }
+        }
+    }
+
+    /// Switches the event loop to edge-triggered registrations for listeners
+    /// and connections, when the poller supports them (epoll only).
+    ///
+    /// Must be called before `listen_on`.
+    pub fn set_edge_triggered(enabled: bool) {
+        EDGE_TRIGGERED.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Runs `callback` once on the event loop thread after `delay`.
+    pub fn set_timeout<F: FnOnce() + Send + 'static>(delay: Duration, callback: F) -> TimerId {
+        let mut callback = Some(callback);
+        timer::schedule(
+            delay,
+            None,
+            Box::new(move || {
+                if let Some(callback) = callback.take() {
+                    callback()
+                }
+            }),
+        )
+    }
+
+    /// Runs `callback` on the event loop thread every `period`, until cancelled.
+    pub fn set_interval<F: FnMut() + Send + 'static>(period: Duration, callback: F) -> TimerId {
+        timer::schedule(period, Some(period), Box::new(callback))
+    }
+
+    /// Cancels a pending timeout or interval, returns `false` if it already
+    /// fired or was cancelled.
+    pub fn cancel(timer_id: TimerId) -> bool {
+        timer::cancel(timer_id)
+    }
+
+    /// Subscribes a WebSocket, an event stream or a deferred response to the
+    /// messages published on `topic`.
+    pub fn subscribe<S: Into<Subscriber>>(topic: &str, subscriber: S) -> SubscriptionId {
+        hub::subscribe(topic, subscriber.into())
+    }
+
+    /// Returns `false` if the subscription was already dropped, e.g. because
+    /// its connection closed or could not keep up.
+    pub fn unsubscribe(subscription_id: SubscriptionId) -> bool {
+        hub::unsubscribe(subscription_id)
+    }
+
+    /// Sends `data` to every subscriber of `topic`, from any thread, and
+    /// returns how many it was queued for. Messages are written as the
+    /// connections drain; a subscriber with too many of them waiting is
+    /// disconnected.
+    pub fn publish(topic: &str, data: &[u8]) -> usize {
+        hub::publish(topic, data)
+    }
+
+    /// Sets how many published messages may wait for one subscriber before
+    /// it is evicted, 256 by default.
+    pub fn set_subscriber_queue_limit(limit: usize) {
+        hub::set_queue_limit(limit);
+    }
+
+    /// Registers a raw file descriptor with the event loop.
+    ///
+    /// `callback` runs on the loop thread each time `fd` becomes ready for
+    /// `interest`; the descriptor stays owned by the caller and must be
+    /// unwatched before it is closed.
+    pub fn watch_fd<F: FnMut(&FdEvent) + Send + 'static>(
+        fd: RawFd,
+        interest: Interest,
+        callback: F,
+    ) -> io::Result<WatchId> {
+        watch::watch(fd, interest, Box::new(callback))
+    }
+
+    pub fn set_fd_interest(watch_id: WatchId, interest: Interest) -> io::Result<()> {
+        watch::set_interest(watch_id, interest)
+    }
+
+    pub fn unwatch_fd(watch_id: WatchId) -> io::Result<()> {
+        watch::unwatch(watch_id)
+    }
+
+    /// Replaces the default poller, selected from `HTTP_LOLO_POLLER` or epoll.
+    ///
+    /// Must be called before any server, timer or file descriptor is
+    /// registered, e.g. `HttpServer::set_poller(PollerKind::Poll.create()?)`.
+    pub fn set_poller(poller: Box<dyn Poller>) -> io::Result<()> {
+        poller::init(poller)
+    }
+
+    /// Registers `handler` for every method on `path`, except the ones given
+    /// their own handler with `handle_method`. This includes `OPTIONS`, so the
+    /// handler answers CORS preflight requests itself.
     pub fn handle_route(&self, path: &str, handler: Handler) {
         let mut routes = ROUTES.lock().unwrap();
-        routes.insert(
-            path.to_string() + "." + self.server_id.to_string().as_str(),
-            handler,
-        );
+        routes
+            .entry(path.to_string() + "." + self.server_id.to_string().as_str())
+            .or_default()
+            .any = Some(handler);
+    }
+
+    /// Registers `handler` for `method` requests on `path`. Paths with only
+    /// method handlers answer other methods with `405 Method Not Allowed`.
+    ///
+    /// `HEAD` requests run the `GET` handler without sending the body, and
+    /// `OPTIONS` is answered with the `Allow` header computed from the
+    /// registered methods, unless these have handlers of their own.
+    pub fn handle_method(&self, method: &str, path: &str, handler: Handler) {
+        let mut routes = ROUTES.lock().unwrap();
+        let route = routes
+            .entry(path.to_string() + "." + self.server_id.to_string().as_str())
+            .or_default();
+        route.methods.retain(|(m, _)| m != method);
+        route.methods.push((method.to_string(), handler));
+    }
+
+    /// Accepts WebSocket connections on `path`. The handshake is answered
+    /// for `GET` requests asking for an upgrade, then `handler` is called
+    /// with the connection opening, each message received and the close.
+    ///
+    /// Messages are sent through the `WebSocket` handle, which can be kept
+    /// and used from other handlers or threads.
+    pub fn handle_websocket(&self, path: &str, handler: WebSocketHandler) {
+        let mut routes = ROUTES.lock().unwrap();
+        routes
+            .entry(path.to_string() + "." + self.server_id.to_string().as_str())
+            .or_default()
+            .websocket = Some(std::sync::Arc::new(handler));
+    }
+
+    /// Serves the files under `root` for request paths starting with
+    /// `prefix`, with the default `StaticOptions`. Routes registered with
+    /// `handle_route` take precedence.
+    pub fn serve_dir<P: AsRef<std::path::Path>>(&self, prefix: &str, root: P) -> io::Result<()> {
+        self.serve_dir_with_options(prefix, root, StaticOptions::default())
+    }
+
+    pub fn serve_dir_with_options<P: AsRef<std::path::Path>>(
+        &self,
+        prefix: &str,
+        root: P,
+        options: StaticOptions,
+    ) -> io::Result<()> {
+        static_files::register(self.server_id, prefix, root.as_ref(), options)
+    }
+
+    /// Tags responses with a body in memory with a strong `ETag` hashed from
+    /// the body, so that `If-None-Match` can answer `304 Not Modified`.
+    /// Off by default, file bodies are always tagged.
+    pub fn set_body_etags(&self, enabled: bool) {
+        self.update_config(|config| config.body_etags = enabled);
+    }
+
+    /// Rejects requests whose body is larger than `limit` bytes with
+    /// `413 Payload Too Large`, before reading it. Compressed bodies are
+    /// checked again once decoded.
+    pub fn set_max_body_size(&self, limit: usize) {
+        self.update_config(|config| config.max_body_size = Some(limit));
+    }
+
+    /// Compresses in-memory response bodies with the best coding accepted by
+    /// the client: brotli when built with the `brotli` feature, gzip or
+    /// deflate. Event streams and file bodies are never compressed on the
+    /// fly, see `StaticOptions::precompressed` for the latter.
+    pub fn enable_compression(&self, options: CompressionOptions) {
+        self.update_config(|config| config.compression = Some(std::sync::Arc::new(options)));
+    }
+
+    pub fn disable_compression(&self) {
+        self.update_config(|config| config.compression = None);
+    }
+
+    /// Accepts cleartext HTTP/2 connections, from clients sending the
+    /// connection preface right away or asking for `Upgrade: h2c`. On by
+    /// default.
+    pub fn set_http2(&self, enabled: bool) {
+        self.update_config(|config| config.http2_disabled = !enabled);
+    }
+
+    /// Expects connections to start with a PROXY protocol header, version 1
+    /// or 2, as sent by load balancers. The addresses it carries are found
+    /// with `Request::proxy_addresses`.
+    pub fn set_proxy_protocol(&self, mode: ProxyProtocol) {
+        self.update_config(|config| config.proxy_protocol = mode);
+    }
+
+    fn update_config<F: FnOnce(&mut ServerConfig)>(&self, f: F) {
+        f(SERVER_CONFIG.lock().unwrap().entry(self.server_id).or_default());
     }
 
     pub fn listen_on(&self, addr: &str) {
         let listener = TcpListener::bind(addr).unwrap();
+        self.listen(
+            listener,
+            #[cfg(feature = "tls")]
+            None,
+        );
+    }
+
+    /// Like `listen_on`, with TLS terminated by the server. The certificate
+    /// chain and its private key are read from PEM files, and served to
+    /// clients whose SNI name matches no `add_certificate` one. Clients
+    /// offering `h2` through ALPN get HTTP/2 unless `set_http2(false)` was
+    /// called before.
+    #[cfg(feature = "tls")]
+    pub fn listen_tls<P: AsRef<std::path::Path>>(
+        &self,
+        addr: &str,
+        cert_path: P,
+        key_path: P,
+    ) -> io::Result<()> {
+        let mut alpn_protocols = vec![b"http/1.1".to_vec()];
+        if http2::enabled(self.server_id) {
+            alpn_protocols.insert(0, b"h2".to_vec());
+        }
+        tls::add_certificate(self.server_id, None, cert_path.as_ref(), key_path.as_ref())?;
+        let config = tls::server_config(self.server_id, alpn_protocols)?;
+        let listener = TcpListener::bind(addr)?;
+        self.listen(listener, Some(config));
+        Ok(())
+    }
+
+    /// Serves another certificate to TLS clients asking for `server_name`
+    /// through SNI. `*.example.com` matches the direct subdomains of
+    /// `example.com`.
+    #[cfg(feature = "tls")]
+    pub fn add_certificate<P: AsRef<std::path::Path>>(
+        &self,
+        server_name: &str,
+        cert_path: P,
+        key_path: P,
+    ) -> io::Result<()> {
+        tls::add_certificate(
+            self.server_id,
+            Some(server_name),
+            cert_path.as_ref(),
+            key_path.as_ref(),
+        )
+    }
+
+    /// Asks TLS clients for a certificate issued by one of the authorities
+    /// in the PEM bundle at `ca_path`, handlers find it with
+    /// `Request::client_certificate`. Unless `required` is set, clients
+    /// without a certificate are accepted too. Must be called before
+    /// `listen_tls`.
+    #[cfg(feature = "tls")]
+    pub fn verify_client_certificates<P: AsRef<std::path::Path>>(
+        &self,
+        ca_path: P,
+        required: bool,
+    ) -> io::Result<()> {
+        tls::verify_clients(self.server_id, ca_path.as_ref(), required)
+    }
+
+    /// Reads the certificates of the server from their files again. Only
+    /// handshakes started afterwards see the new ones, open connections are
+    /// kept. Nothing changes if one of them fails to load.
+    #[cfg(feature = "tls")]
+    pub fn reload_certificates(&self) -> io::Result<()> {
+        tls::reload(self.server_id)
+    }
+
+    /// Reloads the certificates of every server when the process receives
+    /// SIGHUP.
+    #[cfg(feature = "tls")]
+    pub fn reload_certificates_on_sighup() -> io::Result<()> {
+        tls::reload_on_sighup(*WAKER_FD)
+    }
+
+    fn listen(
+        &self,
+        listener: TcpListener,
+        #[cfg(feature = "tls")] tls: Option<std::sync::Arc<rustls::ServerConfig>>,
+    ) {
         listener.set_nonblocking(true).expect("nonblocking works");
         let listener_fd = listener.as_raw_fd();
-        add_interest(
-            *EPOLL_FD,
-            listener_fd,
-            listener_read_event(self.server_id as u64),
-        )
-        .unwrap();
+        #[cfg(not(feature = "tls"))]
+        let secure = false;
+        // the TLS session may hold bytes back, which edge-triggered
+        // readiness can't account for
+        #[cfg(feature = "tls")]
+        let secure = tls.is_some();
+        let edge_triggered = EDGE_TRIGGERED.load(Ordering::Relaxed)
+            && !secure
+            && poller()
+                .add_edge(listener_fd, self.server_id as u64, Interest::Read)
+                .unwrap();
+        if !edge_triggered {
+            poller()
+                .add(listener_fd, self.server_id as u64, Interest::Read)
+                .unwrap();
+        }
         SERVER_CTX
             .lock()
             .unwrap()
-            .insert(self.server_id, (listener, listener_fd));
+            .insert(
+                self.server_id,
+                Listener {
+                    listener,
+                    fd: listener_fd,
+                    edge_triggered,
+                    #[cfg(feature = "tls")]
+                    tls,
+                },
+            );
     }
 }
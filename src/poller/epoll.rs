@@ -0,0 +1,120 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::{Event, Interest, Poller};
+
+const READ_FLAGS: i32 = libc::EPOLLONESHOT | libc::EPOLLIN;
+const WRITE_FLAGS: i32 = libc::EPOLLONESHOT | libc::EPOLLOUT;
+const EDGE_FLAGS: i32 = libc::EPOLLET | libc::EPOLLRDHUP;
+
+fn epoll_create() -> io::Result<RawFd> {
+    let fd = syscall!(epoll_create1(0))?;
+    if let Ok(flags) = syscall!(fcntl(fd, libc::F_GETFD)) {
+        let _ = syscall!(fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC));
+    }
+
+    Ok(fd)
+}
+
+fn interest_event(key: u64, interest: Interest) -> libc::epoll_event {
+    let events = match interest {
+        Interest::Read => READ_FLAGS,
+        Interest::Write => WRITE_FLAGS,
+        Interest::ReadWrite => READ_FLAGS | WRITE_FLAGS,
+    };
+    libc::epoll_event {
+        events: events as u32,
+        u64: key,
+    }
+}
+
+fn add_interest(epoll_fd: RawFd, fd: RawFd, mut event: libc::epoll_event) -> io::Result<()> {
+    syscall!(epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut event))?;
+    Ok(())
+}
+
+fn modify_interest(epoll_fd: RawFd, fd: RawFd, mut event: libc::epoll_event) -> io::Result<()> {
+    syscall!(epoll_ctl(epoll_fd, libc::EPOLL_CTL_MOD, fd, &mut event))?;
+    Ok(())
+}
+
+fn remove_interest(epoll_fd: RawFd, fd: RawFd) -> io::Result<()> {
+    syscall!(epoll_ctl(
+        epoll_fd,
+        libc::EPOLL_CTL_DEL,
+        fd,
+        std::ptr::null_mut()
+    ))?;
+    Ok(())
+}
+
+/// The default backend, one-shot `epoll(7)` registrations, or edge-triggered
+/// ones through `add_edge`.
+pub struct EpollPoller {
+    epoll_fd: RawFd,
+    events: Mutex<Vec<libc::epoll_event>>,
+}
+
+impl EpollPoller {
+    pub fn new() -> io::Result<Self> {
+        Ok(EpollPoller {
+            epoll_fd: epoll_create()?,
+            events: Mutex::new(Vec::with_capacity(1024)),
+        })
+    }
+}
+
+impl Poller for EpollPoller {
+    fn add(&self, fd: RawFd, key: u64, interest: Interest) -> io::Result<()> {
+        add_interest(self.epoll_fd, fd, interest_event(key, interest))
+    }
+
+    fn modify(&self, fd: RawFd, key: u64, interest: Interest) -> io::Result<()> {
+        modify_interest(self.epoll_fd, fd, interest_event(key, interest))
+    }
+
+    fn remove(&self, fd: RawFd) -> io::Result<()> {
+        remove_interest(self.epoll_fd, fd)
+    }
+
+    fn add_edge(&self, fd: RawFd, key: u64, interest: Interest) -> io::Result<bool> {
+        let mut event = interest_event(key, interest);
+        event.events = (event.events as i32 & !libc::EPOLLONESHOT | EDGE_FLAGS) as u32;
+        add_interest(self.epoll_fd, fd, event)?;
+        Ok(true)
+    }
+
+    fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        events.clear();
+        let mut epoll_events = self.events.lock().unwrap();
+        epoll_events.clear();
+        let timeout = timeout.map(|t| t.as_millis() as libc::c_int).unwrap_or(-1);
+        let res = match syscall!(epoll_wait(
+            self.epoll_fd,
+            epoll_events.as_mut_ptr(),
+            epoll_events.capacity() as libc::c_int,
+            timeout,
+        )) {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        // safe  as long as the kernel does nothing wrong - copied from mio
+        unsafe { epoll_events.set_len(res as usize) };
+
+        events.extend(epoll_events.iter().map(|ev| {
+            let flags = ev.events as i32;
+            Event {
+                key: ev.u64,
+                readable: flags & libc::EPOLLIN != 0,
+                writable: flags & libc::EPOLLOUT != 0,
+                hangup: flags & (libc::EPOLLHUP | libc::EPOLLRDHUP) != 0,
+                error: flags & libc::EPOLLERR != 0,
+            }
+        }));
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+mod epoll;
+mod poll;
+#[cfg(feature = "io-uring")]
+mod uring;
+
+pub use epoll::EpollPoller;
+pub use poll::PollPoller;
+#[cfg(feature = "io-uring")]
+pub use uring::UringPoller;
+
+/// Readiness a registered file descriptor is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interest {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Interest {
+    pub fn is_readable(self) -> bool {
+        matches!(self, Interest::Read | Interest::ReadWrite)
+    }
+
+    pub fn is_writable(self) -> bool {
+        matches!(self, Interest::Write | Interest::ReadWrite)
+    }
+}
+
+/// Readiness reported by a `Poller` for the key a descriptor was registered with.
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub key: u64,
+    pub readable: bool,
+    pub writable: bool,
+    pub hangup: bool,
+    pub error: bool,
+}
+
+/// Readiness backend driving `HttpServer::run_all`.
+///
+/// Registrations are one-shot: once an event has been reported for a file
+/// descriptor it stays disarmed until `modify` is called for it again.
+/// Every method may be called from any thread, including while another
+/// thread is blocked in `wait`.
+pub trait Poller: Send + Sync {
+    fn add(&self, fd: RawFd, key: u64, interest: Interest) -> io::Result<()>;
+    fn modify(&self, fd: RawFd, key: u64, interest: Interest) -> io::Result<()>;
+    fn remove(&self, fd: RawFd) -> io::Result<()>;
+    /// Registers `fd` edge-triggered: it stays armed and reports every new
+    /// readiness edge until removed, so the caller must drain it until
+    /// `WouldBlock`. Backends that only support one-shot registrations
+    /// return `false` without registering anything.
+    fn add_edge(&self, _fd: RawFd, _key: u64, _interest: Interest) -> io::Result<bool> {
+        Ok(false)
+    }
+    /// Replaces the content of `events` with the next batch of ready keys,
+    /// returning with an empty batch once `timeout` has elapsed.
+    fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+/// Built-in poller backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PollerKind {
+    Epoll,
+    Poll,
+    #[cfg(feature = "io-uring")]
+    IoUring,
+}
+
+impl PollerKind {
+    /// Reads the backend from the `HTTP_LOLO_POLLER` environment variable
+    /// (`epoll`, `poll` or `io_uring`), defaulting to epoll.
+    pub fn from_env() -> PollerKind {
+        match std::env::var("HTTP_LOLO_POLLER").as_deref() {
+            Ok("poll") => PollerKind::Poll,
+            #[cfg(feature = "io-uring")]
+            Ok("io_uring") | Ok("io-uring") => PollerKind::IoUring,
+            Ok("epoll") | Err(_) => PollerKind::Epoll,
+            Ok(other) => {
+                eprintln!("unknown poller {:?}, using epoll", other);
+                PollerKind::Epoll
+            }
+        }
+    }
+
+    pub fn create(self) -> io::Result<Box<dyn Poller>> {
+        Ok(match self {
+            PollerKind::Epoll => Box::new(EpollPoller::new()?),
+            PollerKind::Poll => Box::new(PollPoller::new()?),
+            #[cfg(feature = "io-uring")]
+            PollerKind::IoUring => Box::new(UringPoller::new()?),
+        })
+    }
+}
+
+static POLLER: OnceLock<Box<dyn Poller>> = OnceLock::new();
+
+pub(crate) fn poller() -> &'static dyn Poller {
+    POLLER
+        .get_or_init(|| {
+            PollerKind::from_env()
+                .create()
+                .expect("can create poller")
+        })
+        .as_ref()
+}
+
+pub(crate) fn init(poller: Box<dyn Poller>) -> io::Result<()> {
+    POLLER.set(poller).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the poller must be chosen before the first server or timer is registered",
+        )
+    })
+}
@@ -0,0 +1,147 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::{Event, Interest, Poller};
+use crate::utils::*;
+
+struct Registration {
+    key: u64,
+    interest: Interest,
+    armed: bool,
+}
+
+/// Portable backend built on `poll(2)`.
+///
+/// One-shot semantics are emulated by disarming a descriptor once it has been
+/// reported. Registration changes made while `wait` is blocked interrupt it
+/// through an eventfd, so the next `poll` call sees them.
+pub struct PollPoller {
+    registry: Mutex<HashMap<RawFd, Registration>>,
+    waker: RawFd,
+    waiting: AtomicBool,
+}
+
+impl PollPoller {
+    pub fn new() -> io::Result<Self> {
+        Ok(PollPoller {
+            registry: Mutex::new(HashMap::new()),
+            waker: eventfd_create()?,
+            waiting: AtomicBool::new(false),
+        })
+    }
+
+    fn changed(&self) {
+        if self.waiting.load(Ordering::Acquire) {
+            let _ = eventfd_signal(self.waker);
+        }
+    }
+}
+
+impl Poller for PollPoller {
+    fn add(&self, fd: RawFd, key: u64, interest: Interest) -> io::Result<()> {
+        let mut registry = self.registry.lock().unwrap();
+        // a descriptor number still registered here was closed without being
+        // removed and has been reused since, so the old registration is stale
+        registry.insert(
+            fd,
+            Registration {
+                key,
+                interest,
+                armed: true,
+            },
+        );
+        drop(registry);
+        self.changed();
+        Ok(())
+    }
+
+    fn modify(&self, fd: RawFd, key: u64, interest: Interest) -> io::Result<()> {
+        let mut registry = self.registry.lock().unwrap();
+        let registration = registry
+            .get_mut(&fd)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        registration.key = key;
+        registration.interest = interest;
+        registration.armed = true;
+        drop(registry);
+        self.changed();
+        Ok(())
+    }
+
+    fn remove(&self, fd: RawFd) -> io::Result<()> {
+        self.registry
+            .lock()
+            .unwrap()
+            .remove(&fd)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        self.changed();
+        Ok(())
+    }
+
+    fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        events.clear();
+        // set before the snapshot so concurrent changes always wake us up
+        self.waiting.store(true, Ordering::Release);
+        let mut fds = vec![libc::pollfd {
+            fd: self.waker,
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        fds.extend(
+            self.registry
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, registration)| registration.armed)
+                .map(|(fd, registration)| {
+                    let mut flags = 0;
+                    if registration.interest.is_readable() {
+                        flags |= libc::POLLIN;
+                    }
+                    if registration.interest.is_writable() {
+                        flags |= libc::POLLOUT;
+                    }
+                    libc::pollfd {
+                        fd: *fd,
+                        events: flags,
+                        revents: 0,
+                    }
+                }),
+        );
+
+        let timeout = timeout.map(|t| t.as_millis() as libc::c_int).unwrap_or(-1);
+        let res = syscall!(poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout));
+        self.waiting.store(false, Ordering::Release);
+        match res {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        if fds[0].revents != 0 {
+            let _ = drain_counter_fd(self.waker);
+        }
+
+        let mut registry = self.registry.lock().unwrap();
+        for pollfd in fds.iter().skip(1).filter(|pollfd| pollfd.revents != 0) {
+            // the descriptor may have been removed or re-registered meanwhile
+            let registration = match registry.get_mut(&pollfd.fd) {
+                Some(registration) if registration.armed => registration,
+                _ => continue,
+            };
+            registration.armed = false;
+            events.push(Event {
+                key: registration.key,
+                readable: pollfd.revents & libc::POLLIN != 0,
+                writable: pollfd.revents & libc::POLLOUT != 0,
+                hangup: pollfd.revents & libc::POLLHUP != 0,
+                error: pollfd.revents & (libc::POLLERR | libc::POLLNVAL) != 0,
+            });
+        }
+        Ok(())
+    }
+}
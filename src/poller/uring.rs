@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+use super::{Event, Interest, Poller};
+use crate::utils::*;
+
+// user_data values below FIRST_TOKEN belong to the backend itself
+const WAKER_TOKEN: u64 = 0;
+const TIMEOUT_TOKEN: u64 = 1;
+const REMOVE_TOKEN: u64 = 2;
+const FIRST_TOKEN: u64 = 16;
+
+struct Registration {
+    key: u64,
+    interest: Interest,
+    // user_data of the poll request currently in flight, if armed
+    token: Option<u64>,
+}
+
+#[derive(Default)]
+struct State {
+    registry: HashMap<RawFd, Registration>,
+    tokens: HashMap<u64, RawFd>,
+    next_token: u64,
+    pending: Vec<squeue::Entry>,
+}
+
+impl State {
+    fn arm(&mut self, fd: RawFd) {
+        let token = self.next_token;
+        self.next_token += 1;
+        let registration = self.registry.get_mut(&fd).expect("registered fd");
+        let mut flags = 0;
+        if registration.interest.is_readable() {
+            flags |= libc::POLLIN;
+        }
+        if registration.interest.is_writable() {
+            flags |= libc::POLLOUT;
+        }
+        registration.token = Some(token);
+        self.tokens.insert(token, fd);
+        self.pending.push(
+            opcode::PollAdd::new(types::Fd(fd), flags as u32)
+                .build()
+                .user_data(token),
+        );
+    }
+
+    fn disarm(&mut self, fd: RawFd) {
+        let token = self.registry.get_mut(&fd).and_then(|r| r.token.take());
+        if let Some(token) = token {
+            self.tokens.remove(&token);
+            self.pending
+                .push(opcode::PollRemove::new(token).build().user_data(REMOVE_TOKEN));
+        }
+    }
+}
+
+/// Backend submitting one-shot `IORING_OP_POLL_ADD` requests to an io_uring.
+///
+/// Registration changes are queued and submitted together with the next
+/// `wait`, which an eventfd polled by the ring interrupts when needed.
+pub struct UringPoller {
+    ring: Mutex<IoUring>,
+    state: Mutex<State>,
+    waker: RawFd,
+    waiting: AtomicBool,
+}
+
+impl UringPoller {
+    pub fn new() -> io::Result<Self> {
+        let waker = eventfd_create()?;
+        let state = State {
+            next_token: FIRST_TOKEN,
+            pending: vec![Self::waker_poll(waker)],
+            ..State::default()
+        };
+        Ok(UringPoller {
+            ring: Mutex::new(IoUring::new(1024)?),
+            state: Mutex::new(state),
+            waker,
+            waiting: AtomicBool::new(false),
+        })
+    }
+
+    fn waker_poll(waker: RawFd) -> squeue::Entry {
+        opcode::PollAdd::new(types::Fd(waker), libc::POLLIN as u32)
+            .build()
+            .user_data(WAKER_TOKEN)
+    }
+
+    fn changed(&self) {
+        if self.waiting.load(Ordering::Acquire) {
+            let _ = eventfd_signal(self.waker);
+        }
+    }
+
+    fn submit_pending(&self, ring: &mut IoUring, timeout: &types::Timespec) -> io::Result<()> {
+        let mut pending = std::mem::take(&mut self.state.lock().unwrap().pending);
+        // complete after the timeout or as soon as any other request does
+        pending.push(
+            opcode::Timeout::new(timeout)
+                .count(1)
+                .build()
+                .user_data(TIMEOUT_TOKEN),
+        );
+        for entry in pending {
+            // safe because the entries reference no user memory except the
+            // timespec, which outlives the submission
+            while unsafe { ring.submission().push(&entry) }.is_err() {
+                ring.submit()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Poller for UringPoller {
+    fn add(&self, fd: RawFd, key: u64, interest: Interest) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        // a descriptor number still registered here was closed without being
+        // removed and has been reused since, so the old registration is stale
+        state.disarm(fd);
+        state.registry.insert(
+            fd,
+            Registration {
+                key,
+                interest,
+                token: None,
+            },
+        );
+        state.arm(fd);
+        drop(state);
+        self.changed();
+        Ok(())
+    }
+
+    fn modify(&self, fd: RawFd, key: u64, interest: Interest) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.disarm(fd);
+        let registration = state
+            .registry
+            .get_mut(&fd)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        registration.key = key;
+        registration.interest = interest;
+        state.arm(fd);
+        drop(state);
+        self.changed();
+        Ok(())
+    }
+
+    fn remove(&self, fd: RawFd) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.disarm(fd);
+        state
+            .registry
+            .remove(&fd)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        drop(state);
+        self.changed();
+        Ok(())
+    }
+
+    fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> io::Result<()> {
+        events.clear();
+        let mut ring = self.ring.lock().unwrap();
+        // an hour stands in for "no timeout", the loop wakes up anyway
+        let timespec = types::Timespec::from(timeout.unwrap_or(Duration::from_secs(3600)));
+
+        self.waiting.store(true, Ordering::Release);
+        let res = self
+            .submit_pending(&mut ring, &timespec)
+            .and_then(|_| ring.submit_and_wait(1));
+        self.waiting.store(false, Ordering::Release);
+        match res {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut state = self.state.lock().unwrap();
+        for cqe in ring.completion() {
+            match cqe.user_data() {
+                WAKER_TOKEN => {
+                    let _ = drain_counter_fd(self.waker);
+                    state.pending.push(Self::waker_poll(self.waker));
+                }
+                TIMEOUT_TOKEN | REMOVE_TOKEN => {}
+                token => {
+                    // stale tokens belong to requests removed or re-armed since
+                    let fd = match state.tokens.remove(&token) {
+                        Some(fd) => fd,
+                        None => continue,
+                    };
+                    let registration = match state.registry.get_mut(&fd) {
+                        Some(registration) => registration,
+                        None => continue,
+                    };
+                    registration.token = None;
+                    let result = cqe.result();
+                    let revents = if result < 0 { libc::POLLERR } else { result as i16 };
+                    events.push(Event {
+                        key: registration.key,
+                        readable: revents & libc::POLLIN != 0,
+                        writable: revents & libc::POLLOUT != 0,
+                        hangup: revents & libc::POLLHUP != 0,
+                        error: revents & (libc::POLLERR | libc::POLLNVAL) != 0,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
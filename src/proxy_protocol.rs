@@ -0,0 +1,196 @@
+use std::io::{self, ErrorKind, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// the longest line allowed by the spec, CRLF included
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+/// Whether connections start with a PROXY protocol header, as sent by load
+/// balancers to pass the address of the client on. See
+/// `HttpServer::set_proxy_protocol`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProxyProtocol {
+    #[default]
+    Disabled,
+    /// Connections may start with a header.
+    Optional,
+    /// Connections without a header are closed.
+    Required,
+}
+
+/// The addresses of a proxied connection, as seen by the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyAddresses {
+    /// The client that connected to the proxy.
+    pub source: SocketAddr,
+    /// The address the client connected to.
+    pub destination: SocketAddr,
+}
+
+pub(crate) enum Progress {
+    /// The header is not complete yet.
+    Pending,
+    /// The header was read. Proxies checking their own health, or relaying
+    /// something other than TCP, send no addresses.
+    Done(Option<ProxyAddresses>),
+    /// The connection doesn't start with a header. The bytes read so far
+    /// belong to the request.
+    Absent,
+}
+
+/// Reads the header at the start of `socket` into `read`. Only header bytes
+/// are consumed, the first one that can't start a header is left in place.
+pub(crate) fn read_header(socket: &TcpStream, read: &mut Vec<u8>) -> io::Result<Progress> {
+    loop {
+        let need = if read.is_empty() {
+            // a request or a TLS handshake must not lose its first byte
+            let mut first = [0u8; 1];
+            match peek(socket, &mut first)? {
+                None => return Ok(Progress::Pending),
+                Some(_) if first[0] == V1_PREFIX[0] || first[0] == V2_SIGNATURE[0] => 1,
+                Some(_) => return Ok(Progress::Absent),
+            }
+        } else if V2_SIGNATURE.starts_with(&read[..read.len().min(V2_SIGNATURE.len())]) {
+            if read.len() < V2_HEADER_LEN {
+                V2_HEADER_LEN - read.len()
+            } else {
+                let len = V2_HEADER_LEN + u16::from_be_bytes([read[14], read[15]]) as usize;
+                if read.len() == len {
+                    return parse_v2(read).map(Progress::Done);
+                }
+                len - read.len()
+            }
+        } else if V1_PREFIX.starts_with(&read[..read.len().min(V1_PREFIX.len())]) {
+            if read.len() < V1_PREFIX.len() {
+                V1_PREFIX.len() - read.len()
+            } else if read.ends_with(b"\n") {
+                return parse_v1(read).map(Progress::Done);
+            } else if read.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY protocol line too long"));
+            } else {
+                // up to the end of the line, which may already be there
+                let mut line = [0u8; V1_MAX_LEN];
+                match peek(socket, &mut line[..V1_MAX_LEN - read.len()])? {
+                    Some(n) => line[..n].iter().position(|&b| b == b'\n').map_or(n, |i| i + 1),
+                    None => return Ok(Progress::Pending),
+                }
+            }
+        } else {
+            return Ok(Progress::Absent);
+        };
+
+        let start = read.len();
+        read.resize(start + need, 0);
+        let mut socket = socket;
+        let res = socket.read(&mut read[start..]);
+        read.truncate(start + *res.as_ref().unwrap_or(&0));
+        match res {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "connection closed in the PROXY protocol header",
+                ))
+            }
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Progress::Pending),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Copies what the socket has received into `buf` without consuming it,
+/// `None` if nothing was received yet.
+fn peek(socket: &TcpStream, buf: &mut [u8]) -> io::Result<Option<usize>> {
+    let res = syscall!(recv(
+        socket.as_raw_fd(),
+        buf.as_mut_ptr() as *mut libc::c_void,
+        buf.len(),
+        libc::MSG_PEEK | libc::MSG_DONTWAIT
+    ));
+    match res {
+        Ok(0) => Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            "connection closed in the PROXY protocol header",
+        )),
+        Ok(n) => Ok(Some(n as usize)),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<ProxyAddresses>> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .and_then(|line| std::str::from_utf8(line).ok())
+        .ok_or_else(|| invalid("invalid PROXY protocol line"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let ip = |addr: &str| -> io::Result<IpAddr> {
+                let ip = match *family {
+                    "TCP4" => addr.parse::<Ipv4Addr>().map(IpAddr::V4),
+                    _ => addr.parse::<Ipv6Addr>().map(IpAddr::V6),
+                };
+                ip.map_err(|_| invalid("invalid address in PROXY protocol line"))
+            };
+            let port = |port: &str| -> io::Result<u16> {
+                // no sign and no leading zeros
+                if port.starts_with(['+', '0']) && port != "0" {
+                    return Err(invalid("invalid port in PROXY protocol line"));
+                }
+                port.parse()
+                    .map_err(|_| invalid("invalid port in PROXY protocol line"))
+            };
+            Ok(Some(ProxyAddresses {
+                source: SocketAddr::new(ip(source)?, port(source_port)?),
+                destination: SocketAddr::new(ip(destination)?, port(destination_port)?),
+            }))
+        }
+        _ => Err(invalid("invalid PROXY protocol line")),
+    }
+}
+
+fn parse_v2(header: &[u8]) -> io::Result<Option<ProxyAddresses>> {
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    if version != 2 || command > 1 {
+        return Err(invalid("unsupported PROXY protocol version or command"));
+    }
+    // LOCAL, the proxy speaking for itself
+    if command == 0 {
+        return Ok(None);
+    }
+    let addresses = &header[V2_HEADER_LEN..];
+    let (source, destination) = match header[13] >> 4 {
+        // AF_INET
+        1 if addresses.len() >= 12 => (
+            IpAddr::from(<[u8; 4]>::try_from(&addresses[0..4]).unwrap()),
+            IpAddr::from(<[u8; 4]>::try_from(&addresses[4..8]).unwrap()),
+        ),
+        // AF_INET6
+        2 if addresses.len() >= 36 => (
+            IpAddr::from(<[u8; 16]>::try_from(&addresses[0..16]).unwrap()),
+            IpAddr::from(<[u8; 16]>::try_from(&addresses[16..32]).unwrap()),
+        ),
+        1 | 2 => return Err(invalid("truncated PROXY protocol addresses")),
+        // AF_UNSPEC and AF_UNIX carry no IP addresses
+        _ => return Ok(None),
+    };
+    let ports = match source {
+        IpAddr::V4(_) => &addresses[8..12],
+        IpAddr::V6(_) => &addresses[32..36],
+    };
+    Ok(Some(ProxyAddresses {
+        source: SocketAddr::new(source, u16::from_be_bytes([ports[0], ports[1]])),
+        destination: SocketAddr::new(destination, u16::from_be_bytes([ports[2], ports[3]])),
+    }))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
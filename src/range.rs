@@ -0,0 +1,214 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{HttpStatus, Request, ResponseWriter};
+
+// more ranges than this in one request are answered with the whole file
+const MAX_RANGES: usize = 64;
+
+/// Parts of the file body sent in a `206 Partial Content` response, each
+/// preceded by its multipart header when there are several.
+#[derive(Debug, Clone)]
+pub(crate) struct FileRanges {
+    pub(crate) parts: Vec<RangePart>,
+    pub(crate) trailer: String,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RangePart {
+    pub(crate) header: String,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+}
+
+impl FileRanges {
+    pub(crate) fn content_length(&self) -> u64 {
+        self.parts
+            .iter()
+            .map(|part| part.header.len() as u64 + part.len)
+            .sum::<u64>()
+            + self.trailer.len() as u64
+    }
+}
+
+/// Turns a `200 OK` file response into a `206` or `416` according to the
+/// `Range` and `If-Range` headers of `req`.
+pub(crate) fn apply(req: &Request, w: &mut ResponseWriter) {
+    let len = match w.file_len() {
+        Some(len) => len,
+        None => return,
+    };
+    if w.status_code.unwrap_or(200) != 200 {
+        return;
+    }
+    w.headers
+        .insert("Accept-Ranges".to_string(), vec!["bytes".to_string()]);
+
+    if req.method != "GET" {
+        return;
+    }
+    let range = match req.header("Range") {
+        Some(range) => range,
+        None => return,
+    };
+    if let Some(validator) = req.header("If-Range") {
+        if !if_range_matches(validator.trim(), w) {
+            return;
+        }
+    }
+    let ranges = match parse_range(range, len) {
+        Some(ranges) => ranges,
+        // unknown units and malformed ranges are ignored
+        None => return,
+    };
+
+    if ranges.is_empty() {
+        w.headers.insert(
+            "Content-Range".to_string(),
+            vec![format!("bytes */{}", len)],
+        );
+        w.headers.remove("Content-Type");
+        w.remove_header("Content-Length");
+        w.set_status(HttpStatus::RangeNotSatisfiable.code());
+        w.replace_body(HttpStatus::RangeNotSatisfiable.reason_phrase().as_bytes());
+        return;
+    }
+
+    w.set_status(HttpStatus::PartialContent.code());
+    w.remove_header("Content-Length");
+    if let [(start, end)] = ranges[..] {
+        w.headers.insert(
+            "Content-Range".to_string(),
+            vec![format!("bytes {}-{}/{}", start, end, len)],
+        );
+        w.set_file_ranges(FileRanges {
+            parts: vec![RangePart {
+                header: String::new(),
+                offset: start,
+                len: end - start + 1,
+            }],
+            trailer: String::new(),
+        });
+        return;
+    }
+
+    let boundary = boundary(w.event_id);
+    let content_type = w
+        .headers
+        .remove("Content-Type")
+        .and_then(|values| values.into_iter().next());
+    let parts = ranges
+        .iter()
+        .map(|&(start, end)| {
+            let mut header = format!("\r\n--{}\r\n", boundary);
+            if let Some(content_type) = &content_type {
+                header.push_str(&format!("Content-Type: {}\r\n", content_type));
+            }
+            header.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n\r\n", start, end, len));
+            RangePart {
+                header,
+                offset: start,
+                len: end - start + 1,
+            }
+        })
+        .collect();
+    w.headers.insert(
+        "Content-Type".to_string(),
+        vec![format!("multipart/byteranges; boundary={}", boundary)],
+    );
+    w.set_file_ranges(FileRanges {
+        parts,
+        trailer: format!("\r\n--{}--\r\n", boundary),
+    });
+}
+
+/// Parses a `Range` header against a body of `len` bytes into inclusive
+/// `(first, last)` pairs. `None` means the header must be ignored, an empty
+/// list that none of the ranges is satisfiable.
+fn parse_range(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+    let specs: Vec<&str> = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+        .collect();
+    // a header without any range is invalid, not unsatisfiable
+    if specs.is_empty() {
+        return None;
+    }
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        let range = if first.is_empty() {
+            // suffix range, the last `n` bytes
+            let suffix: u64 = last.parse().ok()?;
+            if suffix == 0 || len == 0 {
+                None
+            } else {
+                Some((len.saturating_sub(suffix), len - 1))
+            }
+        } else {
+            let first: u64 = first.parse().ok()?;
+            let last = match last {
+                "" => u64::MAX,
+                last => last.parse().ok()?,
+            };
+            if last < first {
+                return None;
+            }
+            if first >= len {
+                None
+            } else {
+                Some((first, last.min(len - 1)))
+            }
+        };
+        ranges.extend(range);
+        if ranges.len() > MAX_RANGES {
+            return None;
+        }
+    }
+
+    // overlapping ranges are coalesced so a file is never sent twice over
+    if ranges.len() > 1 {
+        ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+        for (first, last) in ranges {
+            match merged.last_mut() {
+                Some(prev) if first <= prev.1.saturating_add(1) => prev.1 = prev.1.max(last),
+                _ => merged.push((first, last)),
+            }
+        }
+        ranges = merged;
+    }
+    Some(ranges)
+}
+
+/// `If-Range` only matches a strong entity tag or the exact modification date
+/// of the response.
+fn if_range_matches(validator: &str, w: &ResponseWriter) -> bool {
+    let header = |name: &str| {
+        w.headers
+            .get(name)
+            .and_then(|values| values.first())
+            .map(|value| value.as_str())
+    };
+    if validator.starts_with('"') || validator.starts_with("W/") {
+        match header("ETag") {
+            Some(etag) => !etag.starts_with("W/") && etag == validator,
+            None => false,
+        }
+    } else {
+        header("Last-Modified") == Some(validator)
+    }
+}
+
+fn boundary(event_id: usize) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    format!("lolo{:08x}{:08x}", event_id as u32, nanos)
+}
@@ -1,14 +1,73 @@
 use std::collections::HashMap;
-use std::io::{self, Cursor, ErrorKind, Read};
-use std::net::TcpStream;
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use crate::buffer_pool::{self, PooledBuf, READ_CHUNK};
+use std::net::{SocketAddr, TcpStream};
+use std::time::SystemTime;
 use multipart::server::Multipart;
 use std::os::unix::io::AsRawFd;
-use crate::ServerId;
-use crate::{utils::*, EventId, HttpStatus, ResponseWriter, ROUTES, WRITE_CTX};
+use crate::poller::poller;
+use crate::compression::{Coding, DecodeError};
+use crate::http2::{self, StreamRef};
+use crate::proxy_protocol::{self, Progress};
+use crate::transport::Transport;
+#[cfg(feature = "tls")]
+use crate::ClientCertificate;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use crate::websocket::{self, WebSocket, WebSocketEvent, WebSocketUpgrade};
+use crate::{compression, conditional, range, server_config, sse, static_files, upgrade, ServerId};
+use crate::{server_allow, EventId, HttpStatus, Interest, ResponseWriter, Route, ROUTES, WRITE_CTX};
+use crate::{ProxyAddresses, ProxyProtocol};
+
+/// What is known of a connection when it is accepted, shared by every
+/// request it carries.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ConnectionInfo {
+    pub(crate) id: EventId,
+    pub(crate) remote_addr: Option<SocketAddr>,
+    pub(crate) local_addr: Option<SocketAddr>,
+    // from the PROXY protocol header, once read
+    pub(crate) proxy_addresses: Option<ProxyAddresses>,
+}
+
+const MAX_BODY_RESERVE: usize = 1024 * 1024;
+// decoded size of a compressed body when no limit is configured
+const DEFAULT_MAX_DECODED_BODY: usize = 16 * 1024 * 1024;
+// size of a WebSocket message when no body limit is configured
+const DEFAULT_MAX_MESSAGE: usize = 16 * 1024 * 1024;
+// request line and headers, larger heads are answered with a 431
+const MAX_HEAD_SIZE: usize = 16 * 1024;
 
 pub struct Request {
     header_done: bool,
+    handled: bool,
+    // answered before the body is received, see `check_expectation`
+    skip_body: bool,
+    // the head couldn't be parsed, answered with this status
+    rejected: Option<HttpStatus>,
+    // a `100 Continue` the socket couldn't take at once, sent before the
+    // final response
+    interim: Vec<u8>,
+    interim_written: usize,
+    pub(crate) edge_triggered: bool,
+    // raw bytes received until the request head is parsed
+    head: PooledBuf,
+    // from the server config, read once the head is parsed
+    body_limit: Option<usize>,
     server_id: ServerId,
+    // set by a successful WebSocket handshake, until the connection is handed over
+    pub(crate) websocket: Option<WebSocketUpgrade>,
+    // the connection continues in HTTP/2 once handed over
+    switch_to_http2: bool,
+    // the HTTP/2 stream the request arrived on
+    pub(crate) http2: Option<StreamRef>,
+    // until the header is read
+    pub(crate) proxy_protocol: ProxyProtocol,
+    pub(crate) connection: ConnectionInfo,
+    // position of the request on its connection, from 1
+    pub(crate) sequence_number: u64,
+    #[cfg(feature = "tls")]
+    client_certificate: Option<Arc<ClientCertificate>>,
     pub stream: TcpStream,
     pub method: String,
     pub path: String,
@@ -23,8 +82,24 @@ impl Request {
         Self {
             cookies: HashMap::new(),
             header_done: false,
+            handled: false,
+            skip_body: false,
+            rejected: None,
+            interim: Vec::new(),
+            interim_written: 0,
+            edge_triggered: false,
+            head: PooledBuf::take(),
+            body_limit: None,
             body: Vec::new(),
             server_id,
+            websocket: None,
+            switch_to_http2: false,
+            http2: None,
+            proxy_protocol: ProxyProtocol::Disabled,
+            connection: ConnectionInfo::default(),
+            sequence_number: 1,
+            #[cfg(feature = "tls")]
+            client_certificate: None,
             stream,
             headers: HashMap::new(),
             path: String::default(),
@@ -32,6 +107,73 @@ impl Request {
             method: String::default(),
         }
     }
+    /// A request received on an HTTP/2 stream. Field names get their usual
+    /// capitalization back and `:authority` becomes `Host`.
+    pub(crate) fn from_http2(
+        stream: TcpStream,
+        server_id: ServerId,
+        http2: StreamRef,
+        fields: Vec<(String, String)>,
+        body: Vec<u8>,
+        body_len: usize,
+    ) -> Self {
+        let mut request = Request::new(stream, server_id);
+        let mut authority = None;
+        let mut cookies = Vec::new();
+        for (name, value) in fields {
+            match name.as_str() {
+                ":method" => request.method = value,
+                ":path" => request.path = value,
+                ":authority" => authority = Some(value),
+                // the length is the one of the DATA frames received
+                ":scheme" | "content-length" => {}
+                // may be split in several fields to compress better
+                "cookie" => cookies.push(value),
+                _ => {
+                    let name = title_case(&name);
+                    match request.headers.get_mut(&name) {
+                        Some(existing) => {
+                            existing.push_str(", ");
+                            existing.push_str(&value);
+                        }
+                        None => {
+                            request.headers.insert(name, value);
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(authority) = authority {
+            request.headers.insert("Host".to_string(), authority);
+        }
+        if !cookies.is_empty() {
+            request
+                .headers
+                .insert("Cookie".to_string(), cookies.join("; "));
+            request.parse_cookies();
+        }
+        if body_len > 0 {
+            request
+                .headers
+                .insert("Content-Length".to_string(), body_len.to_string());
+        }
+        request.protocol = "HTTP/2".to_string();
+        request.header_done = true;
+        request.body_limit = Some(
+            server_config(server_id)
+                .max_body_size
+                .unwrap_or(http2::DEFAULT_MAX_BODY),
+        );
+        request.body = body;
+        request.http2 = Some(http2);
+        request
+    }
+
+    /// The server the request was received by, see `HttpServer::server_id`.
+    pub fn server_id(&self) -> ServerId {
+        self.server_id
+    }
+
     pub fn parse_multipart(&mut self) -> Option<HashMap<String, Vec<u8>>> {
         let boundary = self.get_boundary()?;
         let mut multipart = Multipart::with_body(Cursor::new(&self.body), boundary);
@@ -59,97 +201,553 @@ impl Request {
 
         Some(form_data)
     }
+    /// Looks a header up regardless of the case of its name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(name)
+            .or_else(|| {
+                self.headers
+                    .iter()
+                    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                    .map(|(_, value)| value)
+            })
+            .map(|value| value.as_str())
+    }
+    /// The addresses of the client and of the proxy it connected to, from
+    /// the PROXY protocol header of the connection. See
+    /// `HttpServer::set_proxy_protocol`.
+    pub fn proxy_addresses(&self) -> Option<ProxyAddresses> {
+        self.connection.proxy_addresses
+    }
+    /// The address of the peer of the connection. Behind a proxy this is the
+    /// proxy, see `proxy_addresses` for the client it relays.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.connection.remote_addr
+    }
+    /// The address the connection was accepted on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.connection.local_addr
+    }
+    /// Identifies the connection the request arrived on among the ones
+    /// currently open, HTTP/2 streams share the id of their connection.
+    pub fn connection_id(&self) -> EventId {
+        self.connection.id
+    }
+    /// The position of the request among the ones received on its
+    /// connection, starting at 1. Only HTTP/2 connections carry more than
+    /// one.
+    pub fn sequence_number(&self) -> u64 {
+        self.sequence_number
+    }
+    /// The certificate the client authenticated with, on servers asking for
+    /// one with `HttpServer::verify_client_certificates`.
+    #[cfg(feature = "tls")]
+    pub fn client_certificate(&self) -> Option<&ClientCertificate> {
+        self.client_certificate.as_deref()
+    }
+    /// Whether the `If-Match`, `If-Unmodified-Since` and `If-None-Match`
+    /// headers allow changing a resource whose current representation has
+    /// these validators. The server only evaluates them by itself for `GET`
+    /// and `HEAD`, after the handler ran; handlers of other methods check
+    /// them before acting and answer `412 Precondition Failed` otherwise.
+    pub fn preconditions_hold(&self, etag: Option<&str>, last_modified: Option<SystemTime>) -> bool {
+        conditional::preconditions_hold(self, etag, last_modified)
+    }
+    /// The id of the last event received by a client reconnecting to an
+    /// event stream, see `ResponseWriter::event_stream`. Past events are not
+    /// kept by the server, replaying the ones sent after this id is up to
+    /// the handler.
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.header("Last-Event-ID")
+    }
     fn get_boundary(&self) -> Option<String> {
         let content_type = self.headers.get("Content-Type")?;
 
         content_type.split(';').find_map(|part| {
             let part = part.trim();
-            if part.starts_with("boundary=") {
-                Some(part[9..].trim_matches('"').to_string())
-            } else {
-                None
-            }
+            part.strip_prefix("boundary=")
+                .map(|boundary| boundary.trim_matches('"').to_string())
         })
     }
-    pub fn read_cb(&mut self, event_id: EventId, epoll_fd: i32) -> io::Result<()> {
-        let mut buffer = Vec::new();
-        let mut temp_buf = [0u8; 4096];
-
-        match self.stream.read(&mut temp_buf) {
-            Ok(n) => {
-                buffer.extend_from_slice(&temp_buf[..n]);
+    /// Reads what the socket has to offer and runs the handler once the
+    /// request is complete. Returns `true` when the response has already been
+    /// written and the connection can be dropped.
+    pub fn read_cb(&mut self, event_id: EventId) -> io::Result<bool> {
+        if self.proxy_protocol != ProxyProtocol::Disabled && !self.read_proxy_header(event_id)? {
+            if !self.edge_triggered {
+                poller().modify(self.stream.as_raw_fd(), event_id as u64, Interest::Read)?;
             }
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                return Ok(());
-            }
-            Err(e) => return Err(e),
+            return Ok(false);
         }
+        self.send_interim(event_id)?;
+        let mut closed = false;
 
-        if !buffer.is_empty() {
-            if !self.header_done {
-                self.parse_request(&buffer);
+        loop {
+            // the body is read in place, everything else lands in `head`
+            let res = if self.header_done && !self.handled {
+                buffer_pool::read_into(&mut self.body, &mut Transport::new(event_id, &self.stream))
             } else {
-                self.body.extend_from_slice(&buffer);
+                self.head.read_from(&mut Transport::new(event_id, &self.stream))
+            };
+            match res {
+                Ok(0) => {
+                    closed = true;
+                    break;
+                }
+                Ok(n) => {
+                    if self.handled {
+                        // pipelined bytes while the response is deferred are
+                        // ignored, the ones following an upgrade are kept
+                        if self.websocket.is_none() && !upgrade::is_pending(event_id) {
+                            self.head.clear();
+                        }
+                    } else {
+                        if !self.header_done {
+                            match http2::preface(&self.head) {
+                                Some(true) if http2::enabled(self.server_id) => {
+                                    self.handled = true;
+                                    self.switch_to_http2 = true;
+                                    break;
+                                }
+                                // the rest of the preface is on its way
+                                Some(false) if http2::enabled(self.server_id) => {}
+                                _ => {
+                                    self.parse_request();
+                                    if self.rejected.is_some() {
+                                        break;
+                                    }
+                                    if self.header_done && !self.is_complete() {
+                                        self.check_expectation(event_id)?;
+                                    }
+                                }
+                            }
+                        }
+                        if self.is_complete() {
+                            break;
+                        }
+                    }
+                    // with one-shot interest a short read means the socket is
+                    // drained, edge-triggered sockets must hit WouldBlock
+                    if !self.edge_triggered && n < READ_CHUNK {
+                        break;
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
             }
+        }
 
-            if self.header_done && self.body.len() >= self.content_length() {
-                self.handle_complete_request(event_id, epoll_fd)?;
+        if self.switch_to_http2 {
+            return Ok(true);
+        }
+        if let Some(status) = self.rejected.take() {
+            return self.reject(event_id, status);
+        }
+        if !self.handled && self.is_complete() && self.interim.is_empty() {
+            return self.handle_complete_request(event_id);
+        }
+        if closed {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before the response was sent",
+            ));
+        }
+        if !self.edge_triggered {
+            // handshake records the socket couldn't take yet
+            let interest = if Transport::new(event_id, &self.stream).wants_write()
+                || !self.interim.is_empty()
+            {
+                Interest::ReadWrite
             } else {
-                modify_interest(
-                    epoll_fd,
-                    self.stream.as_raw_fd(),
-                    listener_read_event(event_id as u64),
-                )?;
+                Interest::Read
+            };
+            poller().modify(self.stream.as_raw_fd(), event_id as u64, interest)?;
+        }
+        Ok(false)
+    }
+
+    /// Whether the request is still being received, a TLS connection may
+    /// wait for writability meanwhile.
+    pub(crate) fn is_reading(&self) -> bool {
+        !self.handled
+    }
+
+    /// Reads the PROXY protocol header the connection starts with, returns
+    /// `false` until it is complete.
+    fn read_proxy_header(&mut self, event_id: EventId) -> io::Result<bool> {
+        let invalid = |message| io::Error::new(ErrorKind::InvalidData, message);
+        // the bytes read land in `head`, where they belong if they turn out
+        // to start the request
+        match proxy_protocol::read_header(&self.stream, &mut self.head)? {
+            Progress::Pending => return Ok(false),
+            Progress::Done(addresses) => {
+                self.head.clear();
+                self.connection.proxy_addresses = addresses;
             }
+            Progress::Absent if self.proxy_protocol == ProxyProtocol::Required => {
+                return Err(invalid("missing PROXY protocol header"));
+            }
+            Progress::Absent
+                if !self.head.is_empty() && Transport::new(event_id, &self.stream).is_secure() =>
+            {
+                return Err(invalid("invalid PROXY protocol header"));
+            }
+            Progress::Absent => {}
         }
+        self.proxy_protocol = ProxyProtocol::Disabled;
+        Ok(true)
+    }
 
+    fn is_complete(&self) -> bool {
+        // an oversized body is rejected without waiting for it
+        self.header_done
+            && (self.body.len() >= self.content_length() || self.body_too_large() || self.skip_body)
+    }
+
+    /// Handles `Expect: 100-continue` once the head is parsed. The client is
+    /// told to send the body only if a handler will accept it, otherwise the
+    /// final status is sent right away and the body is never read.
+    fn check_expectation(&mut self, event_id: EventId) -> io::Result<()> {
+        let expect = match self.header("Expect") {
+            Some(expect) => expect,
+            None => return Ok(()),
+        };
+        if !expect.trim().eq_ignore_ascii_case("100-continue") || !self.route_accepts() {
+            self.skip_body = true;
+            return Ok(());
+        }
+        // HTTP/1.0 clients don't know about interim responses
+        if self.protocol == "HTTP/1.1" {
+            self.interim.extend_from_slice(b"HTTP/1.1 100 Continue\r\n\r\n");
+            self.send_interim(event_id)?;
+        }
         Ok(())
     }
 
-    fn handle_complete_request(&mut self, event_id: EventId, epoll_fd: i32) -> io::Result<()> {
+    /// Writes what is left of the interim response. The request is handled
+    /// only once it is sent, the final response must not overtake it.
+    fn send_interim(&mut self, event_id: EventId) -> io::Result<()> {
+        if self.interim.is_empty() {
+            return Ok(());
+        }
+        let mut transport = Transport::new(event_id, &self.stream);
+        transport.write_buffered(&mut self.interim, &mut self.interim_written)?;
+        if !self.interim.is_empty() || transport.wants_write() {
+            // the rest waits for writability, which edge-triggered
+            // connections don't watch
+            self.edge_triggered = false;
+        }
+        Ok(())
+    }
+
+    fn route_accepts(&self) -> bool {
+        let key = self.path.clone() + "." + self.server_id.to_string().as_str();
+        match ROUTES.lock().unwrap().get(&key) {
+            Some(route) => route.handler(&self.method).is_some(),
+            None => static_files::accepts(self.server_id, &self.method, &self.path),
+        }
+    }
+
+    fn expectation_failed(&self) -> bool {
+        self.header("Expect")
+            .is_some_and(|expect| !expect.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    fn body_too_large(&self) -> bool {
+        self.body_limit
+            .is_some_and(|limit| self.content_length() > limit)
+    }
+
+    /// Decodes a body sent with `Content-Encoding`, so handlers always see
+    /// the original bytes. Returns the status to answer with when the body
+    /// is refused.
+    fn decode_body(&mut self) -> Result<(), HttpStatus> {
+        if self.body_too_large() {
+            return Err(HttpStatus::PayloadTooLarge);
+        }
+        if self.skip_body {
+            return Ok(());
+        }
+        let encoding = match self.header("Content-Encoding") {
+            Some(encoding) => encoding.to_string(),
+            None => return Ok(()),
+        };
+        let limit = self.body_limit.unwrap_or(DEFAULT_MAX_DECODED_BODY);
+        // codings are listed in the order they were applied
+        for name in encoding.rsplit(',').map(str::trim) {
+            if name.is_empty() || name.eq_ignore_ascii_case("identity") {
+                continue;
+            }
+            let coding = Coding::from_name(name).ok_or(HttpStatus::UnsupportedMediaType)?;
+            let decoded = compression::decode(coding, &self.body, limit).map_err(|e| match e {
+                DecodeError::TooLarge => HttpStatus::PayloadTooLarge,
+                DecodeError::Invalid => HttpStatus::BadRequest,
+            })?;
+            buffer_pool::recycle(std::mem::replace(&mut self.body, decoded));
+        }
+        self.headers.retain(|name, _| {
+            !name.eq_ignore_ascii_case("Content-Encoding")
+                && !name.eq_ignore_ascii_case("Content-Length")
+        });
+        self.headers
+            .insert("Content-Length".to_string(), self.body.len().to_string());
+        Ok(())
+    }
+
+    /// Answers a head that couldn't be parsed, the connection is closed once
+    /// the response is sent.
+    fn reject(&mut self, event_id: EventId, status: HttpStatus) -> io::Result<bool> {
+        self.handled = true;
+        self.head.clear();
+        let mut response_writer = ResponseWriter::new(self.stream.try_clone()?, event_id);
+        response_writer.write_status(status);
+        self.respond(event_id)
+    }
+
+    /// Runs the handler of a complete request. Returns `true` once the
+    /// response is sent, `false` while it is being written or deferred.
+    pub(crate) fn handle_complete_request(&mut self, event_id: EventId) -> io::Result<bool> {
+        self.handled = true;
+        #[cfg(feature = "tls")]
+        {
+            // HTTP/2 streams share the session of their connection
+            let connection = self.http2.as_ref().map_or(event_id, |stream| stream.conn);
+            self.client_certificate = crate::tls::client_certificate(connection);
+        }
         let stream_clone = self.stream.try_clone()?;
         let mut response_writer = ResponseWriter::new(stream_clone, event_id);
 
-        match ROUTES
-            .lock()
-            .unwrap()
-            .get(&(self.path.clone() + "." + self.server_id.to_string().as_str()))
-        {
-            Some(handler) => {
-                handler(self, &mut response_writer);
+        if self.expectation_failed() {
+            response_writer.write_status(HttpStatus::ExpectationFailed);
+            self.finalize(event_id);
+            return self.respond(event_id);
+        }
+        if let Err(status) = self.decode_body() {
+            if matches!(status, HttpStatus::UnsupportedMediaType) {
+                response_writer
+                    .headers
+                    .insert("Accept-Encoding".to_string(), vec![Coding::accepted()]);
             }
+            response_writer.write_status(status);
+            self.finalize(event_id);
+            return self.respond(event_id);
+        }
+        let mut transport = Transport::new(event_id, &self.stream);
+        // h2c is for cleartext connections only
+        if http2::wants_upgrade(self) && !transport.is_secure() {
+            // the request is answered on stream 1 once the connection is
+            // handed over
+            transport.write_all(http2::SWITCHING_PROTOCOLS)?;
+            transport.flush()?;
+            self.switch_to_http2 = true;
+            return Ok(true);
+        }
+
+        let routes = ROUTES.lock().unwrap();
+        if self.method == "OPTIONS" && self.path == "*" {
+            response_writer.write_allow(server_allow(&routes, self.server_id));
+        } else {
+            self.dispatch(&routes, &mut response_writer);
+        }
+        drop(routes);
+
+        if response_writer.is_deferred() {
+            return Ok(false);
+        }
+
+        self.finalize(event_id);
+        self.respond(event_id)
+    }
+
+    fn dispatch(&mut self, routes: &HashMap<String, Route>, response_writer: &mut ResponseWriter) {
+        match routes.get(&(self.path.clone() + "." + self.server_id.to_string().as_str())) {
+            // an HTTP/2 stream can't switch protocols
+            Some(route) if self.wants_websocket(route) && self.http2.is_some() => {
+                match route.handler("GET") {
+                    Some(handler) => handler(self, response_writer),
+                    None => response_writer.write_status(HttpStatus::HttpVersionNotSupported),
+                }
+            }
+            Some(route) if self.wants_websocket(route) => {
+                let handler = route.websocket.clone().unwrap();
+                if websocket::handshake(self, response_writer) {
+                    let max_message = self.body_limit.unwrap_or(DEFAULT_MAX_MESSAGE);
+                    handler(
+                        &WebSocket::new(response_writer.event_id),
+                        WebSocketEvent::Open(self),
+                    );
+                    self.websocket = Some(WebSocketUpgrade {
+                        handler,
+                        max_message,
+                    });
+                }
+            }
+            Some(route) => match route.handler(&self.method) {
+                Some(handler) => handler(self, response_writer),
+                None if self.method == "OPTIONS" => response_writer.write_allow(route.allow()),
+                None => {
+                    response_writer
+                        .headers
+                        .insert("Allow".to_string(), vec![route.allow()]);
+                    response_writer.write_status(HttpStatus::MethodNotAllowed);
+                }
+            },
             None => {
-                response_writer.write_status(HttpStatus::NotFound);
+                if !static_files::serve(self.server_id, self, response_writer) {
+                    response_writer.write_status(HttpStatus::NotFound);
+                }
             }
         }
+    }
 
-        modify_interest(
-            epoll_fd,
-            self.stream.as_raw_fd(),
-            listener_write_event(event_id as u64),
-        )
+    /// A `GET` on a WebSocket route is a handshake, unless a plain handler
+    /// is also registered and the request does not ask for an upgrade.
+    fn wants_websocket(&self, route: &Route) -> bool {
+        route.websocket.is_some()
+            && self.method == "GET"
+            && (self.header("Upgrade").is_some() || route.handler("GET").is_none())
     }
-    pub fn write_cb(&self, event_id: EventId) {
-        if let Some(ctx) = WRITE_CTX
-            .lock()
-            .expect("can lock request_contexts")
-            .get_mut(&event_id)
-        {
-            ctx.excute(self.stream.as_raw_fd())
+
+    /// Takes the socket away from the loop once the response is sent, along
+    /// with the bytes received after the request.
+    pub(crate) fn detach(mut self) -> io::Result<(TcpStream, Vec<u8>)> {
+        self.take_socket()
+    }
+
+    /// Like `detach`, for a connection switching to HTTP/2. A request that
+    /// asked for `Upgrade: h2c` is returned too, to be answered on stream 1.
+    pub(crate) fn into_http2(mut self) -> io::Result<(TcpStream, Vec<u8>, Option<Request>)> {
+        let (stream, leftover) = self.take_socket()?;
+        // only the preface was received
+        if !self.header_done {
+            return Ok((stream, leftover, None));
         }
+        self.handled = false;
+        self.switch_to_http2 = false;
+        self.protocol = "HTTP/2".to_string();
+        self.headers.retain(|name, _| {
+            !["Connection", "Upgrade", "HTTP2-Settings"]
+                .iter()
+                .any(|hop| name.eq_ignore_ascii_case(hop))
+        });
+        Ok((stream, leftover, Some(self)))
+    }
 
-        WRITE_CTX
-            .lock()
-            .expect("can lock request contexts")
-            .remove(&event_id);
+    fn take_socket(&mut self) -> io::Result<(TcpStream, Vec<u8>)> {
+        let _ = poller().remove(self.stream.as_raw_fd());
+        // the duplicated descriptor outlives the request
+        let stream = self.stream.try_clone()?;
+        let mut leftover = self.body.split_off(self.content_length().min(self.body.len()));
+        leftover.extend_from_slice(&self.head);
+        self.head.clear();
+        Ok((stream, leftover))
     }
 
-    fn parse_request(&mut self, data: &[u8]) {
+    /// Adjusts the response written by the handler to the request headers
+    /// before it is sent.
+    pub(crate) fn finalize(&self, event_id: EventId) {
+        let config = server_config(self.server_id);
+        if let Some(w) = WRITE_CTX.lock().unwrap().get_mut(&event_id) {
+            if self.method == "HEAD" {
+                w.omit_body();
+            }
+            // the head of a WebSocket handshake or an event stream
+            if w.is_upgraded() {
+                return;
+            }
+            conditional::add_validators(w, &config);
+            let coding = compression::negotiate(self, w, &config);
+            conditional::evaluate(self, w);
+            if let Some(coding) = coding {
+                if w.status_code.unwrap_or(200) == 200 {
+                    compression::encode(w, coding, &config);
+                }
+            }
+            range::apply(self, w);
+        }
+    }
+
+    /// Sends the response stored for `event_id`, right away on edge-triggered
+    /// connections, otherwise once the socket reports it is writable.
+    pub(crate) fn respond(&mut self, event_id: EventId) -> io::Result<bool> {
+        if let Some(stream) = self.http2 {
+            return Ok(http2::respond(stream, event_id));
+        }
+        if self.edge_triggered && self.write_cb(event_id) {
+            return Ok(true);
+        }
+        // what did not fit in the socket buffer waits for EPOLLOUT like in
+        // one-shot mode, since edge-triggered connections only watch reads
+        self.edge_triggered = false;
+        poller().modify(self.stream.as_raw_fd(), event_id as u64, Interest::Write)?;
+        Ok(false)
+    }
+
+    /// Continues writing the response stored for `event_id`. Returns `true`
+    /// once the connection is done, either sent entirely or failed.
+    pub fn write_cb(&mut self, event_id: EventId) -> bool {
+        let mut write_contexts = WRITE_CTX.lock().expect("can lock write contexts");
+        let done = match write_contexts.get_mut(&event_id) {
+            Some(ctx) => match ctx.excute(self.stream.as_raw_fd()) {
+                Ok(done) => done,
+                Err(e) => {
+                    eprintln!("error writing response {}: {}", event_id, e);
+                    let _ = poller().remove(self.stream.as_raw_fd());
+                    self.abandon_upgrade(event_id);
+                    true
+                }
+            },
+            None => true,
+        };
+        if done {
+            write_contexts.remove(&event_id);
+        }
+        done
+    }
+
+    /// Passes the connection on once the response is sent, when it switches
+    /// to HTTP/2, is upgraded to a WebSocket or streams events. Other
+    /// requests are dropped.
+    pub(crate) fn hand_over(self, event_id: EventId) {
+        if self.switch_to_http2 {
+            http2::adopt(event_id, self);
+        } else if self.websocket.is_some() {
+            websocket::adopt(event_id, self);
+        } else if sse::is_pending(event_id) {
+            sse::adopt(event_id, self);
+        } else if upgrade::is_pending(event_id) {
+            upgrade::adopt(event_id, self);
+        } else {
+            Transport::new(event_id, &self.stream).close();
+        }
+    }
+
+    /// Gives up on a connection that failed before being handed over.
+    pub(crate) fn abandon_upgrade(&mut self, event_id: EventId) {
+        if let Some(upgrade) = self.websocket.take() {
+            websocket::abandon(event_id, upgrade);
+        }
+        sse::abandon(event_id);
+        upgrade::abandon(event_id);
+    }
+
+    fn parse_request(&mut self) {
         let mut headers = [httparse::EMPTY_HEADER; 64];
         let mut req = httparse::Request::new(&mut headers);
 
-        match req.parse(data) {
+        match req.parse(&self.head) {
             Ok(status) => {
+                // an incomplete head stays in the buffer until more bytes
+                // arrive, up to a limit
+                let head_len = match status {
+                    httparse::Status::Complete(headers_len) => headers_len,
+                    httparse::Status::Partial => self.head.len(),
+                };
+                if head_len > MAX_HEAD_SIZE {
+                    self.rejected = Some(HttpStatus::RequestHeaderFieldsTooLarge);
+                    return;
+                }
                 if let httparse::Status::Complete(headers_len) = status {
                     self.method = req.method.unwrap_or("").to_string();
                     self.path = req.path.unwrap_or("").to_string();
@@ -165,27 +763,29 @@ impl Request {
                         }
                     }
 
-                    if let Some(cookie_header) = self.headers.get("Cookie") {
-                        for cookie in cookie_header.split(';') {
-                            if let Some((key, value)) = cookie.trim().split_once('=') {
-                                self.cookies.insert(key.to_string(), value.to_string());
-                            }
-                        }
-                    }
+                    self.parse_cookies();
 
                     self.header_done = true;
+                    self.body_limit = server_config(self.server_id).max_body_size;
 
-                    // Ajout du corps si présent
-                    if headers_len < data.len() {
-                        self.body.extend_from_slice(&data[headers_len..]);
-                    }
-                } else {
-                    // Si l'analyse n'est pas complète, on stocke les données partielles
-                    self.body.extend_from_slice(data);
+                    let mut body = PooledBuf::take().into_vec();
+                    // the announced length is only a hint, don't let it size the allocation
+                    body.reserve(self.content_length().min(MAX_BODY_RESERVE));
+                    body.extend_from_slice(&self.head[headers_len..]);
+                    self.body = body;
+                    self.head.clear();
                 }
             }
-            Err(e) => {
-                eprintln!("Erreur lors de l'analyse de la requête : {:?}", e);
+            Err(_) => self.rejected = Some(HttpStatus::BadRequest),
+        }
+    }
+
+    fn parse_cookies(&mut self) {
+        if let Some(cookie_header) = self.headers.get("Cookie") {
+            for cookie in cookie_header.split(';') {
+                if let Some((key, value)) = cookie.trim().split_once('=') {
+                    self.cookies.insert(key.to_string(), value.to_string());
+                }
             }
         }
     }
@@ -197,3 +797,20 @@ impl Request {
             .unwrap_or(0)
     }
 }
+
+/// `content-type` to `Content-Type`, HTTP/2 sends names in lowercase.
+fn title_case(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut upper = true;
+    for c in name.chars() {
+        out.push(if upper { c.to_ascii_uppercase() } else { c });
+        upper = c == '-';
+    }
+    out
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        buffer_pool::recycle(std::mem::take(&mut self.body));
+    }
+}
@@ -1,15 +1,91 @@
 pub use http_status::*;
 pub use json::*;
-use std::collections::HashMap;
-use std::io::{self, Write};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
 use std::net::TcpStream;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
-use std::{fs, str};
+use std::time::{Duration, SystemTime};
+use std::str;
+
+use crate::buffer_pool::{self, PooledBuf};
+use crate::poller::poller;
+use crate::range::FileRanges;
+use crate::sse::{self, EventStream};
+use crate::transport::Transport;
+use crate::upgrade::{self, Upgraded};
+use crate::utils::sendfile;
+use crate::{ http_status, DeferredResponse, EventId, WRITE_CTX};
+
+// file bytes sent per writable event before yielding to other connections
+const SENDFILE_BUDGET: usize = 1024 * 1024;
+
+/// Copies file bytes through the TLS session of the connection, `sendfile(2)`
+/// would bypass the encryption. Returns how many were accepted.
+fn send_encrypted(
+    transport: &mut Transport,
+    file: &File,
+    offset: &mut u64,
+    count: usize,
+) -> io::Result<usize> {
+    let mut chunk = [0u8; 16 * 1024];
+    let count = count.min(chunk.len());
+    let read = file.read_at(&mut chunk[..count], *offset)?;
+    if read == 0 {
+        return Ok(0);
+    }
+    let n = transport.write(&chunk[..read])?;
+    *offset += n as u64;
+    Ok(n)
+}
+
+/// A file sent as the response body with `sendfile(2)`.
+#[derive(Debug)]
+pub(crate) struct FileBody {
+    pub(crate) file: File,
+    pub(crate) len: u64,
+    pub(crate) modified: Option<SystemTime>,
+}
+
+impl FileBody {
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(FileBody {
+            file: self.file.try_clone()?,
+            len: self.len,
+            modified: self.modified,
+        })
+    }
+}
+
+#[derive(Debug)]
+enum Segment {
+    Bytes(PooledBuf),
+    // the body built in memory
+    Body,
+    File { offset: u64, len: u64 },
+}
+
+/// What is left to send of a response that did not fit in the socket buffer.
+#[derive(Debug, Default)]
+struct Outgoing {
+    segments: VecDeque<Segment>,
+    written: usize,
+}
 
-use crate::{ http_status, remove_interest, EventId, EPOLL_FD, WRITE_CTX};
 #[derive(Debug)]
 pub struct ResponseWriter {
-    event_id: EventId,
+    pub(crate) event_id: EventId,
+    deferred: bool,
+    // HEAD responses announce the length of a body they don't send
+    head_only: bool,
+    file: Option<FileBody>,
+    // set when only parts of the file are sent
+    ranges: Option<FileRanges>,
+    outgoing: Option<Outgoing>,
+    // the connection outlives the response, see `keep_open`
+    upgraded: bool,
     pub body: Vec<u8>,
     pub stream: TcpStream,
     pub headers: HashMap<String, Vec<String>>,
@@ -19,7 +95,13 @@ impl ResponseWriter {
     pub fn new(stream: TcpStream, event_fd_id: EventId) -> Self {
         ResponseWriter {
             event_id: event_fd_id,
-            body: Vec::new(),
+            deferred: false,
+            head_only: false,
+            file: None,
+            ranges: None,
+            outgoing: None,
+            upgraded: false,
+            body: PooledBuf::take().into_vec(),
             stream,
             headers: HashMap::new(),
             status_code: None,
@@ -28,26 +110,160 @@ impl ResponseWriter {
     pub fn execute_html_file(&mut self, file_path: &str) -> io::Result<()> {
         let path = Path::new(file_path);
         if path.exists() && path.is_file() {
-            let contents = fs::read_to_string(path)?;
             self.headers
                 .insert("Content-Type".to_string(), vec!["text/html".to_string()]);
-            self.body = contents.into_bytes();
-            self.write();
-            Ok(())
+            self.write_file(path)
         } else {
             self.set_status(404);
             self.write_string("File not found");
             Err(io::Error::new(io::ErrorKind::NotFound, "File not found"))
         }
     }
+
+    /// Uses the content of the file at `path` as the body. The file is never
+    /// loaded in memory, it is streamed to the socket with `sendfile(2)`.
+    pub fn write_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "not a regular file"));
+        }
+        self.body.clear();
+        self.ranges = None;
+        self.file = Some(FileBody {
+            file,
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+        self.write();
+        Ok(())
+    }
+
+    pub(crate) fn omit_body(&mut self) {
+        self.head_only = true;
+    }
+
+    /// Answers an `OPTIONS` request with `204 No Content`.
+    pub(crate) fn write_allow(&mut self, allow: String) {
+        self.headers.insert("Allow".to_string(), vec![allow]);
+        self.body.clear();
+        self.set_status(HttpStatus::NoContent.code());
+        self.write();
+    }
+
+    /// Leaves the socket open and registered once the response is sent, for
+    /// protocols taking over the connection after a `101`.
+    pub(crate) fn keep_open(&mut self) {
+        self.upgraded = true;
+    }
+
+    pub(crate) fn is_upgraded(&self) -> bool {
+        self.upgraded
+    }
+
+    pub(crate) fn file_len(&self) -> Option<u64> {
+        self.file.as_ref().map(|file| file.len)
+    }
+
+    pub(crate) fn file_modified(&self) -> Option<SystemTime> {
+        self.file.as_ref().and_then(|file| file.modified)
+    }
+
+    pub(crate) fn set_file_ranges(&mut self, ranges: FileRanges) {
+        self.ranges = Some(ranges);
+    }
+
+    /// Drops the file body, if any, in favour of `body`.
+    pub(crate) fn replace_body(&mut self, body: &[u8]) {
+        self.file = None;
+        self.ranges = None;
+        self.body.clear();
+        self.body.extend_from_slice(body);
+    }
+
     fn clone(&self) -> Self {
-        ResponseWriter {
-            body: self.body.clone(),
+        let mut clone = ResponseWriter {
+            body: {
+                let mut body = PooledBuf::take().into_vec();
+                body.extend_from_slice(&self.body);
+                body
+            },
             stream: self.stream.try_clone().unwrap(),
             headers: self.headers.clone(),
-            status_code: self.status_code.clone(),
-            event_id: self.event_id.clone(),
+            status_code: self.status_code,
+            event_id: self.event_id,
+            deferred: self.deferred,
+            head_only: self.head_only,
+            file: None,
+            ranges: self.ranges.clone(),
+            outgoing: None,
+            upgraded: self.upgraded,
+        };
+        if let Some(file) = &self.file {
+            match file.try_clone() {
+                Ok(file) => clone.file = Some(file),
+                // sending the head without its body would pass for a
+                // complete, empty response
+                Err(e) => {
+                    eprintln!("couldn't duplicate the body of response {}: {}", self.event_id, e);
+                    clone.ranges = None;
+                    clone.body.clear();
+                    clone.status_code = Some(HttpStatus::InternalServerError.code());
+                }
+            }
         }
+        clone
+    }
+    /// Parks the request instead of answering it from the handler.
+    ///
+    /// The returned handle can be completed later, from any thread. If it is
+    /// not completed within `timeout` the client gets a `503`. Headers set
+    /// before are kept, a body written before is dropped.
+    pub fn defer(&mut self, timeout: Duration) -> DeferredResponse {
+        self.deferred = true;
+        WRITE_CTX.lock().unwrap().remove(&self.event_id);
+        self.body.clear();
+        DeferredResponse::new(self.clone(), timeout)
+    }
+
+    pub fn is_deferred(&self) -> bool {
+        self.deferred
+    }
+
+    /// Switches the connection to `protocol` with a `101 Switching Protocols`
+    /// response. Once it is sent, `on_upgrade` is called on the loop thread
+    /// with the socket, which the server no longer reads or closes.
+    /// Not available on TLS connections, which are answered with `501 Not
+    /// Implemented` instead.
+    pub fn upgrade<F: FnOnce(Upgraded) + Send + 'static>(&mut self, protocol: &str, on_upgrade: F) {
+        if Transport::new(self.event_id, &self.stream).is_secure() {
+            self.write_status(HttpStatus::NotImplemented);
+            return;
+        }
+        self.headers
+            .insert("Upgrade".to_string(), vec![protocol.to_string()]);
+        self.headers
+            .insert("Connection".to_string(), vec!["Upgrade".to_string()]);
+        self.set_status(HttpStatus::SwitchingProtocols.code());
+        self.keep_open();
+        self.write_string("");
+        upgrade::register(self.event_id, Box::new(on_upgrade));
+    }
+
+    /// Answers with a `text/event-stream` that stays open after the handler
+    /// returns. Events are pushed through the returned handle, a comment is
+    /// sent after 15 seconds without events to keep the connection alive.
+    pub fn event_stream(&mut self) -> EventStream {
+        self.headers.insert(
+            "Content-Type".to_string(),
+            vec!["text/event-stream".to_string()],
+        );
+        self.headers
+            .insert("Cache-Control".to_string(), vec!["no-cache".to_string()]);
+        self.set_status(HttpStatus::Ok.code());
+        self.keep_open();
+        self.write_string("");
+        sse::open(self.event_id)
     }
     pub fn write_string(&mut self, str: &str) {
         self.body.extend_from_slice(str.as_bytes());
@@ -64,34 +280,231 @@ impl ResponseWriter {
         let v = self.clone();
         WRITE_CTX.lock().unwrap().insert(self.event_id, v);
     }
-    pub fn excute(&mut self, og_raw_fd: i32) {
-        let mut headers_str = String::new();
+    /// Writes as much of the response as the socket accepts. Returns `true`
+    /// once everything is sent and the connection is closed, `false` when it
+    /// must be called again after the socket becomes writable.
+    pub fn excute(&mut self, og_raw_fd: i32) -> io::Result<bool> {
+        if self.outgoing.is_none() {
+            self.outgoing = Some(self.serialize());
+        }
+        let mut outgoing = self.outgoing.take().unwrap_or_default();
+        let res = self.send(&mut outgoing);
+        let done = outgoing.segments.is_empty();
+        self.outgoing = Some(outgoing);
+        if !res? {
+            return Ok(false);
+        }
+        debug_assert!(done);
+        if self.upgraded {
+            return Ok(true);
+        }
+
+        Transport::new(self.event_id, &self.stream).close();
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+
+        poller().remove(og_raw_fd)?;
+        Ok(true)
+    }
+
+    fn serialize(&self) -> Outgoing {
+        let status = HttpStatus::from_code(self.status_code.unwrap_or(200));
+        let mut response = PooledBuf::take();
+        response.reserve(256 + self.body.len());
+        let _ = write!(
+            response,
+            "HTTP/1.1 {} {}\r\n",
+            status.code(),
+            status.reason_phrase()
+        );
         for (k, v) in &self.headers {
             for header_value in v {
-                headers_str.push_str(&format!("{}: {}\r\n", k, header_value));
+                response.extend_from_slice(k.as_bytes());
+                response.extend_from_slice(b": ");
+                response.extend_from_slice(header_value.as_bytes());
+                response.extend_from_slice(b"\r\n");
             }
         }
-        let status = HttpStatus::from_code(self.status_code.or(Some(200)).unwrap());
-        let response = format!(
-            "HTTP/1.1 {} {}\r\n{}\r\n",
-            status.code(),
-            status.reason_phrase(),
-            headers_str,
-        );
+        if let Some(len) = self.content_length() {
+            let _ = write!(response, "Content-Length: {}\r\n", len);
+        }
+        // connections are not kept alive
+        if !self.upgraded && !self.has_header("Connection") {
+            response.extend_from_slice(b"Connection: close\r\n");
+        }
+        response.extend_from_slice(b"\r\n");
 
-        let mut response_extended = response.as_bytes().to_vec();
-        response_extended.extend_from_slice(&self.body);
+        let mut segments = self.body_segments();
+        // a body built in memory goes out with the head
+        if let Some(Segment::Body) = segments.front() {
+            segments.pop_front();
+            response.extend_from_slice(&self.body);
+        }
+        segments.push_front(Segment::Bytes(response));
+        Outgoing {
+            segments,
+            written: 0,
+        }
+    }
 
-        self.stream.write_all(response_extended.as_slice()).unwrap();
+    // 1xx, 204 and 304 responses never have a body
+    fn is_bodyless(&self) -> bool {
+        matches!(self.status_code.unwrap_or(200), 100..=199 | 204 | 304)
+    }
 
-        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    /// Whether the handler set `name`, regardless of its case.
+    fn has_header(&self, name: &str) -> bool {
+        self.headers.keys().any(|key| key.eq_ignore_ascii_case(name))
+    }
 
-        remove_interest(*EPOLL_FD, og_raw_fd).unwrap();
+    /// Removes `name` in whatever case the handler set it.
+    pub(crate) fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|key, _| !key.eq_ignore_ascii_case(name));
     }
+
+    /// The `Content-Length` to add to the head, `None` when the handler set
+    /// one or there is no body to announce.
+    fn content_length(&self) -> Option<u64> {
+        // a streamed body ends with the connection
+        if self.is_bodyless() || self.upgraded || self.has_header("Content-Length") {
+            return None;
+        }
+        Some(match (&self.file, &self.ranges) {
+            (Some(_), Some(ranges)) => ranges.content_length(),
+            (Some(file), None) => file.len,
+            (None, _) => self.body.len() as u64,
+        })
+    }
+
+    fn body_segments(&self) -> VecDeque<Segment> {
+        let mut segments = VecDeque::new();
+        match (&self.file, &self.ranges) {
+            _ if self.is_bodyless() || self.head_only => {}
+            (Some(_), Some(ranges)) => {
+                for part in &ranges.parts {
+                    if !part.header.is_empty() {
+                        let mut header = PooledBuf::take();
+                        header.extend_from_slice(part.header.as_bytes());
+                        segments.push_back(Segment::Bytes(header));
+                    }
+                    segments.push_back(Segment::File {
+                        offset: part.offset,
+                        len: part.len,
+                    });
+                }
+                if !ranges.trailer.is_empty() {
+                    let mut trailer = PooledBuf::take();
+                    trailer.extend_from_slice(ranges.trailer.as_bytes());
+                    segments.push_back(Segment::Bytes(trailer));
+                }
+            }
+            (Some(file), None) => segments.push_back(Segment::File {
+                offset: 0,
+                len: file.len,
+            }),
+            (None, _) => segments.push_back(Segment::Body),
+        }
+        segments
+    }
+
+    /// Splits the response into the status, the header fields with lowercase
+    /// names and a reader for the body, for HTTP/2 streams. Headers that only
+    /// make sense on an HTTP/1 connection are left out.
+    pub(crate) fn http2_parts(&mut self) -> (u16, Vec<(String, String)>, BodyReader) {
+        let mut headers = Vec::with_capacity(self.headers.len() + 1);
+        for (name, values) in &self.headers {
+            let name = name.to_ascii_lowercase();
+            if matches!(
+                name.as_str(),
+                "connection" | "keep-alive" | "proxy-connection" | "transfer-encoding" | "upgrade"
+            ) {
+                continue;
+            }
+            for value in values {
+                headers.push((name.clone(), value.clone()));
+            }
+        }
+        if let Some(len) = self.content_length() {
+            headers.push(("content-length".to_string(), len.to_string()));
+        }
+        let reader = BodyReader {
+            segments: self.body_segments(),
+            body: std::mem::take(&mut self.body),
+            file: self.file.take().map(|file| file.file),
+            written: 0,
+        };
+        (self.status_code.unwrap_or(200), headers, reader)
+    }
+
+    /// Returns `false` when the socket buffer is full or the sendfile budget
+    /// for this round is spent.
+    fn send(&mut self, outgoing: &mut Outgoing) -> io::Result<bool> {
+        let mut transport = Transport::new(self.event_id, &self.stream);
+        let mut budget = SENDFILE_BUDGET;
+        while let Some(segment) = outgoing.segments.front_mut() {
+            match segment {
+                Segment::Bytes(_) | Segment::Body => {
+                    let bytes = match segment {
+                        Segment::Bytes(bytes) => &bytes[..],
+                        _ => &self.body[..],
+                    };
+                    while outgoing.written < bytes.len() {
+                        match transport.write(&bytes[outgoing.written..]) {
+                            Ok(n) => outgoing.written += n,
+                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    outgoing.written = 0;
+                }
+                Segment::File { offset, len } => {
+                    let file = match &self.file {
+                        Some(file) => file,
+                        None => return Err(io::Error::new(ErrorKind::NotFound, "file body is gone")),
+                    };
+                    while *len > 0 {
+                        if budget == 0 {
+                            return Ok(false);
+                        }
+                        let count = (*len).min(budget as u64) as usize;
+                        let before = *offset;
+                        let res = if transport.is_secure() {
+                            send_encrypted(&mut transport, &file.file, offset, count)
+                        } else {
+                            sendfile(self.stream.as_raw_fd(), file.file.as_raw_fd(), offset, count)
+                        };
+                        match res {
+                            Ok(0) => {
+                                return Err(io::Error::new(
+                                    ErrorKind::UnexpectedEof,
+                                    "file shrank while being sent",
+                                ))
+                            }
+                            Ok(_) => {
+                                let sent = *offset - before;
+                                *len -= sent;
+                                budget -= sent as usize;
+                            }
+                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+            }
+            outgoing.segments.pop_front();
+        }
+        match transport.flush() {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
     pub fn set_cookie(&mut self, name: &str, value: &str) {
         self.headers
             .entry("Set-Cookie".to_string())
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(format!("{}={}", name, value));
     }
 
@@ -110,7 +523,7 @@ impl ResponseWriter {
         }
         self.headers
             .entry("Set-Cookie".to_string())
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(cookie);
     }
     pub fn write_json(&mut self, body: JsonValue) {
@@ -122,6 +535,20 @@ impl ResponseWriter {
         self.write()
     }
 
+    /// Sets a strong entity tag, `tag` is quoted if it is not already.
+    ///
+    /// File bodies get one derived from their size and modification time
+    /// unless it is set explicitly.
+    pub fn set_etag(&mut self, tag: &str) {
+        self.headers
+            .insert("ETag".to_string(), vec![quote_etag(tag)]);
+    }
+
+    pub fn set_weak_etag(&mut self, tag: &str) {
+        self.headers
+            .insert("ETag".to_string(), vec![format!("W/{}", quote_etag(tag))]);
+    }
+
     pub fn set_status(&mut self, status_code: u16) {
         self.status_code = Some(status_code);
     }
@@ -132,3 +559,97 @@ impl ResponseWriter {
         self.write();
     }
 }
+
+fn quote_etag(tag: &str) -> String {
+    if tag.len() >= 2 && tag.starts_with('"') && tag.ends_with('"') {
+        tag.to_string()
+    } else {
+        format!("\"{}\"", tag)
+    }
+}
+
+/// Reads the body of a response in chunks, see `ResponseWriter::http2_parts`.
+#[derive(Debug)]
+pub(crate) struct BodyReader {
+    segments: VecDeque<Segment>,
+    body: Vec<u8>,
+    file: Option<File>,
+    // into the front segment
+    written: usize,
+}
+
+impl BodyReader {
+    /// Appends up to `max` bytes of the body to `out`, returns how many.
+    pub(crate) fn read(&mut self, out: &mut Vec<u8>, max: usize) -> io::Result<usize> {
+        let mut total = 0;
+        while total < max {
+            let segment = match self.segments.front_mut() {
+                Some(segment) => segment,
+                None => break,
+            };
+            match segment {
+                Segment::Bytes(_) | Segment::Body => {
+                    let bytes = match segment {
+                        Segment::Bytes(bytes) => &bytes[..],
+                        _ => &self.body[..],
+                    };
+                    let n = (bytes.len() - self.written).min(max - total);
+                    out.extend_from_slice(&bytes[self.written..self.written + n]);
+                    self.written += n;
+                    total += n;
+                    if self.written < bytes.len() {
+                        continue;
+                    }
+                    self.written = 0;
+                }
+                Segment::File { offset, len } => {
+                    let file = match &self.file {
+                        Some(file) => file,
+                        None => return Err(io::Error::new(ErrorKind::NotFound, "file body is gone")),
+                    };
+                    let count = (*len).min((max - total) as u64) as usize;
+                    let start = out.len();
+                    out.resize(start + count, 0);
+                    let n = match file.read_at(&mut out[start..], *offset) {
+                        Ok(n) => n,
+                        Err(e) => {
+                            out.truncate(start);
+                            return Err(e);
+                        }
+                    };
+                    out.truncate(start + n);
+                    if n == 0 && count > 0 {
+                        return Err(io::Error::new(
+                            ErrorKind::UnexpectedEof,
+                            "file shrank while being sent",
+                        ));
+                    }
+                    *offset += n as u64;
+                    *len -= n as u64;
+                    total += n;
+                    if *len > 0 {
+                        continue;
+                    }
+                }
+            }
+            self.segments.pop_front();
+        }
+        Ok(total)
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.segments.is_empty()
+    }
+}
+
+impl Drop for BodyReader {
+    fn drop(&mut self) {
+        buffer_pool::recycle(std::mem::take(&mut self.body));
+    }
+}
+
+impl Drop for ResponseWriter {
+    fn drop(&mut self) {
+        buffer_pool::recycle(std::mem::take(&mut self.body));
+    }
+}
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::poller::{poller, Event};
+use crate::transport::Transport;
+use crate::{hub, timer, wake_loop, EventId, Interest, Request, TimerId};
+
+// comments sent on idle streams so proxies don't time them out
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// A message of an event stream, see `EventStream::send`.
+#[derive(Debug, Clone, Default)]
+pub struct ServerEvent {
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+    data: String,
+}
+
+impl ServerEvent {
+    /// An event of the default `message` type. Line breaks in `data` are
+    /// sent as several `data:` lines and restored by the client.
+    pub fn new(data: &str) -> Self {
+        ServerEvent {
+            data: data.to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn event(mut self, event: &str) -> Self {
+        self.event = Some(single_line(event));
+        self
+    }
+
+    /// The id the client sends back in `Last-Event-ID` when it reconnects.
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(single_line(id).replace('\0', ""));
+        self
+    }
+
+    /// How long the client waits before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        if let Some(event) = &self.event {
+            let _ = writeln!(out, "event: {}", event);
+        }
+        if let Some(id) = &self.id {
+            let _ = writeln!(out, "id: {}", id);
+        }
+        if let Some(retry) = self.retry {
+            let _ = writeln!(out, "retry: {}", retry.as_millis());
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            let _ = writeln!(out, "data: {}", line);
+        }
+        out.push(b'\n');
+    }
+}
+
+/// Handle to a `text/event-stream` response, returned by
+/// `ResponseWriter::event_stream`. It is `Copy` and `Send`, events can be
+/// pushed from any handler, timer or thread until the client goes away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EventStream {
+    id: EventId,
+}
+
+struct Stream {
+    // set once the response head is sent and the connection handed over
+    socket: Option<TcpStream>,
+    pending: Vec<u8>,
+    written: usize,
+    // closed once `pending` is flushed
+    closing: bool,
+    keep_alive: Option<TimerId>,
+    // when something was last pushed, keep-alive comments wait for silence
+    last_push: Instant,
+}
+
+lazy_static! {
+    static ref STREAMS: Mutex<HashMap<EventId, Stream>> = Mutex::new(HashMap::new());
+    static ref DIRTY: Mutex<Vec<EventId>> = Mutex::new(Vec::new());
+}
+
+impl EventStream {
+    pub(crate) fn id(&self) -> EventId {
+        self.id
+    }
+
+    pub fn send(&self, event: &ServerEvent) -> bool {
+        self.push(|out| event.encode(out))
+    }
+
+    /// Sends `data` as an event of the default `message` type.
+    pub fn send_data(&self, data: &str) -> bool {
+        self.send(&ServerEvent::new(data))
+    }
+
+    /// Sends a comment line, ignored by clients.
+    pub fn comment(&self, text: &str) -> bool {
+        self.push(|out| {
+            for line in text.replace("\r\n", "\n").split(['\r', '\n']) {
+                let _ = writeln!(out, ": {}", line);
+            }
+            out.push(b'\n');
+        })
+    }
+
+    /// Changes how long the stream may stay silent before a comment is sent
+    /// to keep the connection alive, 15 seconds by default. `None` disables
+    /// it.
+    pub fn set_keep_alive(&self, interval: Option<Duration>) {
+        let mut streams = STREAMS.lock().unwrap();
+        if let Some(stream) = streams.get_mut(&self.id) {
+            if let Some(timer_id) = stream.keep_alive.take() {
+                timer::cancel(timer_id);
+            }
+            stream.keep_alive =
+                interval.map(|interval| schedule_keep_alive(self.id, interval, interval));
+        }
+    }
+
+    /// Ends the response once the events already sent are flushed.
+    pub fn close(&self) {
+        if let Some(stream) = STREAMS.lock().unwrap().get_mut(&self.id) {
+            stream.closing = true;
+            if let Some(timer_id) = stream.keep_alive.take() {
+                timer::cancel(timer_id);
+            }
+        }
+        mark_dirty(self.id);
+    }
+
+    pub fn is_open(&self) -> bool {
+        STREAMS
+            .lock()
+            .unwrap()
+            .get(&self.id)
+            .is_some_and(|stream| !stream.closing)
+    }
+
+    fn push<F: FnOnce(&mut Vec<u8>)>(&self, encode: F) -> bool {
+        match STREAMS.lock().unwrap().get_mut(&self.id) {
+            Some(stream) if !stream.closing => {
+                encode(&mut stream.pending);
+                stream.last_push = Instant::now();
+            }
+            _ => return false,
+        }
+        mark_dirty(self.id);
+        true
+    }
+}
+
+/// Starts an event stream for the response `id`, its head is written by
+/// the caller.
+pub(crate) fn open(id: EventId) -> EventStream {
+    let keep_alive = schedule_keep_alive(id, DEFAULT_KEEP_ALIVE, DEFAULT_KEEP_ALIVE);
+    STREAMS.lock().unwrap().insert(
+        id,
+        Stream {
+            socket: None,
+            pending: Vec::new(),
+            written: 0,
+            closing: false,
+            keep_alive: Some(keep_alive),
+            last_push: Instant::now(),
+        },
+    );
+    EventStream { id }
+}
+
+pub(crate) fn is_pending(id: EventId) -> bool {
+    STREAMS
+        .lock()
+        .unwrap()
+        .get(&id)
+        .is_some_and(|stream| stream.socket.is_none())
+}
+
+/// Takes over the socket once the response head is sent, see `is_pending`.
+pub(crate) fn adopt(id: EventId, request: Request) {
+    let head_only = request.method == "HEAD";
+    let socket = request.detach().map(|(socket, _)| socket);
+
+    let mut streams = STREAMS.lock().unwrap();
+    let mut stream = match streams.remove(&id) {
+        Some(stream) => stream,
+        None => return,
+    };
+    let socket = match socket.and_then(|socket| {
+        poller().add(socket.as_raw_fd(), id as u64, Interest::Read)?;
+        Ok(socket)
+    }) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("couldn't keep event stream {} open: {}", id, e);
+            return teardown(id, stream);
+        }
+    };
+    stream.socket = Some(socket);
+    if head_only {
+        return teardown(id, stream);
+    }
+    service(id, stream, &mut streams);
+}
+
+/// Bytes pushed to `id` and not written yet, `None` once it is closed or
+/// closing.
+pub(crate) fn backlog(id: EventId) -> Option<usize> {
+    match STREAMS.lock().unwrap().get(&id) {
+        Some(stream) if !stream.closing => Some(stream.pending.len() - stream.written),
+        _ => None,
+    }
+}
+
+/// Drops the connection right away, without flushing what is pending.
+pub(crate) fn abort(id: EventId) {
+    abandon(id)
+}
+
+/// Drops a stream whose response head could not be sent.
+pub(crate) fn abandon(id: EventId) {
+    if let Some(stream) = STREAMS.lock().unwrap().remove(&id) {
+        teardown(id, stream);
+    }
+}
+
+/// Handles readiness of an event stream, returns `false` if the key is not
+/// one.
+pub(crate) fn dispatch(ev: &Event) -> bool {
+    let id = ev.key as EventId;
+    let mut streams = STREAMS.lock().unwrap();
+    if streams.get(&id).is_none_or(|stream| stream.socket.is_none()) {
+        return false;
+    }
+    let stream = streams.remove(&id).unwrap();
+    if ev.readable || ev.hangup || ev.error {
+        // clients never send anything, reading only tells when they leave
+        if let Err(e) = drain(&mut Transport::new(id, stream.socket.as_ref().unwrap())) {
+            if e.kind() != ErrorKind::UnexpectedEof {
+                eprintln!("error reading event stream {}: {}", id, e);
+            }
+            teardown(id, stream);
+            return true;
+        }
+    }
+    service(id, stream, &mut streams);
+    true
+}
+
+/// Writes the events pushed since the last iteration.
+pub(crate) fn flush_pending() {
+    let mut dirty = std::mem::take(&mut *DIRTY.lock().unwrap());
+    dirty.sort_unstable();
+    dirty.dedup();
+    let mut streams = STREAMS.lock().unwrap();
+    for id in dirty {
+        match streams.remove(&id) {
+            Some(stream) if stream.socket.is_some() => service(id, stream, &mut streams),
+            Some(stream) => {
+                streams.insert(id, stream);
+            }
+            None => {}
+        }
+    }
+}
+
+/// Flushes what it can and re-arms the socket, or closes it.
+fn service(id: EventId, mut stream: Stream, streams: &mut HashMap<EventId, Stream>) {
+    let socket = stream.socket.as_ref().unwrap();
+    let mut transport = Transport::new(id, socket);
+    if let Err(e) = transport.write_buffered(&mut stream.pending, &mut stream.written) {
+        eprintln!("error writing event stream {}: {}", id, e);
+        return teardown(id, stream);
+    }
+    let flushed = transport.is_flushed(&stream.pending, stream.written);
+    if flushed && stream.closing {
+        return teardown(id, stream);
+    }
+    let interest = if flushed {
+        Interest::Read
+    } else {
+        Interest::ReadWrite
+    };
+    if let Err(e) = poller().modify(socket.as_raw_fd(), id as u64, interest) {
+        eprintln!("couldn't re-arm event stream {}: {}", id, e);
+        return teardown(id, stream);
+    }
+    streams.insert(id, stream);
+}
+
+fn teardown(id: EventId, stream: Stream) {
+    hub::connection_closed(id);
+    if let Some(timer_id) = stream.keep_alive {
+        timer::cancel(timer_id);
+    }
+    if let Some(socket) = stream.socket {
+        let _ = poller().remove(socket.as_raw_fd());
+        Transport::new(id, &socket).close();
+        let _ = socket.shutdown(Shutdown::Both);
+    }
+}
+
+fn drain(socket: &mut Transport) -> io::Result<()> {
+    let mut buf = [0; 512];
+    loop {
+        match socket.read(&mut buf) {
+            Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "client left")),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Sends a comment after `delay` if nothing was pushed for `interval`, and
+/// schedules the next check as long as the stream is open, so no timer
+/// outlives it.
+fn schedule_keep_alive(id: EventId, delay: Duration, interval: Duration) -> TimerId {
+    timer::schedule(
+        delay,
+        None,
+        Box::new(move || {
+            let mut streams = STREAMS.lock().unwrap();
+            match streams.get_mut(&id) {
+                Some(stream) if !stream.closing => {
+                    let idle = stream.last_push.elapsed();
+                    if idle < interval {
+                        // events went out meanwhile, wait for the rest of the interval
+                        let keep_alive = schedule_keep_alive(id, interval - idle, interval);
+                        stream.keep_alive = Some(keep_alive);
+                        return;
+                    }
+                    stream.pending.extend_from_slice(b":\n\n");
+                    stream.last_push = Instant::now();
+                    let keep_alive = schedule_keep_alive(id, interval, interval);
+                    stream.keep_alive = Some(keep_alive);
+                }
+                _ => return,
+            }
+            drop(streams);
+            mark_dirty(id);
+        }),
+    )
+}
+
+fn mark_dirty(id: EventId) {
+    DIRTY.lock().unwrap().push(id);
+    wake_loop();
+}
+
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
@@ -0,0 +1,315 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::utils::{encoding_qvalue, fmt_http_date};
+use crate::{mime, HttpStatus, Request, ResponseWriter, ServerId};
+
+/// How a directory registered with `HttpServer::serve_dir_with_options` is served.
+#[derive(Debug, Clone)]
+pub struct StaticOptions {
+    /// File served for a directory URL, `index.html` by default.
+    pub index_file: Option<String>,
+    /// Renders a listing of directories without an index file instead of
+    /// answering `403 Forbidden`.
+    pub autoindex: bool,
+    /// Serves `file.br` or `file.gz` in place of `file` when they exist and
+    /// the client accepts that encoding.
+    pub precompressed: bool,
+}
+
+impl Default for StaticOptions {
+    fn default() -> Self {
+        StaticOptions {
+            index_file: Some("index.html".to_string()),
+            autoindex: false,
+            precompressed: false,
+        }
+    }
+}
+
+struct StaticDir {
+    server_id: ServerId,
+    prefix: String,
+    root: PathBuf,
+    options: StaticOptions,
+}
+
+lazy_static! {
+    static ref STATIC_DIRS: Mutex<Vec<StaticDir>> = Mutex::new(Vec::new());
+}
+
+pub(crate) fn register(
+    server_id: ServerId,
+    prefix: &str,
+    root: &Path,
+    options: StaticOptions,
+) -> io::Result<()> {
+    // canonical so that resolved paths can be checked against it
+    let root = fs::canonicalize(root)?;
+    if !root.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "not a directory"));
+    }
+    let prefix = format!("/{}", prefix.trim_matches('/'));
+    let mut dirs = STATIC_DIRS.lock().unwrap();
+    dirs.push(StaticDir {
+        server_id,
+        prefix,
+        root,
+        options,
+    });
+    // longest prefix first
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.prefix.len()));
+    Ok(())
+}
+
+/// Answers `req` from a registered directory, returns `false` if no
+/// directory of this server matches the path.
+pub(crate) fn serve(server_id: ServerId, req: &Request, w: &mut ResponseWriter) -> bool {
+    let (path, query) = match req.path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (req.path.as_str(), None),
+    };
+    let path = match percent_decode(path) {
+        Some(path) => path,
+        None => return false,
+    };
+
+    let dirs = STATIC_DIRS.lock().unwrap();
+    let dir = match find(&dirs, server_id, &path) {
+        Some(dir) => dir,
+        None => return false,
+    };
+
+    if req.method == "OPTIONS" {
+        w.write_allow("GET, HEAD, OPTIONS".to_string());
+        return true;
+    }
+    if req.method != "GET" && req.method != "HEAD" {
+        w.headers
+            .insert("Allow".to_string(), vec!["GET, HEAD, OPTIONS".to_string()]);
+        w.write_status(HttpStatus::MethodNotAllowed);
+        return true;
+    }
+
+    let relative = &path[dir.prefix.len()..];
+    let file_path = match resolve(&dir.root, relative) {
+        Some(file_path) => file_path,
+        None => {
+            w.write_status(HttpStatus::NotFound);
+            return true;
+        }
+    };
+
+    if file_path.is_dir() {
+        if !path.ends_with('/') {
+            let mut location = req.path.split('?').next().unwrap_or("/").to_string() + "/";
+            if let Some(query) = query {
+                location = location + "?" + query;
+            }
+            w.headers.insert("Location".to_string(), vec![location]);
+            w.write_status(HttpStatus::MovedPermanently);
+            return true;
+        }
+        let index = dir
+            .options
+            .index_file
+            .as_ref()
+            .and_then(|index| resolve(&dir.root, &format!("{}/{}", relative, index)))
+            .filter(|index| index.is_file());
+        match index {
+            Some(index) => send_file(dir, req, &index, w),
+            None if dir.options.autoindex => send_listing(&dir.root, &file_path, &path, w),
+            None => w.write_status(HttpStatus::Forbidden),
+        }
+        return true;
+    }
+
+    send_file(dir, req, &file_path, w);
+    true
+}
+
+// in order of preference when the client weighs them equally
+const PRECOMPRESSED: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// Picks the best precompressed sibling of `path` accepted by the client.
+fn precompressed_variant(root: &Path, req: &Request, path: &Path) -> Option<(&'static str, PathBuf)> {
+    let accept_encoding = req.header("Accept-Encoding")?;
+    let mut best: Option<(f32, &'static str, PathBuf)> = None;
+    for (coding, ext) in PRECOMPRESSED {
+        let q = encoding_qvalue(accept_encoding, coding);
+        if q <= 0.0 || best.as_ref().is_some_and(|(best_q, _, _)| *best_q >= q) {
+            continue;
+        }
+        let mut variant = path.as_os_str().to_owned();
+        variant.push(".");
+        variant.push(ext);
+        let variant = match fs::canonicalize(variant) {
+            Ok(variant) if variant.starts_with(root) && variant.is_file() => variant,
+            _ => continue,
+        };
+        best = Some((q, coding, variant));
+    }
+    best.map(|(_, coding, variant)| (coding, variant))
+}
+
+/// Whether a `method` request for `path` would be served from a directory,
+/// without touching the file system.
+pub(crate) fn accepts(server_id: ServerId, method: &str, path: &str) -> bool {
+    let path = path.split('?').next().unwrap_or("");
+    matches!(method, "GET" | "HEAD" | "OPTIONS")
+        && percent_decode(path)
+            .is_some_and(|path| find(&STATIC_DIRS.lock().unwrap(), server_id, &path).is_some())
+}
+
+fn find<'a>(dirs: &'a [StaticDir], server_id: ServerId, path: &str) -> Option<&'a StaticDir> {
+    dirs.iter().find(|dir| {
+        dir.server_id == server_id
+            && (dir.prefix == "/"
+                || path == dir.prefix
+                || path.starts_with(&dir.prefix) && path[dir.prefix.len()..].starts_with('/'))
+    })
+}
+
+/// Joins `relative` to `root`, refusing `..` components and anything that
+/// resolves outside of `root` through symlinks.
+fn resolve(root: &Path, relative: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for component in relative.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => return None,
+            c if c.contains('\0') || c.contains('\\') => return None,
+            c => path.push(c),
+        }
+    }
+    let resolved = fs::canonicalize(&path).ok()?;
+    if resolved.starts_with(root) {
+        Some(resolved)
+    } else {
+        None
+    }
+}
+
+fn send_file(dir: &StaticDir, req: &Request, file_path: &Path, w: &mut ResponseWriter) {
+    let mut path = file_path.to_path_buf();
+    if dir.options.precompressed {
+        w.headers
+            .insert("Vary".to_string(), vec!["Accept-Encoding".to_string()]);
+        if let Some((coding, variant)) = precompressed_variant(&dir.root, req, file_path) {
+            w.headers
+                .insert("Content-Encoding".to_string(), vec![coding.to_string()]);
+            path = variant;
+        }
+    }
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return w.write_status(HttpStatus::NotFound),
+    };
+    // the type of the original file, not of its compressed variant
+    w.headers.insert(
+        "Content-Type".to_string(),
+        vec![mime::from_path(file_path).to_string()],
+    );
+    if let Ok(modified) = metadata.modified() {
+        w.headers
+            .insert("Last-Modified".to_string(), vec![fmt_http_date(modified)]);
+    }
+    if let Err(e) = w.write_file(&path) {
+        eprintln!("couldn't serve {}: {}", path.display(), e);
+        w.headers.clear();
+        w.write_status(HttpStatus::Forbidden);
+    }
+}
+
+fn send_listing(root: &Path, dir: &Path, url_path: &str, w: &mut ResponseWriter) {
+    let mut entries: Vec<(String, bool, u64, String)> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                // symlinks pointing outside of the root are not listed
+                let target = fs::canonicalize(entry.path()).ok()?;
+                if !target.starts_with(root) {
+                    return None;
+                }
+                let metadata = fs::metadata(&target).ok()?;
+                let modified = metadata.modified().map(fmt_http_date).unwrap_or_default();
+                Some((
+                    entry.file_name().to_string_lossy().into_owned(),
+                    metadata.is_dir(),
+                    metadata.len(),
+                    modified,
+                ))
+            })
+            .collect(),
+        Err(_) => return w.write_status(HttpStatus::Forbidden),
+    };
+    entries.sort();
+
+    let title = html_escape(url_path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Index of {0}</title></head>\n<body><h1>Index of {0}</h1><hr><pre>\n<a href=\"../\">../</a>\n",
+        title
+    );
+    for (name, is_dir, size, modified) in entries {
+        let suffix = if is_dir { "/" } else { "" };
+        let size = if is_dir { "-".to_string() } else { size.to_string() };
+        html.push_str(&format!(
+            "<a href=\"{}{}\">{}{}</a>  {}  {}\n",
+            percent_encode(&name),
+            suffix,
+            html_escape(&name),
+            suffix,
+            modified,
+            size
+        ));
+    }
+    html.push_str("</pre><hr></body></html>\n");
+
+    w.headers.insert(
+        "Content-Type".to_string(),
+        vec!["text/html; charset=utf-8".to_string()],
+    );
+    w.write_string(&html);
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn percent_encode(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for b in input.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+fn html_escape(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::poller::poller;
+use crate::{utils::*, Interest};
+
+pub type TimerId = usize;
+
+pub(crate) const TIMER_KEY: u64 = 2;
+
+type TimerCallback = Box<dyn FnMut() + Send>;
+
+struct Timer {
+    deadline: Instant,
+    period: Option<Duration>,
+    // taken out while the callback runs so the lock is not held
+    callback: Option<TimerCallback>,
+}
+
+lazy_static! {
+    static ref TIMERS: Mutex<HashMap<TimerId, Timer>> = Mutex::new(HashMap::new());
+    static ref NEXT_TIMER_ID: Mutex<TimerId> = Mutex::new(1);
+    pub(crate) static ref TIMER_FD: i32 = {
+        let fd = timerfd_create().expect("can create timerfd");
+        poller().add(fd, TIMER_KEY, Interest::Read).expect("can register timerfd");
+        fd
+    };
+}
+
+pub(crate) fn schedule(delay: Duration, period: Option<Duration>, callback: TimerCallback) -> TimerId {
+    let timer_id = {
+        let mut next_id = NEXT_TIMER_ID.lock().unwrap();
+        let timer_id = *next_id;
+        *next_id += 1;
+        timer_id
+    };
+    let mut timers = TIMERS.lock().unwrap();
+    timers.insert(
+        timer_id,
+        Timer {
+            deadline: Instant::now() + delay,
+            period,
+            callback: Some(callback),
+        },
+    );
+    rearm(&timers);
+    timer_id
+}
+
+pub(crate) fn cancel(timer_id: TimerId) -> bool {
+    let mut timers = TIMERS.lock().unwrap();
+    let removed = timers.remove(&timer_id).is_some();
+    if removed {
+        rearm(&timers);
+    }
+    removed
+}
+
+/// Runs every due callback on the loop thread, then re-arms the timerfd for
+/// the next deadline.
+pub(crate) fn run_expired() {
+    let _ = drain_counter_fd(*TIMER_FD);
+
+    let now = Instant::now();
+    let due: Vec<(TimerId, TimerCallback)> = TIMERS
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .filter(|(_, timer)| timer.deadline <= now)
+        .filter_map(|(timer_id, timer)| Some((*timer_id, timer.callback.take()?)))
+        .collect();
+
+    for (timer_id, mut callback) in due {
+        callback();
+
+        let mut timers = TIMERS.lock().unwrap();
+        let reschedule = match timers.get_mut(&timer_id) {
+            Some(timer) => match timer.period {
+                Some(period) => {
+                    // skip missed ticks instead of firing them in a burst
+                    timer.deadline = (timer.deadline + period).max(Instant::now());
+                    timer.callback = Some(callback);
+                    true
+                }
+                None => false,
+            },
+            // cancelled from inside its own callback
+            None => true,
+        };
+        if !reschedule {
+            timers.remove(&timer_id);
+        }
+    }
+
+    rearm(&TIMERS.lock().unwrap());
+    poller().modify(*TIMER_FD, TIMER_KEY, Interest::Read).unwrap();
+}
+
+fn rearm(timers: &HashMap<TimerId, Timer>) {
+    let next = timers
+        .values()
+        .filter(|timer| timer.callback.is_some())
+        .map(|timer| timer.deadline)
+        .min()
+        .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    if let Err(e) = timerfd_arm(*TIMER_FD, next) {
+        eprintln!("couldn't arm timerfd: {}", e);
+    }
+}
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+
+use crate::utils::eventfd_signal;
+use crate::x509::ClientCertificate;
+use crate::{EventId, ServerId};
+
+/// The TLS state of a connection accepted on a `listen_tls` listener.
+struct Session {
+    conn: ServerConnection,
+    // decrypted bytes not read yet, the poller can't see them
+    buffered: usize,
+    // parsed on first use
+    client_certificate: Option<Arc<ClientCertificate>>,
+}
+
+/// A certificate chain and its key, with the files they were read from.
+#[derive(Debug)]
+struct Certificate {
+    // `None` for the default certificate
+    server_name: Option<String>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    key: Arc<CertifiedKey>,
+}
+
+/// The certificates of a server, picked by the name clients send through
+/// SNI. Handshakes look them up as they start, so reloading them leaves
+/// established connections alone.
+#[derive(Debug, Default)]
+struct Certificates {
+    certificates: Mutex<Vec<Certificate>>,
+}
+
+lazy_static! {
+    static ref SESSIONS: Mutex<HashMap<EventId, Session>> = Mutex::new(HashMap::new());
+    static ref CERTIFICATES: Mutex<HashMap<ServerId, Arc<Certificates>>> =
+        Mutex::new(HashMap::new());
+    static ref CLIENT_VERIFIERS: Mutex<HashMap<ServerId, Arc<dyn ClientCertVerifier>>> =
+        Mutex::new(HashMap::new());
+    static ref PROVIDER: Arc<CryptoProvider> = Arc::new(rustls::crypto::ring::default_provider());
+}
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+static RELOAD_WAKER: AtomicI32 = AtomicI32::new(-1);
+
+fn certificates(server_id: ServerId) -> Arc<Certificates> {
+    CERTIFICATES
+        .lock()
+        .unwrap()
+        .entry(server_id)
+        .or_default()
+        .clone()
+}
+
+/// Builds the listener configuration of a server, its certificates are
+/// added with `add_certificate`.
+pub(crate) fn server_config(
+    server_id: ServerId,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> io::Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder_with_provider(PROVIDER.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?;
+    let builder = match CLIENT_VERIFIERS.lock().unwrap().get(&server_id) {
+        Some(verifier) => builder.with_client_cert_verifier(verifier.clone()),
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_cert_resolver(certificates(server_id));
+    config.alpn_protocols = alpn_protocols;
+    Ok(Arc::new(config))
+}
+
+/// Asks the clients of a server for a certificate issued by one of the
+/// authorities of the PEM bundle at `ca_path`. Clients without one are
+/// still accepted unless `required` is set.
+pub(crate) fn verify_clients(server_id: ServerId, ca_path: &Path, required: bool) -> io::Result<()> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca_path).map_err(|e| pem_error(ca_path, e))? {
+        roots
+            .add(cert.map_err(|e| pem_error(ca_path, e))?)
+            .map_err(invalid_data)?;
+    }
+    let mut builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), PROVIDER.clone());
+    if !required {
+        builder = builder.allow_unauthenticated();
+    }
+    let verifier = builder.build().map_err(invalid_data)?;
+    CLIENT_VERIFIERS.lock().unwrap().insert(server_id, verifier);
+    Ok(())
+}
+
+/// Loads a certificate for `server_name`, or the default one, replacing the
+/// one loaded before for that name.
+pub(crate) fn add_certificate(
+    server_id: ServerId,
+    server_name: Option<&str>,
+    cert_path: &Path,
+    key_path: &Path,
+) -> io::Result<()> {
+    let key = load_key(cert_path, key_path)?;
+    let server_name = server_name.map(|name| name.trim_end_matches('.').to_ascii_lowercase());
+    let certificates = certificates(server_id);
+    let mut certificates = certificates.certificates.lock().unwrap();
+    certificates.retain(|certificate| certificate.server_name != server_name);
+    certificates.push(Certificate {
+        server_name,
+        cert_path: cert_path.to_path_buf(),
+        key_path: key_path.to_path_buf(),
+        key,
+    });
+    Ok(())
+}
+
+/// Reads the certificates of a server from disk again, all of them or none
+/// if one fails to load.
+pub(crate) fn reload(server_id: ServerId) -> io::Result<()> {
+    let certificates = certificates(server_id);
+    let mut certificates = certificates.certificates.lock().unwrap();
+    let keys = certificates
+        .iter()
+        .map(|certificate| load_key(&certificate.cert_path, &certificate.key_path))
+        .collect::<io::Result<Vec<_>>>()?;
+    for (certificate, key) in certificates.iter_mut().zip(keys) {
+        certificate.key = key;
+    }
+    Ok(())
+}
+
+/// Reloads the certificates of every server when SIGHUP is received. The
+/// handler only raises a flag and wakes the loop through `waker`.
+pub(crate) fn reload_on_sighup(waker: RawFd) -> io::Result<()> {
+    extern "C" fn on_sighup(_: libc::c_int) {
+        RELOAD_REQUESTED.store(true, Ordering::Relaxed);
+        let _ = eventfd_signal(RELOAD_WAKER.load(Ordering::Relaxed));
+    }
+    RELOAD_WAKER.store(waker, Ordering::Relaxed);
+    let handler = on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGHUP, handler) } == libc::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Runs the reload asked for by SIGHUP, called from the loop.
+pub(crate) fn reload_if_requested() {
+    if !RELOAD_REQUESTED.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let server_ids: Vec<ServerId> = CERTIFICATES.lock().unwrap().keys().copied().collect();
+    for server_id in server_ids {
+        if let Err(e) = reload(server_id) {
+            eprintln!("couldn't reload certificates of server {}: {}", server_id, e);
+        }
+    }
+}
+
+fn load_key(cert_path: &Path, key_path: &Path) -> io::Result<Arc<CertifiedKey>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(cert_path, e))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificate in {}", cert_path.display()),
+        ));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| pem_error(key_path, e))?;
+    let key = CertifiedKey::from_der(certs, key, &PROVIDER).map_err(invalid_data)?;
+    Ok(Arc::new(key))
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.certificates.lock().unwrap();
+        let find = |name: Option<&str>| {
+            certificates
+                .iter()
+                .find(|certificate| certificate.server_name.as_deref() == name)
+                .map(|certificate| certificate.key.clone())
+        };
+        let server_name = client_hello.server_name().map(str::to_ascii_lowercase);
+        server_name
+            .as_deref()
+            .and_then(|name| {
+                // `*.example.com` covers a single label
+                find(Some(name)).or_else(|| {
+                    let (_, parent) = name.split_once('.')?;
+                    find(Some(&format!("*.{}", parent)))
+                })
+            })
+            .or_else(|| find(None))
+    }
+}
+
+fn pem_error(path: &Path, e: pem::Error) -> io::Error {
+    let kind = match e {
+        pem::Error::Io(ref e) => e.kind(),
+        _ => io::ErrorKind::InvalidData,
+    };
+    io::Error::new(kind, format!("{}: {}", path.display(), e))
+}
+
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Starts the server side of the handshake for a freshly accepted connection.
+pub(crate) fn accept(id: EventId, config: &Arc<ServerConfig>) -> io::Result<()> {
+    let conn = ServerConnection::new(config.clone()).map_err(invalid_data)?;
+    SESSIONS
+        .lock()
+        .unwrap()
+        .insert(
+            id,
+            Session {
+                conn,
+                buffered: 0,
+                client_certificate: None,
+            },
+        );
+    Ok(())
+}
+
+/// The verified certificate the client of connection `id` presented.
+pub(crate) fn client_certificate(id: EventId) -> Option<Arc<ClientCertificate>> {
+    let mut sessions = SESSIONS.lock().unwrap();
+    let session = sessions.get_mut(&id)?;
+    if session.client_certificate.is_none() && !session.conn.is_handshaking() {
+        let chain = session.conn.peer_certificates()?;
+        let chain = chain.iter().map(|cert| cert.to_vec()).collect();
+        session.client_certificate = ClientCertificate::parse(chain).map(Arc::new);
+    }
+    session.client_certificate.clone()
+}
+
+pub(crate) fn read(id: EventId, socket: &TcpStream, buf: &mut [u8]) -> Option<io::Result<usize>> {
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.get_mut(&id).map(|session| session.read(socket, buf))
+}
+
+pub(crate) fn write(id: EventId, socket: &TcpStream, buf: &[u8]) -> Option<io::Result<usize>> {
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.get_mut(&id).map(|session| session.write(socket, buf))
+}
+
+pub(crate) fn flush(id: EventId, socket: &TcpStream) -> Option<io::Result<()>> {
+    let mut sessions = SESSIONS.lock().unwrap();
+    sessions.get_mut(&id).map(|session| session.flush(socket))
+}
+
+pub(crate) fn is_secure(id: EventId) -> bool {
+    SESSIONS.lock().unwrap().contains_key(&id)
+}
+
+/// Whether encrypted records are still waiting for room in the socket.
+pub(crate) fn wants_write(id: EventId) -> bool {
+    SESSIONS
+        .lock()
+        .unwrap()
+        .get(&id)
+        .is_some_and(|session| session.conn.wants_write())
+}
+
+/// Whether decrypted bytes are waiting to be read, no readiness event will
+/// report them.
+pub(crate) fn has_buffered(id: EventId) -> bool {
+    SESSIONS
+        .lock()
+        .unwrap()
+        .get(&id)
+        .is_some_and(|session| session.buffered > 0)
+}
+
+/// Sends close_notify if the socket takes it and forgets the session.
+pub(crate) fn close(id: EventId, socket: &TcpStream) {
+    if let Some(mut session) = SESSIONS.lock().unwrap().remove(&id) {
+        session.conn.send_close_notify();
+        let _ = session.flush(socket);
+    }
+}
+
+impl Session {
+    fn read(&mut self, mut socket: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Ok(n) => {
+                    self.buffered = self.buffered.saturating_sub(n);
+                    return Ok(n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            // handshake messages go out as soon as they are produced
+            match self.flush(socket) {
+                Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+                _ => {}
+            }
+            if self.conn.read_tls(&mut socket)? == 0 {
+                return Ok(0);
+            }
+            match self.conn.process_new_packets() {
+                Ok(state) => self.buffered = state.plaintext_bytes_to_read(),
+                Err(e) => {
+                    // let the peer see the alert
+                    let _ = self.flush(socket);
+                    return Err(invalid_data(e));
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, socket: &TcpStream, buf: &[u8]) -> io::Result<usize> {
+        // earlier records go first, the session would buffer without bound
+        self.flush(socket)?;
+        let n = self.conn.writer().write(buf)?;
+        match self.flush(socket) {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+            _ => {}
+        }
+        if n == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self, mut socket: &TcpStream) -> io::Result<()> {
+        while self.conn.wants_write() {
+            if self.conn.write_tls(&mut socket)? == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use crate::{buffer_pool, EventId};
+
+#[cfg(feature = "tls")]
+use crate::tls;
+
+/// Without TLS support every connection is a plain socket.
+#[cfg(not(feature = "tls"))]
+mod tls {
+    use std::io;
+    use std::net::TcpStream;
+
+    use crate::EventId;
+
+    pub(crate) fn read(_: EventId, _: &TcpStream, _: &mut [u8]) -> Option<io::Result<usize>> {
+        None
+    }
+
+    pub(crate) fn write(_: EventId, _: &TcpStream, _: &[u8]) -> Option<io::Result<usize>> {
+        None
+    }
+
+    pub(crate) fn flush(_: EventId, _: &TcpStream) -> Option<io::Result<()>> {
+        None
+    }
+
+    pub(crate) fn is_secure(_: EventId) -> bool {
+        false
+    }
+
+    pub(crate) fn wants_write(_: EventId) -> bool {
+        false
+    }
+
+    pub(crate) fn has_buffered(_: EventId) -> bool {
+        false
+    }
+
+    pub(crate) fn close(_: EventId, _: &TcpStream) {}
+}
+
+/// The byte stream of a connection: its socket, or the TLS session running
+/// over it when it was accepted on a `listen_tls` listener.
+pub(crate) struct Transport<'a> {
+    id: EventId,
+    socket: &'a TcpStream,
+}
+
+impl<'a> Transport<'a> {
+    pub(crate) fn new(id: EventId, socket: &'a TcpStream) -> Self {
+        Transport { id, socket }
+    }
+
+    pub(crate) fn is_secure(&self) -> bool {
+        tls::is_secure(self.id)
+    }
+
+    /// Whether written bytes are still held back, `flush` pushes them out.
+    pub(crate) fn wants_write(&self) -> bool {
+        tls::wants_write(self.id)
+    }
+
+    /// Whether bytes were received and decrypted but not read yet, a
+    /// readiness event won't come for them.
+    pub(crate) fn has_buffered(&self) -> bool {
+        tls::has_buffered(self.id)
+    }
+
+    /// Ends the TLS session, if any, before the socket is shut down.
+    pub(crate) fn close(&self) {
+        tls::close(self.id, self.socket)
+    }
+
+    /// Appends what can be read to `incoming`, up to about `budget` bytes so
+    /// that other connections get their turn. Returns `true` when the peer
+    /// closed the connection.
+    pub(crate) fn read_budgeted(&mut self, incoming: &mut Vec<u8>, budget: usize) -> io::Result<bool> {
+        let mut total = 0;
+        // bytes decrypted past the budget would not trigger another event
+        while total < budget || self.has_buffered() {
+            match buffer_pool::read_into(incoming, self) {
+                Ok(0) => return Ok(true),
+                Ok(n) => total += n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(false)
+    }
+
+    /// Writes `outgoing` from `written` on until the socket would block, and
+    /// empties it once everything went out.
+    pub(crate) fn write_buffered(&mut self, outgoing: &mut Vec<u8>, written: &mut usize) -> io::Result<()> {
+        while *written < outgoing.len() {
+            match self.write(&outgoing[*written..]) {
+                Ok(n) => *written += n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if *written == outgoing.len() {
+            outgoing.clear();
+            *written = 0;
+            match self.flush() {
+                Err(e) if e.kind() != ErrorKind::WouldBlock => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `write_buffered` left nothing behind, in the buffer or the
+    /// TLS session.
+    pub(crate) fn is_flushed(&self, outgoing: &[u8], written: usize) -> bool {
+        written == outgoing.len() && !self.wants_write()
+    }
+}
+
+impl Read for Transport<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match tls::read(self.id, self.socket, buf) {
+            Some(res) => res,
+            None => {
+                let mut socket = self.socket;
+                socket.read(buf)
+            }
+        }
+    }
+}
+
+impl Write for Transport<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match tls::write(self.id, self.socket, buf) {
+            Some(res) => res,
+            None => {
+                let mut socket = self.socket;
+                socket.write(buf)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        tls::flush(self.id, self.socket).unwrap_or(Ok(()))
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpStream;
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::{watch, EventId, FdEvent, Interest, Request, WatchId};
+
+type UpgradeCallback = Box<dyn FnOnce(Upgraded) + Send>;
+
+lazy_static! {
+    static ref PENDING: Mutex<HashMap<EventId, UpgradeCallback>> = Mutex::new(HashMap::new());
+}
+
+/// A connection switched to another protocol, handed over by
+/// `ResponseWriter::upgrade` once the `101` response is sent.
+///
+/// The stream is non-blocking. It can be moved to a thread of its own
+/// after `set_nonblocking(false)`, or kept on the event loop with `watch`.
+#[derive(Debug)]
+pub struct Upgraded {
+    pub stream: TcpStream,
+    /// Bytes the client sent right after the request, already read from
+    /// the socket. They must be handled before waiting for more.
+    pub buffered: Vec<u8>,
+}
+
+impl Upgraded {
+    /// Registers the stream with the event loop, `callback` runs on the loop
+    /// thread each time it is ready for `interest`. The stream is closed
+    /// when the watch is removed with `HttpServer::unwatch_fd`.
+    pub fn watch<F>(self, interest: Interest, mut callback: F) -> io::Result<WatchId>
+    where
+        F: FnMut(&mut TcpStream, &FdEvent) + Send + 'static,
+    {
+        let mut stream = self.stream;
+        let fd = stream.as_raw_fd();
+        watch::watch(fd, interest, Box::new(move |event| callback(&mut stream, event)))
+    }
+}
+
+pub(crate) fn register(id: EventId, on_upgrade: UpgradeCallback) {
+    PENDING.lock().unwrap().insert(id, on_upgrade);
+}
+
+pub(crate) fn is_pending(id: EventId) -> bool {
+    PENDING.lock().unwrap().contains_key(&id)
+}
+
+/// Calls the upgrade callback of `id` with the connection of `request`.
+pub(crate) fn adopt(id: EventId, request: Request) {
+    let on_upgrade = match PENDING.lock().unwrap().remove(&id) {
+        Some(on_upgrade) => on_upgrade,
+        None => return,
+    };
+    match request.detach() {
+        Ok((stream, buffered)) => on_upgrade(Upgraded { stream, buffered }),
+        Err(e) => eprintln!("couldn't hand over upgraded connection {}: {}", id, e),
+    }
+}
+
+/// Forgets the callback of a connection that failed before the `101` was
+/// sent.
+pub(crate) fn abandon(id: EventId) {
+    PENDING.lock().unwrap().remove(&id);
+}
@@ -1,61 +1,171 @@
-use std::os::unix::io:: RawFd;
+use std::os::unix::io::RawFd;
 use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-const READ_FLAGS: i32 = libc::EPOLLONESHOT | libc::EPOLLIN;
-const WRITE_FLAGS: i32 = libc::EPOLLONESHOT | libc::EPOLLOUT;
-
-#[allow(unused_macros)]
-macro_rules! syscall {
-    ($fn: ident ( $($arg: expr),* $(,)* ) ) => {{
-        let res = unsafe { libc::$fn($($arg, )*) };
-        if res == -1 {
-            Err(std::io::Error::last_os_error())
-        } else {
-            Ok(res)
-        }
-    }};
+pub fn eventfd_create() -> io::Result<RawFd> {
+    syscall!(eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC))
 }
-pub fn epoll_create() -> io::Result<RawFd> {
-    let fd = syscall!(epoll_create1(0))?;
-    if let Ok(flags) = syscall!(fcntl(fd, libc::F_GETFD)) {
-        let _ = syscall!(fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC));
-    }
 
-    Ok(fd)
+pub fn eventfd_signal(fd: RawFd) -> io::Result<()> {
+    let value: u64 = 1;
+    syscall!(write(fd, &value as *const u64 as *const libc::c_void, 8))?;
+    Ok(())
 }
 
-pub fn listener_read_event(key: u64) -> libc::epoll_event {
-    libc::epoll_event {
-        events: READ_FLAGS as u32,
-        u64: key,
-    }
+pub fn timerfd_create() -> io::Result<RawFd> {
+    syscall!(timerfd_create(
+        libc::CLOCK_MONOTONIC,
+        libc::TFD_NONBLOCK | libc::TFD_CLOEXEC
+    ))
 }
 
-pub fn listener_write_event(key: u64) -> libc::epoll_event {
-    libc::epoll_event {
-        events: WRITE_FLAGS as u32,
-        u64: key,
-    }
+/// Arms `fd` to fire once after `delay`, or disarms it when `delay` is `None`.
+pub fn timerfd_arm(fd: RawFd, delay: Option<Duration>) -> io::Result<()> {
+    // an all-zero it_value disarms the timer, so clamp real delays to 1ns
+    let delay = delay.map(|d| d.max(Duration::from_nanos(1))).unwrap_or_default();
+    let spec = libc::itimerspec {
+        it_interval: libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        },
+        it_value: libc::timespec {
+            tv_sec: delay.as_secs() as libc::time_t,
+            tv_nsec: delay.subsec_nanos() as libc::c_long,
+        },
+    };
+    syscall!(timerfd_settime(fd, 0, &spec, std::ptr::null_mut()))?;
+    Ok(())
 }
 
+/// Reads the counter of an eventfd or timerfd, resetting it.
+pub fn drain_counter_fd(fd: RawFd) -> io::Result<u64> {
+    let mut value: u64 = 0;
+    syscall!(read(fd, &mut value as *mut u64 as *mut libc::c_void, 8))?;
+    Ok(value)
+}
 
+/// Copies up to `count` bytes from `in_fd` at `offset` to `out_fd` in the
+/// kernel, advancing `offset` by the amount sent.
+pub fn sendfile(out_fd: RawFd, in_fd: RawFd, offset: &mut u64, count: usize) -> io::Result<usize> {
+    let mut off = *offset as libc::off_t;
+    let sent = syscall!(sendfile(out_fd, in_fd, &mut off, count))?;
+    *offset = off as u64;
+    Ok(sent as usize)
+}
 
-pub fn add_interest(epoll_fd: RawFd, fd: RawFd, mut event: libc::epoll_event) -> io::Result<()> {
-    syscall!(epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut event))?;
-    Ok(())
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats `time` as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn fmt_http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86400;
+    let (hour, minute, second) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+
+    // civil-from-days, http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
 }
 
-pub fn modify_interest(epoll_fd: RawFd, fd: RawFd, mut event: libc::epoll_event) -> io::Result<()> {
-    syscall!(epoll_ctl(epoll_fd, libc::EPOLL_CTL_MOD, fd, &mut event))?;
-    Ok(())
+/// Parses an HTTP date in any of the three formats allowed by RFC 9110:
+/// IMF-fixdate, RFC 850 and asctime.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let tokens: Vec<&str> = date
+        .split([' ', ',', '-', ':'])
+        .filter(|token| !token.is_empty())
+        .collect();
+    let month_of = |name: &str| MONTHS.iter().position(|m| m.eq_ignore_ascii_case(name));
+    let (day, month, year, time) = match tokens.as_slice() {
+        // asctime: Sun Nov  6 08:49:37 1994
+        [_, month, day, h, m, s, year] if month_of(month).is_some() => {
+            (*day, month_of(month)?, *year, [*h, *m, *s])
+        }
+        // IMF-fixdate and RFC 850: Sun, 06 Nov 1994 08:49:37 GMT
+        [_, day, month, year, h, m, s, zone] if zone.eq_ignore_ascii_case("GMT") => {
+            (*day, month_of(month)?, *year, [*h, *m, *s])
+        }
+        _ => return None,
+    };
+    let day: u64 = day.parse().ok()?;
+    let mut year: i64 = year.parse().ok()?;
+    if year < 100 {
+        // two digit years of RFC 850 dates
+        year += if year >= 70 { 1900 } else { 2000 };
+    }
+    let [hour, minute, second] = [
+        time[0].parse::<u64>().ok()?,
+        time[1].parse::<u64>().ok()?,
+        time[2].parse::<u64>().ok()?,
+    ];
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 || year < 1970 {
+        return None;
+    }
+
+    // days-from-civil, the inverse of the computation in fmt_http_date
+    let month = month as i64 + 1;
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days as u64 * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
 }
 
-pub fn remove_interest(epoll_fd: RawFd, fd: RawFd) -> io::Result<()> {
-    syscall!(epoll_ctl(
-        epoll_fd,
-        libc::EPOLL_CTL_DEL,
-        fd,
-        std::ptr::null_mut()
-    ))?;
-    Ok(())
+/// Parses a list of `token;q=value` preferences such as `Accept-Encoding`,
+/// entries without a q-value default to 1.
+pub fn parse_qvalues(header: &str) -> Vec<(&str, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let token = params.next()?.trim();
+            if token.is_empty() {
+                return None;
+            }
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((token, q.clamp(0.0, 1.0)))
+        })
+        .collect()
+}
+
+/// Weight given to `coding` by an `Accept-Encoding` header, falling back to
+/// the `*` entry. 0 means not acceptable.
+pub fn encoding_qvalue(accept_encoding: &str, coding: &str) -> f32 {
+    let prefs = parse_qvalues(accept_encoding);
+    prefs
+        .iter()
+        .find(|(token, _)| token.eq_ignore_ascii_case(coding))
+        .or_else(|| prefs.iter().find(|(token, _)| *token == "*"))
+        .map(|(_, q)| *q)
+        .unwrap_or(0.0)
 }
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::poller::{poller, Event};
+use crate::{EventId, Interest, SERVER_ID};
+
+pub type WatchId = EventId;
+
+/// Readiness reported to a watch callback.
+#[derive(Debug, Clone, Copy)]
+pub struct FdEvent {
+    pub watch_id: WatchId,
+    pub fd: RawFd,
+    pub readable: bool,
+    pub writable: bool,
+    pub hangup: bool,
+    pub error: bool,
+}
+
+type WatchCallback = Box<dyn FnMut(&FdEvent) + Send>;
+
+struct Watched {
+    fd: RawFd,
+    interest: Interest,
+    // taken out while the callback runs so the lock is not held
+    callback: Option<WatchCallback>,
+}
+
+lazy_static! {
+    static ref WATCHED: Mutex<HashMap<WatchId, Watched>> = Mutex::new(HashMap::new());
+}
+
+pub(crate) fn watch(fd: RawFd, interest: Interest, callback: WatchCallback) -> io::Result<WatchId> {
+    let watch_id = {
+        let mut next_id = SERVER_ID.lock().unwrap();
+        let watch_id = *next_id;
+        *next_id += 1;
+        watch_id
+    };
+    WATCHED.lock().unwrap().insert(
+        watch_id,
+        Watched {
+            fd,
+            interest,
+            callback: Some(callback),
+        },
+    );
+    if let Err(e) = poller().add(fd, watch_id as u64, interest) {
+        WATCHED.lock().unwrap().remove(&watch_id);
+        return Err(e);
+    }
+    Ok(watch_id)
+}
+
+pub(crate) fn set_interest(watch_id: WatchId, interest: Interest) -> io::Result<()> {
+    let mut watched = WATCHED.lock().unwrap();
+    let entry = watched
+        .get_mut(&watch_id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown watch id"))?;
+    entry.interest = interest;
+    // re-armed after the callback returns when it is the one calling us
+    if entry.callback.is_some() {
+        poller().modify(entry.fd, watch_id as u64, interest)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn unwatch(watch_id: WatchId) -> io::Result<()> {
+    let entry = WATCHED
+        .lock()
+        .unwrap()
+        .remove(&watch_id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "unknown watch id"))?;
+    poller().remove(entry.fd)
+}
+
+/// Runs the callback registered for `ev`, returns `false` if the key is not
+/// a watched file descriptor.
+pub(crate) fn dispatch(ev: &Event) -> bool {
+    let watch_id = ev.key as WatchId;
+    let (fd, mut callback) = match WATCHED.lock().unwrap().get_mut(&watch_id) {
+        Some(entry) => match entry.callback.take() {
+            Some(callback) => (entry.fd, callback),
+            None => return true,
+        },
+        None => return false,
+    };
+
+    callback(&FdEvent {
+        watch_id,
+        fd,
+        readable: ev.readable,
+        writable: ev.writable,
+        hangup: ev.hangup,
+        error: ev.error,
+    });
+
+    // the callback may have unwatched itself
+    if let Some(entry) = WATCHED.lock().unwrap().get_mut(&watch_id) {
+        entry.callback = Some(callback);
+        if let Err(e) = poller().modify(entry.fd, watch_id as u64, entry.interest) {
+            eprintln!("couldn't re-arm watched fd {}: {}", entry.fd, e);
+        }
+    }
+    true
+}
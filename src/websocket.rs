@@ -0,0 +1,645 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use lazy_static::lazy_static;
+
+use crate::poller::poller;
+use crate::transport::Transport;
+use crate::{hub, timer, wake_loop, EventId, HttpStatus, Interest, Request, ResponseWriter};
+
+pub type WebSocketId = EventId;
+
+pub(crate) type WebSocketHandler = Box<dyn Fn(&WebSocket, WebSocketEvent) + Send + Sync>;
+
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_NO_STATUS: u16 = 1005;
+const CLOSE_ABNORMAL: u16 = 1006;
+const CLOSE_INVALID_DATA: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+// how long a peer has to answer our close frame before the socket is dropped
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// bytes read per readiness event before yielding to other connections
+const READ_BUDGET: usize = 1024 * 1024;
+
+/// A complete message, reassembled from its fragments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// What a WebSocket handler is called with.
+pub enum WebSocketEvent<'a> {
+    /// The handshake succeeded, messages sent from here on follow it.
+    Open(&'a Request),
+    Message(Message),
+    /// The connection is gone, after a close handshake or not (code `1006`).
+    /// Called exactly once per connection.
+    Close { code: u16, reason: String },
+}
+
+/// Handle to a WebSocket connection. It is `Copy` and `Send`, so it can be
+/// stored and used from any handler or thread to push messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct WebSocket {
+    id: WebSocketId,
+}
+
+/// Frames waiting to be picked up by the loop, filled from any thread.
+#[derive(Default)]
+struct Outbox {
+    data: Vec<u8>,
+    close_sent: bool,
+    force_close: bool,
+}
+
+struct Connection {
+    id: WebSocketId,
+    stream: TcpStream,
+    handler: Arc<WebSocketHandler>,
+    max_message: usize,
+    incoming: Vec<u8>,
+    // opcode and payload of a fragmented message being received
+    fragments: Option<(u8, Vec<u8>)>,
+    outgoing: Vec<u8>,
+    written: usize,
+    close_received: bool,
+    close_sent: bool,
+    // the socket is closed once `outgoing` is flushed
+    closing: bool,
+    close_reported: bool,
+}
+
+/// Set on the request by a successful handshake, the connection is handed
+/// over once the `101` response is sent.
+pub(crate) struct WebSocketUpgrade {
+    pub(crate) handler: Arc<WebSocketHandler>,
+    pub(crate) max_message: usize,
+}
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<HashMap<WebSocketId, Connection>> = Mutex::new(HashMap::new());
+    static ref OUTBOX: Mutex<HashMap<WebSocketId, Outbox>> = Mutex::new(HashMap::new());
+    static ref DIRTY: Mutex<Vec<WebSocketId>> = Mutex::new(Vec::new());
+}
+
+impl WebSocket {
+    pub(crate) fn new(id: WebSocketId) -> Self {
+        WebSocket { id }
+    }
+
+    pub fn id(&self) -> WebSocketId {
+        self.id
+    }
+
+    /// Returns `false` once the connection is closed or closing.
+    pub fn send_text(&self, text: &str) -> bool {
+        queue(self.id, OP_TEXT, text.as_bytes())
+    }
+
+    pub fn send_binary(&self, data: &[u8]) -> bool {
+        queue(self.id, OP_BINARY, data)
+    }
+
+    pub fn send(&self, message: &Message) -> bool {
+        match message {
+            Message::Text(text) => self.send_text(text),
+            Message::Binary(data) => self.send_binary(data),
+        }
+    }
+
+    pub fn ping(&self, payload: &[u8]) -> bool {
+        payload.len() <= 125 && queue(self.id, OP_PING, payload)
+    }
+
+    /// Starts the close handshake. The socket is closed when the peer
+    /// answers, or after a few seconds if it does not.
+    pub fn close(&self, code: u16, reason: &str) -> bool {
+        queue(self.id, OP_CLOSE, &close_payload(code, reason))
+    }
+
+    pub fn is_open(&self) -> bool {
+        OUTBOX
+            .lock()
+            .unwrap()
+            .get(&self.id)
+            .is_some_and(|outbox| !outbox.close_sent)
+    }
+}
+
+fn queue(id: WebSocketId, opcode: u8, payload: &[u8]) -> bool {
+    let mut outboxes = OUTBOX.lock().unwrap();
+    let outbox = match outboxes.get_mut(&id) {
+        Some(outbox) if !outbox.close_sent => outbox,
+        _ => return false,
+    };
+    encode_frame(&mut outbox.data, opcode, payload);
+    outbox.close_sent = opcode == OP_CLOSE;
+    drop(outboxes);
+    if opcode == OP_CLOSE {
+        timer::schedule(
+            CLOSE_TIMEOUT,
+            None,
+            Box::new(move || {
+                if let Some(outbox) = OUTBOX.lock().unwrap().get_mut(&id) {
+                    outbox.force_close = true;
+                    DIRTY.lock().unwrap().push(id);
+                }
+            }),
+        );
+    }
+    DIRTY.lock().unwrap().push(id);
+    wake_loop();
+    true
+}
+
+/// Validates the upgrade request and writes the `101 Switching Protocols`
+/// response. Returns `false` when an error response was written instead.
+pub(crate) fn handshake(req: &Request, w: &mut ResponseWriter) -> bool {
+    let upgrade = req
+        .header("Upgrade")
+        .is_some_and(|v| has_token(v, "websocket"));
+    let connection = req
+        .header("Connection")
+        .is_some_and(|v| has_token(v, "upgrade"));
+    if !upgrade || !connection {
+        w.headers
+            .insert("Upgrade".to_string(), vec!["websocket".to_string()]);
+        w.headers
+            .insert("Connection".to_string(), vec!["Upgrade".to_string()]);
+        w.write_status(HttpStatus::UpgradeRequired);
+        return false;
+    }
+    if req.header("Sec-WebSocket-Version").map(str::trim) != Some("13") {
+        w.headers
+            .insert("Sec-WebSocket-Version".to_string(), vec!["13".to_string()]);
+        w.write_status(HttpStatus::UpgradeRequired);
+        return false;
+    }
+    let key = match req.header("Sec-WebSocket-Key").map(str::trim) {
+        Some(key) if BASE64.decode(key).is_ok_and(|k| k.len() == 16) => key,
+        _ => {
+            w.write_status(HttpStatus::BadRequest);
+            return false;
+        }
+    };
+
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(HANDSHAKE_GUID.as_bytes());
+    let accept = BASE64.encode(sha1.digest().bytes());
+
+    w.headers
+        .insert("Upgrade".to_string(), vec!["websocket".to_string()]);
+    w.headers
+        .insert("Connection".to_string(), vec!["Upgrade".to_string()]);
+    w.headers
+        .insert("Sec-WebSocket-Accept".to_string(), vec![accept]);
+    w.set_status(HttpStatus::SwitchingProtocols.code());
+    w.keep_open();
+    w.write_string("");
+    OUTBOX.lock().unwrap().insert(w.event_id, Outbox::default());
+    true
+}
+
+/// Takes over the socket of an upgraded request once its `101` response is
+/// sent. Does nothing for other requests.
+pub(crate) fn adopt(id: WebSocketId, mut request: Request) {
+    let upgrade = match request.websocket.take() {
+        Some(upgrade) => upgrade,
+        None => return,
+    };
+    let (stream, incoming) = match request.detach().and_then(|(stream, incoming)| {
+        poller().add(stream.as_raw_fd(), id as u64, Interest::Read)?;
+        Ok((stream, incoming))
+    }) {
+        Ok(detached) => detached,
+        Err(e) => {
+            eprintln!("couldn't keep websocket {} open: {}", id, e);
+            return abandon(id, upgrade);
+        }
+    };
+
+    // early frames may be held by the TLS session rather than the socket
+    let buffered = Transport::new(id, &stream).has_buffered();
+    CONNECTIONS.lock().unwrap().insert(
+        id,
+        Connection {
+            id,
+            stream,
+            handler: upgrade.handler,
+            max_message: upgrade.max_message,
+            incoming,
+            fragments: None,
+            outgoing: Vec::new(),
+            written: 0,
+            close_received: false,
+            close_sent: false,
+            closing: false,
+            close_reported: false,
+        },
+    );
+    // frames sent from the open callback, and any sent early by the client
+    service(id, buffered);
+}
+
+/// Drops a handshake whose response could not be sent.
+pub(crate) fn abandon(id: WebSocketId, upgrade: WebSocketUpgrade) {
+    OUTBOX.lock().unwrap().remove(&id);
+    hub::connection_closed(id);
+    (upgrade.handler)(
+        &WebSocket { id },
+        WebSocketEvent::Close {
+            code: CLOSE_ABNORMAL,
+            reason: String::new(),
+        },
+    );
+}
+
+/// Bytes queued for `id` and not written yet, `None` once it is closed or
+/// closing.
+pub(crate) fn backlog(id: WebSocketId) -> Option<usize> {
+    let queued = match OUTBOX.lock().unwrap().get(&id) {
+        Some(outbox) if !outbox.close_sent => outbox.data.len(),
+        _ => return None,
+    };
+    let unsent = CONNECTIONS
+        .lock()
+        .unwrap()
+        .get(&id)
+        .map_or(0, |conn| conn.outgoing.len() - conn.written);
+    Some(queued + unsent)
+}
+
+/// Drops the connection without a close handshake, on the next iteration.
+pub(crate) fn abort(id: WebSocketId) {
+    if let Some(outbox) = OUTBOX.lock().unwrap().get_mut(&id) {
+        outbox.close_sent = true;
+        outbox.force_close = true;
+    }
+    DIRTY.lock().unwrap().push(id);
+    wake_loop();
+}
+
+/// Handles readiness of a WebSocket connection, returns `false` if the key
+/// is not one.
+pub(crate) fn dispatch(ev: &crate::poller::Event) -> bool {
+    let id = ev.key as WebSocketId;
+    if !CONNECTIONS.lock().unwrap().contains_key(&id) {
+        return false;
+    }
+    service(id, ev.readable || ev.hangup || ev.error);
+    true
+}
+
+/// Sends what other handlers and threads queued since the last iteration.
+pub(crate) fn flush_pending() {
+    let mut dirty = std::mem::take(&mut *DIRTY.lock().unwrap());
+    dirty.sort_unstable();
+    dirty.dedup();
+    for id in dirty {
+        service(id, false);
+    }
+}
+
+fn service(id: WebSocketId, readable: bool) {
+    // taken out of the map so handlers run without the lock
+    let mut conn = match CONNECTIONS.lock().unwrap().remove(&id) {
+        Some(conn) => conn,
+        None => return,
+    };
+    let ws = WebSocket { id };
+    let mut events = Vec::new();
+
+    let mut eof = false;
+    if readable {
+        eof = match conn.read() {
+            Ok(eof) => eof,
+            Err(e) => {
+                eprintln!("error reading websocket {}: {}", id, e);
+                true
+            }
+        };
+    }
+    if let Err((code, reason)) = conn.parse(&mut events) {
+        conn.fail(code, reason);
+        if !conn.close_reported {
+            conn.close_reported = true;
+            events.push(WebSocketEvent::Close {
+                code,
+                reason: reason.to_string(),
+            });
+        }
+    }
+    for event in events {
+        (conn.handler)(&ws, event);
+    }
+
+    let force_close = conn.take_outbox(id);
+    if eof || force_close {
+        return teardown(id, conn);
+    }
+    match conn.write() {
+        Ok(()) => {}
+        Err(e) => {
+            eprintln!("error writing websocket {}: {}", id, e);
+            return teardown(id, conn);
+        }
+    }
+    if conn.closing && conn.is_flushed() {
+        return teardown(id, conn);
+    }
+
+    let interest = if !conn.is_flushed() {
+        Interest::ReadWrite
+    } else {
+        Interest::Read
+    };
+    if let Err(e) = poller().modify(conn.stream.as_raw_fd(), id as u64, interest) {
+        eprintln!("couldn't re-arm websocket {}: {}", id, e);
+        return teardown(id, conn);
+    }
+    CONNECTIONS.lock().unwrap().insert(id, conn);
+}
+
+fn teardown(id: WebSocketId, conn: Connection) {
+    let _ = poller().remove(conn.stream.as_raw_fd());
+    Transport::new(id, &conn.stream).close();
+    let _ = conn.stream.shutdown(Shutdown::Both);
+    OUTBOX.lock().unwrap().remove(&id);
+    hub::connection_closed(id);
+    if !conn.close_reported {
+        (conn.handler)(
+            &WebSocket { id },
+            WebSocketEvent::Close {
+                code: CLOSE_ABNORMAL,
+                reason: String::new(),
+            },
+        );
+    }
+}
+
+impl Connection {
+    /// Returns `true` when the peer closed the TCP connection.
+    fn read(&mut self) -> io::Result<bool> {
+        Transport::new(self.id, &self.stream).read_budgeted(&mut self.incoming, READ_BUDGET)
+    }
+
+    /// Consumes the complete frames of `incoming`.
+    fn parse(&mut self, events: &mut Vec<WebSocketEvent<'static>>) -> Result<(), (u16, &'static str)> {
+        let mut consumed = 0;
+        let res = loop {
+            if self.close_received || self.closing {
+                // nothing is read after a close frame
+                consumed = self.incoming.len();
+                break Ok(());
+            }
+            match parse_frame(&self.incoming[consumed..], self.max_message)? {
+                Some((frame, len)) => {
+                    consumed += len;
+                    if let Err(e) = self.handle_frame(frame, events) {
+                        break Err(e);
+                    }
+                }
+                None => break Ok(()),
+            }
+        };
+        self.incoming.drain(..consumed);
+        res
+    }
+
+    fn handle_frame(
+        &mut self,
+        frame: Frame,
+        events: &mut Vec<WebSocketEvent<'static>>,
+    ) -> Result<(), (u16, &'static str)> {
+        match frame.opcode {
+            OP_TEXT | OP_BINARY => {
+                if self.fragments.is_some() {
+                    return Err((CLOSE_PROTOCOL_ERROR, "expected a continuation frame"));
+                }
+                if frame.fin {
+                    events.push(WebSocketEvent::Message(message(frame.opcode, frame.payload)?));
+                } else {
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+            }
+            OP_CONTINUATION => {
+                let (opcode, mut payload) = self
+                    .fragments
+                    .take()
+                    .ok_or((CLOSE_PROTOCOL_ERROR, "unexpected continuation frame"))?;
+                if payload.len() + frame.payload.len() > self.max_message {
+                    return Err((CLOSE_TOO_BIG, "message too big"));
+                }
+                payload.extend_from_slice(&frame.payload);
+                if frame.fin {
+                    events.push(WebSocketEvent::Message(message(opcode, payload)?));
+                } else {
+                    self.fragments = Some((opcode, payload));
+                }
+            }
+            OP_PING => encode_frame(&mut self.outgoing, OP_PONG, &frame.payload),
+            OP_PONG => {}
+            OP_CLOSE => {
+                let (code, reason) = parse_close(&frame.payload)?;
+                self.close_received = true;
+                if !self.close_sent {
+                    // echo the status code, as RFC 6455 section 5.5.1 asks
+                    let echo = if code == CLOSE_NO_STATUS {
+                        Vec::new()
+                    } else {
+                        close_payload(code, "")
+                    };
+                    encode_frame(&mut self.outgoing, OP_CLOSE, &echo);
+                    self.close_sent = true;
+                }
+                self.closing = true;
+                self.close_reported = true;
+                events.push(WebSocketEvent::Close { code, reason });
+            }
+            _ => return Err((CLOSE_PROTOCOL_ERROR, "unknown opcode")),
+        }
+        Ok(())
+    }
+
+    /// Sends a close frame for a protocol violation and closes the socket
+    /// once it is written, without waiting for the answer.
+    fn fail(&mut self, code: u16, reason: &str) {
+        if !self.close_sent {
+            encode_frame(&mut self.outgoing, OP_CLOSE, &close_payload(code, reason));
+            self.close_sent = true;
+        }
+        self.closing = true;
+    }
+
+    /// Moves the frames queued through the `WebSocket` handle to `outgoing`,
+    /// returns `true` when the close timeout expired.
+    fn take_outbox(&mut self, id: WebSocketId) -> bool {
+        let mut outboxes = OUTBOX.lock().unwrap();
+        let outbox = match outboxes.get_mut(&id) {
+            Some(outbox) => outbox,
+            None => return false,
+        };
+        if !self.close_sent {
+            self.outgoing.extend_from_slice(&outbox.data);
+            self.close_sent = outbox.close_sent;
+        }
+        outbox.data.clear();
+        // frames queued after ours are dropped, ours is the last one sent
+        outbox.close_sent |= self.close_sent;
+        outbox.force_close
+    }
+
+    fn write(&mut self) -> io::Result<()> {
+        Transport::new(self.id, &self.stream).write_buffered(&mut self.outgoing, &mut self.written)
+    }
+
+    fn is_flushed(&self) -> bool {
+        Transport::new(self.id, &self.stream).is_flushed(&self.outgoing, self.written)
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Parses the frame at the start of `buf`, `None` if it is not complete yet.
+fn parse_frame(
+    buf: &[u8],
+    max_message: usize,
+) -> Result<Option<(Frame, usize)>, (u16, &'static str)> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buf[0] & 0x80 != 0;
+    if buf[0] & 0x70 != 0 {
+        return Err((CLOSE_PROTOCOL_ERROR, "reserved bits set"));
+    }
+    let opcode = buf[0] & 0x0F;
+    if buf[1] & 0x80 == 0 {
+        return Err((CLOSE_PROTOCOL_ERROR, "client frames must be masked"));
+    }
+    let (len, mut pos) = match buf[1] & 0x7F {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4)
+        }
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+            let mut len = [0; 8];
+            len.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(len), 10)
+        }
+        len => (len as u64, 2),
+    };
+    if opcode >= OP_CLOSE && (!fin || len > 125) {
+        return Err((CLOSE_PROTOCOL_ERROR, "invalid control frame"));
+    }
+    if len > max_message as u64 {
+        return Err((CLOSE_TOO_BIG, "message too big"));
+    }
+    let len = len as usize;
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+    let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+    pos += 4;
+    let payload = buf[pos..pos + len]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        pos + len,
+    )))
+}
+
+fn message(opcode: u8, payload: Vec<u8>) -> Result<Message, (u16, &'static str)> {
+    if opcode == OP_TEXT {
+        String::from_utf8(payload)
+            .map(Message::Text)
+            .map_err(|_| (CLOSE_INVALID_DATA, "invalid utf-8"))
+    } else {
+        Ok(Message::Binary(payload))
+    }
+}
+
+fn parse_close(payload: &[u8]) -> Result<(u16, String), (u16, &'static str)> {
+    match payload {
+        [] => Ok((CLOSE_NO_STATUS, String::new())),
+        [_] => Err((CLOSE_PROTOCOL_ERROR, "invalid close frame")),
+        [hi, lo, reason @ ..] => {
+            let code = u16::from_be_bytes([*hi, *lo]);
+            // codes a peer may send, RFC 6455 section 7.4
+            let valid = matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999);
+            if !valid {
+                return Err((CLOSE_PROTOCOL_ERROR, "invalid close code"));
+            }
+            let reason = std::str::from_utf8(reason)
+                .map_err(|_| (CLOSE_INVALID_DATA, "invalid utf-8"))?;
+            Ok((code, reason.to_string()))
+        }
+    }
+}
+
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    // control frames carry at most 125 bytes
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    payload
+}
+
+/// Appends an unmasked frame, as servers send them.
+fn encode_frame(out: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+    out.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => out.push(len as u8),
+        len @ 126..=0xFFFF => {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+}
+
+pub(crate) fn has_token(value: &str, token: &str) -> bool {
+    value
+        .split(',')
+        .any(|item| item.trim().eq_ignore_ascii_case(token))
+}
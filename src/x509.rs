@@ -0,0 +1,341 @@
+use std::fmt::Write;
+use std::net::IpAddr;
+
+// DER tags
+const BOOLEAN: u8 = 0x01;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+// GeneralName choices, implicitly tagged
+const RFC822_NAME: u8 = 0x81;
+const DNS_NAME: u8 = 0x82;
+const URI: u8 = 0x86;
+const IP_ADDRESS: u8 = 0x87;
+
+// 2.5.29.17
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// The certificate a TLS client authenticated with, see
+/// `HttpServer::verify_client_certificates`.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    /// The DER encoded chain sent by the client, its own certificate first.
+    pub chain: Vec<Vec<u8>>,
+    /// The subject of the client certificate as an RFC 4514 string, e.g.
+    /// `CN=billing,O=Example`.
+    pub subject: String,
+    /// The subject alternative names of the client certificate.
+    pub alt_names: Vec<SubjectAltName>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectAltName {
+    Dns(String),
+    Email(String),
+    Uri(String),
+    Ip(IpAddr),
+}
+
+impl ClientCertificate {
+    /// Reads the subject and alternative names of the first certificate,
+    /// `None` if it is not valid DER.
+    pub(crate) fn parse(chain: Vec<Vec<u8>>) -> Option<Self> {
+        let (subject, alt_names) = describe(chain.first()?)?;
+        Some(ClientCertificate {
+            chain,
+            subject,
+            alt_names,
+        })
+    }
+}
+
+fn describe(cert: &[u8]) -> Option<(String, Vec<SubjectAltName>)> {
+    let cert = Der(cert).expect(SEQUENCE)?;
+    let mut tbs = Der(Der(cert).expect(SEQUENCE)?);
+    let (tag, _) = tbs.next()?;
+    if tag == VERSION {
+        // serial number
+        tbs.next()?;
+    }
+    // signature algorithm, issuer and validity
+    for _ in 0..3 {
+        tbs.next()?;
+    }
+    let subject = format_name(tbs.expect(SEQUENCE)?)?;
+    // public key, then the optional unique ids and extensions
+    tbs.next()?;
+    let mut alt_names = Vec::new();
+    while let Some((tag, value)) = tbs.next() {
+        if tag != EXTENSIONS {
+            continue;
+        }
+        let mut extensions = Der(Der(value).expect(SEQUENCE)?);
+        while let Some(extension) = extensions.expect(SEQUENCE) {
+            let mut extension = Der(extension);
+            let oid = extension.expect(OID)?;
+            let (mut tag, mut value) = extension.next()?;
+            if tag == BOOLEAN {
+                (tag, value) = extension.next()?;
+            }
+            if oid == SUBJECT_ALT_NAME && tag == OCTET_STRING {
+                alt_names = parse_alt_names(Der(value).expect(SEQUENCE)?);
+            }
+        }
+    }
+    Some((subject, alt_names))
+}
+
+fn parse_alt_names(names: &[u8]) -> Vec<SubjectAltName> {
+    let mut names = Der(names);
+    let mut alt_names = Vec::new();
+    while let Some((tag, value)) = names.next() {
+        let text = || String::from_utf8_lossy(value).into_owned();
+        let name = match tag {
+            DNS_NAME => SubjectAltName::Dns(text()),
+            RFC822_NAME => SubjectAltName::Email(text()),
+            URI => SubjectAltName::Uri(text()),
+            IP_ADDRESS => {
+                let ip = match value.len() {
+                    4 => IpAddr::from(<[u8; 4]>::try_from(value).unwrap()),
+                    16 => IpAddr::from(<[u8; 16]>::try_from(value).unwrap()),
+                    _ => continue,
+                };
+                SubjectAltName::Ip(ip)
+            }
+            // other names, directory names and the like
+            _ => continue,
+        };
+        alt_names.push(name);
+    }
+    alt_names
+}
+
+/// Formats a distinguished name the way RFC 4514 does, last RDN first.
+fn format_name(name: &[u8]) -> Option<String> {
+    let mut rdns = Vec::new();
+    let mut name = Der(name);
+    while let Some(set) = name.expect(SET) {
+        let mut attributes = Vec::new();
+        let mut set = Der(set);
+        while let Some(attribute) = set.expect(SEQUENCE) {
+            let mut attribute = Der(attribute);
+            let oid = attribute.expect(OID)?;
+            let (tag, value) = attribute.next()?;
+            attributes.push(format!("{}={}", attribute_name(oid), attribute_value(tag, value)));
+        }
+        rdns.push(attributes.join("+"));
+    }
+    rdns.reverse();
+    Some(rdns.join(","))
+}
+
+fn attribute_name(oid: &[u8]) -> String {
+    let name = match oid {
+        [0x55, 0x04, 0x03] => "CN",
+        [0x55, 0x04, 0x06] => "C",
+        [0x55, 0x04, 0x07] => "L",
+        [0x55, 0x04, 0x08] => "ST",
+        [0x55, 0x04, 0x09] => "STREET",
+        [0x55, 0x04, 0x0a] => "O",
+        [0x55, 0x04, 0x0b] => "OU",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x01] => "UID",
+        [0x09, 0x92, 0x26, 0x89, 0x93, 0xf2, 0x2c, 0x64, 0x01, 0x19] => "DC",
+        _ => return dotted(oid),
+    };
+    name.to_string()
+}
+
+fn dotted(oid: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut arc: u64 = 0;
+    for &b in oid {
+        arc = (arc << 7) | (b & 0x7f) as u64;
+        if b & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (arc / 40).min(2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+            arc = 0;
+        }
+    }
+    arcs.iter()
+        .map(|arc| arc.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn attribute_value(tag: u8, value: &[u8]) -> String {
+    let text = match tag {
+        // UTF8String, PrintableString, TeletexString, IA5String
+        0x0c | 0x13 | 0x14 | 0x16 => String::from_utf8_lossy(value).into_owned(),
+        // BMPString
+        0x1e => char::decode_utf16(
+            value
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]])),
+        )
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect(),
+        // anything else is given as the hex of its encoding
+        _ => {
+            let mut hex = format!("#{:02x}{:02x}", tag, value.len());
+            for b in value {
+                let _ = write!(hex, "{:02x}", b);
+            }
+            return hex;
+        }
+    };
+    let mut escaped = String::with_capacity(text.len());
+    let last = text.chars().count().saturating_sub(1);
+    for (i, c) in text.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (i == 0 && (c == '#' || c == ' '))
+            || (i == last && c == ' ');
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Reads DER elements one after the other.
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    /// The tag and contents of the next element.
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let (&tag, rest) = self.0.split_first()?;
+        let (&first, mut rest) = rest.split_first()?;
+        let len = if first < 0x80 {
+            first as usize
+        } else {
+            let count = (first & 0x7f) as usize;
+            if count == 0 || count > 4 || rest.len() < count {
+                return None;
+            }
+            let len = rest[..count]
+                .iter()
+                .fold(0usize, |len, &b| (len << 8) | b as usize);
+            rest = &rest[count..];
+            len
+        };
+        if rest.len() < len {
+            return None;
+        }
+        let (value, rest) = rest.split_at(len);
+        self.0 = rest;
+        Some((tag, value))
+    }
+
+    /// The contents of the next element, if it has the given tag.
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        match self.next()? {
+            (found, value) if found == tag => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, SanType};
+
+    fn parse(der: &[u8]) -> Option<ClientCertificate> {
+        ClientCertificate::parse(vec![der.to_vec()])
+    }
+
+    fn certificate() -> Vec<u8> {
+        let mut params = CertificateParams::new(vec!["client.test".to_string()]).unwrap();
+        params.subject_alt_names.extend([
+            SanType::Rfc822Name("ops@example.com".try_into().unwrap()),
+            SanType::URI("spiffe://example.com/billing".try_into().unwrap()),
+            SanType::IpAddress("10.0.0.1".parse().unwrap()),
+        ]);
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::OrganizationName, "Example");
+        params.distinguished_name.push(DnType::CommonName, "billing, eu");
+        let key = KeyPair::generate().unwrap();
+        params.self_signed(&key).unwrap().der().to_vec()
+    }
+
+    #[test]
+    fn reads_subject_and_alt_names() {
+        let parsed = parse(&certificate()).unwrap();
+        assert_eq!(parsed.subject, "CN=billing\\, eu,O=Example");
+        assert_eq!(
+            parsed.alt_names,
+            [
+                SubjectAltName::Dns("client.test".to_string()),
+                SubjectAltName::Email("ops@example.com".to_string()),
+                SubjectAltName::Uri("spiffe://example.com/billing".to_string()),
+                SubjectAltName::Ip("10.0.0.1".parse().unwrap()),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_der_is_rejected() {
+        let der = certificate();
+        for len in 0..der.len() {
+            assert!(parse(&der[..len]).is_none(), "{}", len);
+        }
+    }
+
+    #[test]
+    fn oversized_lengths_are_rejected() {
+        // longer than the data
+        assert!(parse(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff, 0x30]).is_none());
+        // more length bytes than a length may have
+        assert!(parse(&[0x30, 0x89, 1, 0, 0, 0, 0, 0, 0, 0, 0]).is_none());
+        // indefinite length, not DER
+        assert!(parse(&[0x30, 0x80, 0, 0]).is_none());
+
+        // the outer length grown past the certificate
+        let mut der = certificate();
+        assert_eq!(der[1], 0x82);
+        let len = u16::from_be_bytes([der[2], der[3]]) + 1;
+        der[2..4].copy_from_slice(&len.to_be_bytes());
+        assert!(parse(&der).is_none());
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        assert!(ClientCertificate::parse(Vec::new()).is_none());
+        assert!(parse(b"not a certificate").is_none());
+        assert!(parse(&[0x30, 0x03, 0x02, 0x01, 0x00]).is_none());
+
+        // whatever the bytes, parsing must not panic
+        let mut state: u32 = 0x1234_5678;
+        for len in 0..512 {
+            let garbage: Vec<u8> = (0..len)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (state >> 24) as u8
+                })
+                .collect();
+            let _ = parse(&garbage);
+        }
+        let der = certificate();
+        for i in 0..der.len() {
+            let mut corrupted = der.clone();
+            corrupted[i] ^= 0xff;
+            let _ = parse(&corrupted);
+        }
+    }
+
+    #[test]
+    fn alt_names_of_unknown_shape_are_skipped() {
+        // an IP address of 3 bytes, then a DNS name
+        let names = [0x87, 3, 10, 0, 0, 0x82, 1, b'a'];
+        assert_eq!(parse_alt_names(&names), [SubjectAltName::Dns("a".to_string())]);
+    }
+}
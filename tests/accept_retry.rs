@@ -0,0 +1,50 @@
+//! An edge-triggered listener gets no new edge for the connections left in
+//! its backlog after a failed accept, it must try again by itself.
+
+mod common;
+
+use std::fs::File;
+use std::io::Write;
+
+use common::*;
+use http_lolo::PollerKind;
+
+fn set_fd_limit(limit: libc::rlim_t) -> libc::rlim_t {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit), 0);
+        let previous = rlimit.rlim_cur;
+        rlimit.rlim_cur = limit;
+        assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &rlimit), 0);
+        previous
+    }
+}
+
+#[test]
+fn edge_triggered_listener_accepts_again_after_emfile() {
+    let port = start(PollerKind::Epoll, true);
+    // the loop has its own descriptors once it served a request
+    assert_eq!(get(port, "/").status, 200);
+
+    let previous = set_fd_limit((settled_fds() + 16) as libc::rlim_t);
+    let mut fillers = Vec::new();
+    while let Ok(file) = File::open("/dev/null") {
+        fillers.push(file);
+    }
+    // the last free descriptor goes to the client, the server has none left
+    fillers.pop();
+    let mut stream = connect(port);
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n")
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+
+    drop(fillers);
+    set_fd_limit(previous);
+    let response = Response::read(&mut stream);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"hello");
+}
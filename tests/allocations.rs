@@ -0,0 +1,121 @@
+//! Allocations made by the event loop per request, with the read and write
+//! buffers coming from the pool.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use http_lolo::{HttpServer, Request, ResponseWriter};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // only the loop thread is counted, not the clients
+    static COUNTED: Cell<bool> = const { Cell::new(false) };
+}
+
+fn count(size: usize) {
+    if COUNTED.try_with(Cell::get).unwrap_or(false) {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn start() -> u16 {
+    let server = HttpServer::new();
+    server.handle_route(
+        "/",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| w.write_string("hello")),
+    );
+    server.handle_method(
+        "POST",
+        "/upload",
+        Box::new(|r: &mut Request, w: &mut ResponseWriter| {
+            w.write_string(&r.body.len().to_string())
+        }),
+    );
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    server.listen_on(&format!("127.0.0.1:{}", port));
+    thread::spawn(|| {
+        COUNTED.with(|counted| counted.set(true));
+        HttpServer::run_all();
+    });
+    port
+}
+
+fn exchange(port: u16, request: &[u8], response: &mut Vec<u8>) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.write_all(request).unwrap();
+    response.clear();
+    stream.read_to_end(response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 200"));
+}
+
+/// Average allocations and bytes allocated by the loop per request.
+fn measure(port: u16, request: &[u8], requests: usize) -> (f64, f64) {
+    let mut response = Vec::new();
+    // fills the pools
+    for _ in 0..50 {
+        exchange(port, request, &mut response);
+    }
+    ALLOCATIONS.store(0, Ordering::Relaxed);
+    ALLOCATED.store(0, Ordering::Relaxed);
+    for _ in 0..requests {
+        exchange(port, request, &mut response);
+    }
+    (
+        ALLOCATIONS.load(Ordering::Relaxed) as f64 / requests as f64,
+        ALLOCATED.load(Ordering::Relaxed) as f64 / requests as f64,
+    )
+}
+
+#[test]
+fn allocations_per_request() {
+    let port = start();
+
+    let (allocations, bytes) = measure(port, b"GET / HTTP/1.1\r\nHost: test\r\n\r\n", 500);
+    println!("GET: {:.1} allocations, {:.0} bytes", allocations, bytes);
+    assert!(allocations <= 14.0, "{:.1} allocations per GET", allocations);
+
+    let mut upload = b"POST /upload HTTP/1.1\r\nHost: test\r\nContent-Length: 102400\r\n\r\n".to_vec();
+    upload.resize(upload.len() + 102400, b'x');
+    let (allocations, bytes) = measure(port, &upload, 100);
+    println!("100 KiB POST: {:.1} allocations, {:.0} bytes", allocations, bytes);
+    assert!(allocations <= 21.0, "{:.1} allocations per POST", allocations);
+    // the body is read into a pooled buffer, not a new one per request
+    assert!(bytes <= 4096.0, "{:.0} bytes allocated per POST", bytes);
+}
@@ -0,0 +1,698 @@
+//! A server shared by the tests of one binary, and a raw HTTP/1.1 client.
+//!
+//! The poller is chosen once per process, so each backend gets a test binary
+//! of its own calling `backend_tests!`.
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use http_lolo::{
+    CompressionOptions, HttpServer, HttpStatus, PollerKind, Request, ResponseWriter,
+    WebSocketEvent,
+};
+
+pub const BIG_LEN: usize = 4 * 1024 * 1024;
+
+static PORT: OnceLock<u16> = OnceLock::new();
+/// Successful `PUT /doc` requests.
+pub static DOC_UPDATES: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+/// WebSockets opened on `/ws`.
+pub static WEBSOCKET_OPENS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+static SERIAL: Mutex<()> = Mutex::new(());
+
+/// Runs the tests one at a time, they count the descriptors of the process.
+pub fn serial() -> MutexGuard<'static, ()> {
+    SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// A port nothing listens on yet.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Starts the event loop on `kind` with the routes of the suite, once.
+pub fn start(kind: PollerKind, edge_triggered: bool) -> u16 {
+    *PORT.get_or_init(|| {
+        HttpServer::set_poller(kind.create().expect("can create poller")).unwrap();
+        HttpServer::set_edge_triggered(edge_triggered);
+        let server = HttpServer::new();
+        add_routes(&server);
+        server.enable_compression(CompressionOptions::default());
+        server.serve_dir("/static", static_root()).unwrap();
+        let port = free_port();
+        server.listen_on(&format!("127.0.0.1:{}", port));
+        thread::spawn(HttpServer::run_all);
+        port
+    })
+}
+
+/// A file of ten bytes, `0123456789`.
+pub fn small_file() -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("http_lolo_test_{}.txt", std::process::id()));
+    if !path.exists() {
+        std::fs::write(&path, b"0123456789").unwrap();
+    }
+    path
+}
+
+/// A directory served under `/static`, next to a file it must not expose:
+///
+/// ```text
+/// secret.txt
+/// public/hello.txt, style.css, large.txt
+/// public/sub/index.html
+/// public/escape -> ../secret.txt
+/// ```
+pub fn static_root() -> std::path::PathBuf {
+    let base = std::env::temp_dir().join(format!("http_lolo_static_{}", std::process::id()));
+    let root = base.join("public");
+    if !root.exists() {
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(base.join("secret.txt"), b"secret").unwrap();
+        std::fs::write(root.join("hello.txt"), b"hello").unwrap();
+        std::fs::write(root.join("style.css"), b"body {}").unwrap();
+        std::fs::write(root.join("large.txt"), text_body()).unwrap();
+        std::fs::write(root.join("sub/index.html"), b"<p>sub</p>").unwrap();
+        std::os::unix::fs::symlink("../secret.txt", root.join("escape")).unwrap();
+    }
+    root
+}
+
+/// Text worth compressing, above the default minimum size.
+pub fn text_body() -> String {
+    "compress me, ".repeat(200)
+}
+
+pub fn big_body() -> Vec<u8> {
+    (0..BIG_LEN).map(|i| (i % 251) as u8).collect()
+}
+
+fn add_routes(server: &HttpServer) {
+    server.handle_route(
+        "/",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| w.write_string("hello")),
+    );
+    server.handle_route(
+        "/big",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            w.body = big_body();
+            w.write_string("");
+        }),
+    );
+    server.handle_method(
+        "POST",
+        "/echo",
+        Box::new(|r: &mut Request, w: &mut ResponseWriter| {
+            w.body = std::mem::take(&mut r.body);
+            w.write_string("");
+        }),
+    );
+    server.handle_route(
+        "/deferred",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            let deferred = w.defer(Duration::from_secs(5));
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                deferred.complete(|w| w.write_string("later"));
+            });
+        }),
+    );
+    server.handle_route(
+        "/deferred-overwritten",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            w.write_string("early");
+            let deferred = w.defer(Duration::from_secs(5));
+            thread::spawn(move || deferred.complete(|w| w.write_string("later")));
+        }),
+    );
+    server.handle_route(
+        "/deferred-timer",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            let deferred = w.defer(Duration::from_secs(5));
+            HttpServer::set_timeout(Duration::from_millis(50), move || {
+                deferred.complete(|w| w.write_string("from a timer"));
+            });
+        }),
+    );
+    server.handle_route(
+        "/deferred-empty",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            let deferred = w.defer(Duration::from_secs(5));
+            thread::spawn(move || deferred.complete(|_| {}));
+        }),
+    );
+    server.handle_route(
+        "/deferred-expired",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            let deferred = w.defer(Duration::from_millis(100));
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                assert!(!deferred.complete(|w| w.write_string("too late")));
+            });
+        }),
+    );
+    server.handle_route(
+        "/file",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| w.write_file(small_file()).unwrap()),
+    );
+    server.handle_method(
+        "GET",
+        "/doc",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            w.set_etag("v1");
+            w.write_string("doc");
+        }),
+    );
+    server.handle_method(
+        "PUT",
+        "/doc",
+        Box::new(|r: &mut Request, w: &mut ResponseWriter| {
+            if r.preconditions_hold(Some("\"v1\""), None) {
+                DOC_UPDATES.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                w.write_string("updated");
+            } else {
+                w.write_status(HttpStatus::PreconditionFailed);
+            }
+        }),
+    );
+    server.handle_method(
+        "PUT",
+        "/unchecked",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            w.set_etag("v2");
+            w.write_string("changed");
+        }),
+    );
+    server.handle_route(
+        "/own-length",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            w.headers
+                .insert("content-length".to_string(), vec!["5".to_string()]);
+            w.write_string("hello");
+        }),
+    );
+    server.handle_websocket(
+        "/ws",
+        Box::new(|_, event| {
+            if let WebSocketEvent::Open(_) = event {
+                WEBSOCKET_OPENS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }),
+    );
+    server.handle_route(
+        "/events",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            let events = w.event_stream();
+            events.set_keep_alive(Some(Duration::from_millis(20)));
+            events.send_data("hi");
+        }),
+    );
+    server.handle_route(
+        "/busy-events",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            let events = w.event_stream();
+            events.set_keep_alive(Some(Duration::from_millis(100)));
+            thread::spawn(move || {
+                for _ in 0..30 {
+                    thread::sleep(Duration::from_millis(10));
+                    events.send_data("tick");
+                }
+                events.close();
+            });
+        }),
+    );
+    server.handle_route(
+        "/idle-poll",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            HttpServer::subscribe("idle", w.defer(Duration::from_millis(100)));
+        }),
+    );
+    server.handle_route(
+        "/upgrade-echo",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            w.upgrade("echo", |upgraded| {
+                thread::spawn(move || {
+                    let mut stream = upgraded.stream;
+                    stream.set_nonblocking(false).unwrap();
+                    stream.write_all(&upgraded.buffered).unwrap();
+                    let mut buf = [0; 256];
+                    loop {
+                        match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => stream.write_all(&buf[..n]).unwrap(),
+                        }
+                    }
+                });
+            });
+        }),
+    );
+    server.handle_route(
+        "/connection",
+        Box::new(|r: &mut Request, w: &mut ResponseWriter| {
+            w.write_string(&format!(
+                "{} {} {}",
+                r.remote_addr().unwrap(),
+                r.connection_id(),
+                r.sequence_number()
+            ));
+        }),
+    );
+    server.handle_route(
+        "/live",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            HttpServer::subscribe("live", w.event_stream());
+        }),
+    );
+    server.handle_route(
+        "/text",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| {
+            w.headers
+                .insert("Content-Type".to_string(), vec!["text/plain".to_string()]);
+            w.headers
+                .insert("ETag".to_string(), vec!["\"v1\"".to_string()]);
+            w.write_string(&text_body());
+        }),
+    );
+    server.handle_route(
+        "/cors",
+        Box::new(|r: &mut Request, w: &mut ResponseWriter| {
+            if r.method == "OPTIONS" {
+                w.headers.insert(
+                    "Access-Control-Allow-Methods".to_string(),
+                    vec!["GET, PATCH".to_string()],
+                );
+                w.write_status(HttpStatus::NoContent);
+            } else {
+                w.write_string("cors");
+            }
+        }),
+    );
+    server.handle_route(
+        "/status",
+        Box::new(|_: &mut Request, w: &mut ResponseWriter| w.write_status(HttpStatus::Accepted)),
+    );
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    // names in lower case, in the order received
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.header_values(name).first().copied()
+    }
+
+    pub fn header_values(&self, name: &str) -> Vec<&str> {
+        self.headers
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    /// Reads a response up to the end of the connection.
+    pub fn read(stream: &mut TcpStream) -> Response {
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut raw = Vec::new();
+        stream.read_to_end(&mut raw).expect("response within the timeout");
+        Response::parse(&raw)
+    }
+
+    pub fn parse(raw: &[u8]) -> Response {
+        let end = raw
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .unwrap_or_else(|| panic!("incomplete response: {:?}", String::from_utf8_lossy(raw)));
+        let head = std::str::from_utf8(&raw[..end]).unwrap();
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.to_ascii_lowercase(), value.trim().to_string()));
+        }
+        Response {
+            status,
+            headers,
+            body: raw[end + 4..].to_vec(),
+        }
+    }
+}
+
+/// The client preface of a prior-knowledge HTTP/2 connection, with empty
+/// settings.
+pub const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\0\0\0\x04\0\0\0\0\0";
+
+pub fn write_frame(stream: &mut TcpStream, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    let len = (payload.len() as u32).to_be_bytes();
+    let mut frame = vec![len[1], len[2], len[3], kind, flags];
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).unwrap();
+}
+
+/// Reads one HTTP/2 frame: its type, stream and payload.
+pub fn read_frame(stream: &mut TcpStream) -> (u8, u32, Vec<u8>) {
+    let mut head = [0u8; 9];
+    stream.read_exact(&mut head).unwrap();
+    let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).unwrap();
+    let id = u32::from_be_bytes([head[5], head[6], head[7], head[8]]) & 0x7fff_ffff;
+    (head[3], id, payload)
+}
+
+/// Reads HTTP/2 frames until the HEADERS of `stream_id`.
+pub fn read_headers_frame(stream: &mut TcpStream, stream_id: u32) -> Vec<u8> {
+    loop {
+        match read_frame(stream) {
+            (0x1, id, payload) if id == stream_id => return payload,
+            _ => {}
+        }
+    }
+}
+
+pub fn connect(port: u16) -> TcpStream {
+    TcpStream::connect(("127.0.0.1", port)).unwrap()
+}
+
+/// Sends `request` in one write and reads the response.
+pub fn send(port: u16, request: &[u8]) -> Response {
+    let mut stream = connect(port);
+    stream.write_all(request).unwrap();
+    Response::read(&mut stream)
+}
+
+pub fn get(port: u16, path: &str) -> Response {
+    send(port, format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).as_bytes())
+}
+
+/// The descriptors open in this process, the server's included.
+pub fn open_fds() -> usize {
+    std::fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+/// The descriptor count once the previous tests' connections are closed.
+pub fn settled_fds() -> usize {
+    let mut last = open_fds();
+    loop {
+        thread::sleep(Duration::from_millis(50));
+        let open = open_fds();
+        if open == last {
+            return open;
+        }
+        last = open;
+    }
+}
+
+/// Waits for the descriptor count to drop back to `expected`, the server
+/// closes its side once it notices the client is gone.
+pub fn wait_for_fds(expected: usize) -> usize {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let open = open_fds();
+        if open <= expected || Instant::now() > deadline {
+            return open;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Closes `stream` with a reset instead of a FIN.
+pub fn reset(stream: TcpStream) {
+    use std::os::unix::io::AsRawFd;
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let res = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(res, 0);
+}
+
+/// The suite run against every poller backend.
+#[macro_export]
+macro_rules! backend_tests {
+    ($kind:expr, $edge_triggered:expr) => {
+        use std::io::Write;
+        use std::thread;
+        use std::time::Duration;
+
+        use common::*;
+
+        fn port() -> u16 {
+            start($kind, $edge_triggered)
+        }
+
+        #[test]
+        fn get_route() {
+            let _serial = serial();
+            let response = get(port(), "/");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("Content-Length"), Some("5"));
+            assert_eq!(response.body, b"hello");
+        }
+
+        #[test]
+        fn unknown_route_is_not_found() {
+            let _serial = serial();
+            assert_eq!(get(port(), "/missing").status, 404);
+        }
+
+        #[test]
+        fn status_written_by_the_server() {
+            let _serial = serial();
+            let response = get(port(), "/status");
+            assert_eq!(response.status, 202);
+            assert_eq!(response.body, b"Accepted");
+        }
+
+        #[test]
+        fn keep_alive_request_gets_a_closing_response() {
+            let _serial = serial();
+            let response = send(
+                port(),
+                b"GET / HTTP/1.1\r\nHost: test\r\nConnection: keep-alive\r\n\r\n",
+            );
+            // `send` reads until the server closes the connection
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("Connection"), Some("close"));
+            assert_eq!(response.body, b"hello");
+        }
+
+        #[test]
+        fn large_response() {
+            let _serial = serial();
+            let response = get(port(), "/big");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("Content-Length"), Some(BIG_LEN.to_string().as_str()));
+            assert!(response.body == big_body(), "body differs");
+        }
+
+        #[test]
+        fn large_upload() {
+            let _serial = serial();
+            let body = big_body();
+            let mut stream = connect(port());
+            let writer = {
+                let mut stream = stream.try_clone().unwrap();
+                let body = body.clone();
+                thread::spawn(move || {
+                    let head = format!(
+                        "POST /echo HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n",
+                        body.len()
+                    );
+                    stream.write_all(head.as_bytes()).unwrap();
+                    stream.write_all(&body).unwrap();
+                })
+            };
+            let response = Response::read(&mut stream);
+            writer.join().unwrap();
+            assert_eq!(response.status, 200);
+            assert!(response.body == body, "body differs");
+        }
+
+        #[test]
+        fn request_split_across_reads() {
+            let _serial = serial();
+            let mut stream = connect(port());
+            for part in [
+                &b"POST /ec"[..],
+                b"ho HTTP/1.1\r\nHost: test\r\nContent-Le",
+                b"ngth: 10\r\n\r\n",
+                b"01234",
+                b"56789",
+            ] {
+                stream.write_all(part).unwrap();
+                stream.flush().unwrap();
+                thread::sleep(Duration::from_millis(30));
+            }
+            let response = Response::read(&mut stream);
+            assert_eq!(response.status, 200);
+            assert_eq!(response.body, b"0123456789");
+        }
+
+        #[test]
+        fn deferred_response() {
+            let _serial = serial();
+            let response = get(port(), "/deferred");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.body, b"later");
+        }
+
+        #[test]
+        fn deferred_response_is_what_the_completion_wrote() {
+            let _serial = serial();
+            let response = get(port(), "/deferred-overwritten");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.body, b"later");
+        }
+
+        #[test]
+        fn deferred_response_written_empty_is_an_error() {
+            let _serial = serial();
+            assert_eq!(get(port(), "/deferred-empty").status, 500);
+        }
+
+        #[test]
+        fn expired_deferred_response() {
+            let _serial = serial();
+            assert_eq!(get(port(), "/deferred-expired").status, 503);
+        }
+
+        #[test]
+        fn timer_completes_deferred_response() {
+            let _serial = serial();
+            let response = get(port(), "/deferred-timer");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.body, b"from a timer");
+        }
+
+        #[test]
+        fn cancelled_interval_stops_firing() {
+            use std::sync::atomic::{AtomicUsize, Ordering};
+            use std::sync::Arc;
+
+            use http_lolo::HttpServer;
+
+            let _serial = serial();
+            port();
+            let fired = Arc::new(AtomicUsize::new(0));
+            let counter = fired.clone();
+            let timer_id = HttpServer::set_interval(Duration::from_millis(10), move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            });
+            let deadline = std::time::Instant::now() + Duration::from_secs(5);
+            while fired.load(Ordering::SeqCst) < 3 {
+                assert!(std::time::Instant::now() < deadline, "interval never fired");
+                thread::sleep(Duration::from_millis(5));
+            }
+            assert!(HttpServer::cancel(timer_id));
+            // a callback already running when cancelled may still finish
+            thread::sleep(Duration::from_millis(20));
+            let after_cancel = fired.load(Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(100));
+            assert_eq!(fired.load(Ordering::SeqCst), after_cancel);
+            assert!(!HttpServer::cancel(timer_id));
+        }
+
+        #[test]
+        fn watched_pipe_calls_back_when_written() {
+            use std::io::Read;
+            use std::os::unix::io::AsRawFd;
+            use std::sync::{mpsc, Arc};
+
+            use http_lolo::{HttpServer, Interest};
+
+            let _serial = serial();
+            port();
+            let (reader, mut writer) = std::io::pipe().unwrap();
+            // still open once unwatched, the callback owning it is dropped
+            let reader = Arc::new(reader);
+            let fd = reader.as_raw_fd();
+            let watched = reader.clone();
+            let (sender, received) = mpsc::channel();
+            let watch_id = HttpServer::watch_fd(fd, Interest::Read, move |event| {
+                assert_eq!(event.fd, fd);
+                assert!(event.readable);
+                let mut buf = [0; 16];
+                let n = (&*watched).read(&mut buf).unwrap();
+                sender.send(buf[..n].to_vec()).unwrap();
+            })
+            .unwrap();
+
+            writer.write_all(b"ping").unwrap();
+            let bytes = received.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(bytes, b"ping");
+            writer.write_all(b"pong").unwrap();
+            let bytes = received.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(bytes, b"pong");
+
+            HttpServer::unwatch_fd(watch_id).unwrap();
+            writer.write_all(b"unwatched").unwrap();
+            assert!(received.recv_timeout(Duration::from_millis(100)).is_err());
+        }
+
+        #[test]
+        fn concurrent_requests() {
+            let _serial = serial();
+            let port = port();
+            let clients: Vec<_> = (0..32)
+                .map(|_| thread::spawn(move || get(port, "/").status))
+                .collect();
+            for client in clients {
+                assert_eq!(client.join().unwrap(), 200);
+            }
+        }
+
+        #[test]
+        fn closed_connections_are_released() {
+            let _serial = serial();
+            let port = port();
+            // the loop registers its own descriptors on the first request
+            get(port, "/");
+            let before = settled_fds();
+            for i in 0..20 {
+                let mut stream = connect(port);
+                stream.write_all(b"GET / HTTP/1.1\r\nHost: te").unwrap();
+                thread::sleep(Duration::from_millis(5));
+                if i % 2 == 0 {
+                    reset(stream);
+                } else {
+                    drop(stream);
+                }
+            }
+            // gone while the server waits to write the rest of a response
+            for _ in 0..10 {
+                let mut stream = connect(port);
+                stream.write_all(b"GET /big HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+                let mut first = [0u8; 1024];
+                std::io::Read::read_exact(&mut stream, &mut first).unwrap();
+                reset(stream);
+            }
+            let after = wait_for_fds(before);
+            assert!(after <= before, "{} descriptors leaked", after - before);
+        }
+    };
+}
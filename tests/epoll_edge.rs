@@ -0,0 +1,26 @@
+mod common;
+
+// epoll with edge-triggered listeners and connections, read until WouldBlock
+backend_tests!(http_lolo::PollerKind::Epoll, true);
+
+#[test]
+fn request_arriving_at_once_is_read_until_would_block() {
+    let _serial = serial();
+    // many read chunks behind a single edge, none comes for the rest
+    let body = vec![b'x'; 256 * 1024];
+    let mut request = format!(
+        "POST /echo HTTP/1.1\r\nHost: test\r\nContent-Length: {}\r\n\r\n",
+        body.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&body);
+    let mut stream = connect(port());
+    let writer = {
+        let mut stream = stream.try_clone().unwrap();
+        thread::spawn(move || stream.write_all(&request).unwrap())
+    };
+    let response = Response::read(&mut stream);
+    writer.join().unwrap();
+    assert_eq!(response.status, 200);
+    assert!(response.body == body, "body differs");
+}
@@ -0,0 +1,497 @@
+//! Protocol behaviour independent of the poller backend.
+
+mod common;
+
+use std::io::{Read, Write};
+
+use common::*;
+use http_lolo::PollerKind;
+
+fn port() -> u16 {
+    start(PollerKind::Epoll, false)
+}
+
+#[test]
+fn content_length_set_by_the_handler_is_not_repeated() {
+    let response = get(port(), "/own-length");
+    assert_eq!(response.header_values("Content-Length"), ["5"]);
+    assert_eq!(response.body, b"hello");
+}
+
+fn get_range(range: &str) -> Response {
+    send(
+        port(),
+        format!("GET /file HTTP/1.1\r\nHost: test\r\nRange: {}\r\n\r\n", range).as_bytes(),
+    )
+}
+
+#[test]
+fn single_range() {
+    let response = get_range("bytes=2-4");
+    assert_eq!(response.status, 206);
+    assert_eq!(response.header("Content-Range"), Some("bytes 2-4/10"));
+    assert_eq!(response.body, b"234");
+}
+
+#[test]
+fn unsatisfiable_range() {
+    let response = get_range("bytes=20-");
+    assert_eq!(response.status, 416);
+    assert_eq!(response.header("Content-Range"), Some("bytes */10"));
+}
+
+#[test]
+fn range_without_specs_is_ignored() {
+    for range in ["bytes=", "bytes= , ", "bytes=5-2", "items=0-1"] {
+        let response = get_range(range);
+        assert_eq!(response.status, 200, "{}", range);
+        assert_eq!(response.body, b"0123456789", "{}", range);
+    }
+}
+
+const PAST: &str = "Mon, 01 Jan 2001 00:00:00 GMT";
+const FUTURE: &str = "Fri, 01 Jan 2100 00:00:00 GMT";
+
+fn request(method: &str, path: &str, header: &str) -> Response {
+    send(
+        port(),
+        format!(
+            "{} {} HTTP/1.1\r\nHost: test\r\nContent-Length: 0\r\n{}\r\n\r\n",
+            method, path, header
+        )
+        .as_bytes(),
+    )
+}
+
+#[test]
+fn matching_if_none_match_is_not_modified() {
+    let response = request("GET", "/doc", "If-None-Match: \"v0\", W/\"v1\"");
+    assert_eq!(response.status, 304);
+    assert_eq!(response.header("ETag"), Some("\"v1\""));
+    assert!(response.body.is_empty());
+
+    assert_eq!(request("GET", "/doc", "If-None-Match: \"v0\"").status, 200);
+}
+
+#[test]
+fn if_modified_since() {
+    let response = request("GET", "/file", &format!("If-Modified-Since: {}", FUTURE));
+    assert_eq!(response.status, 304);
+    assert!(response.header("Last-Modified").is_some());
+
+    let response = request("GET", "/file", &format!("If-Modified-Since: {}", PAST));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"0123456789");
+}
+
+#[test]
+fn failed_preconditions_of_a_get() {
+    assert_eq!(request("GET", "/doc", "If-Match: \"v0\"").status, 412);
+    assert_eq!(request("GET", "/doc", "If-Match: W/\"v1\"").status, 412);
+    assert_eq!(request("GET", "/doc", "If-Match: \"v1\"").status, 200);
+    let since = format!("If-Unmodified-Since: {}", PAST);
+    assert_eq!(request("GET", "/file", &since).status, 412);
+}
+
+#[test]
+fn handlers_of_unsafe_methods_check_preconditions_first() {
+    let updates = DOC_UPDATES.load(std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(request("PUT", "/doc", "If-Match: \"v0\"").status, 412);
+    assert_eq!(request("PUT", "/doc", "If-None-Match: *").status, 412);
+    assert_eq!(DOC_UPDATES.load(std::sync::atomic::Ordering::SeqCst), updates);
+
+    let response = request("PUT", "/doc", "If-Match: \"v1\"");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"updated");
+    assert!(DOC_UPDATES.load(std::sync::atomic::Ordering::SeqCst) > updates);
+}
+
+#[test]
+fn unsafe_methods_are_not_failed_after_the_handler_ran() {
+    // the handler made its change, a 412 would claim it didn't
+    let response = request("PUT", "/unchecked", "If-Match: \"v1\"");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"changed");
+}
+
+#[test]
+fn options_reaches_a_route_handler() {
+    let response = request("OPTIONS", "/cors", "Access-Control-Request-Method: PATCH");
+    assert_eq!(response.status, 204);
+    assert_eq!(response.header("Access-Control-Allow-Methods"), Some("GET, PATCH"));
+    assert_eq!(response.header("Allow"), None);
+}
+
+#[test]
+fn options_of_method_routes_is_answered_by_the_server() {
+    let response = request("OPTIONS", "/doc", "");
+    assert_eq!(response.status, 204);
+    assert_eq!(response.header("Allow"), Some("GET, PUT, HEAD, OPTIONS"));
+
+    let response = request("OPTIONS", "*", "");
+    let allow = response.header("Allow").unwrap();
+    assert!(allow.contains("PUT"), "{}", allow);
+    // the methods of `handle_route` handlers are unknown
+    assert!(!allow.contains("PATCH"), "{}", allow);
+}
+
+#[test]
+fn websocket_routes_are_not_upgraded_on_http2() {
+    let opens = WEBSOCKET_OPENS.load(std::sync::atomic::Ordering::SeqCst);
+    let mut stream = connect(port());
+    stream
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(HTTP2_PREFACE).unwrap();
+    // GET http://test/ws, literal fields without indexing
+    write_frame(&mut stream, 0x1, 0x5, 1, b"\x82\x86\x04\x03/ws\x01\x04test");
+
+    let headers = read_headers_frame(&mut stream, 1);
+    assert!(headers.windows(3).any(|w| w == b"505"), "{:?}", headers);
+    assert_eq!(WEBSOCKET_OPENS.load(std::sync::atomic::Ordering::SeqCst), opens);
+}
+
+#[test]
+fn event_streams_keep_sending_keep_alive_comments() {
+    let mut stream = connect(port());
+    stream
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(b"GET /events HTTP/1.1\r\nHost: test\r\n\r\n")
+        .unwrap();
+    let mut received = Vec::new();
+    let mut buf = [0u8; 256];
+    while received.windows(3).filter(|w| w == b":\n\n").count() < 3 {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "{}", String::from_utf8_lossy(&received));
+        received.extend_from_slice(&buf[..n]);
+    }
+    assert!(received.windows(10).any(|w| w == b"data: hi\n\n"));
+}
+
+#[test]
+fn keep_alive_comments_wait_for_idle_streams() {
+    let response = get(port(), "/busy-events");
+    assert_eq!(response.status, 200);
+    let ticks = response.body.windows(12).filter(|w| w == b"data: tick\n\n").count();
+    assert_eq!(ticks, 30);
+    assert!(
+        !response.body.windows(3).any(|w| w == b":\n\n"),
+        "{}",
+        String::from_utf8_lossy(&response.body)
+    );
+}
+
+#[test]
+fn expired_long_polls_are_unsubscribed() {
+    assert_eq!(get(port(), "/idle-poll").status, 503);
+    assert_eq!(http_lolo::HttpServer::publish("idle", b"late"), 0);
+}
+
+#[test]
+fn subscribers_are_dropped_when_their_connection_closes() {
+    let mut stream = connect(port());
+    stream
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(b"GET /live HTTP/1.1\r\nHost: test\r\n\r\n").unwrap();
+    let mut buf = [0u8; 512];
+    assert!(stream.read(&mut buf).unwrap() > 0);
+    assert_eq!(http_lolo::HttpServer::publish("live", b"one"), 1);
+
+    drop(stream);
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert_eq!(http_lolo::HttpServer::publish("live", b"two"), 0);
+}
+
+#[test]
+fn malformed_requests_are_answered_and_closed() {
+    for raw in [
+        &b"NOT AN HTTP REQUEST\r\n\r\n"[..],
+        b"GET / HTTP/1.1\r\nHost: test\r\nno colon here\r\n\r\n",
+    ] {
+        let response = send(port(), raw);
+        assert_eq!(response.status, 400, "{}", String::from_utf8_lossy(raw));
+        assert_eq!(response.header("Connection"), Some("close"));
+    }
+}
+
+#[test]
+fn too_many_headers_are_a_bad_request() {
+    let headers: String = (0..100).map(|i| format!("X-Header-{}: {}\r\n", i, i)).collect();
+    let response = send(
+        port(),
+        format!("GET / HTTP/1.1\r\nHost: test\r\n{}\r\n", headers).as_bytes(),
+    );
+    assert_eq!(response.status, 400);
+}
+
+#[test]
+fn endless_request_head_is_refused() {
+    let mut stream = connect(port());
+    let filler = format!("X-Filler: {}\r\n", "a".repeat(1012));
+    let mut head = b"GET / HTTP/1.1\r\n".to_vec();
+    while head.len() + filler.len() <= 16 * 1024 {
+        head.extend_from_slice(filler.as_bytes());
+    }
+    stream.write_all(&head).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    // past the limit, the head still never ends
+    stream.write_all(filler.as_bytes()).unwrap();
+    let response = Response::read(&mut stream);
+    assert_eq!(response.status, 431);
+    assert_eq!(response.header("Connection"), Some("close"));
+}
+
+#[test]
+fn static_files_have_the_type_of_their_extension() {
+    let response = get(port(), "/static/hello.txt");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some("text/plain; charset=utf-8"));
+    assert_eq!(response.body, b"hello");
+    let response = get(port(), "/static/style.css");
+    assert_eq!(response.header("Content-Type"), Some("text/css; charset=utf-8"));
+}
+
+#[test]
+fn static_paths_cannot_leave_the_root() {
+    for path in [
+        "/static/../secret.txt",
+        "/static/%2e%2e/secret.txt",
+        "/static/sub/..%2f..%2fsecret.txt",
+        "/static/escape",
+    ] {
+        let response = get(port(), path);
+        assert!([403, 404].contains(&response.status), "{}: {}", path, response.status);
+        assert_ne!(response.body, b"secret", "{}", path);
+    }
+}
+
+#[test]
+fn static_directories_are_redirected_to_a_trailing_slash() {
+    let response = get(port(), "/static/sub?x=1");
+    assert_eq!(response.status, 301);
+    assert_eq!(response.header("Location"), Some("/static/sub/?x=1"));
+
+    let response = get(port(), "/static/sub/");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Type"), Some("text/html; charset=utf-8"));
+    assert_eq!(response.body, b"<p>sub</p>");
+}
+
+fn get_encoded(path: &str, accept_encoding: &str) -> Response {
+    request("GET", path, &format!("Accept-Encoding: {}", accept_encoding))
+}
+
+fn gunzip(body: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    flate2::read::GzDecoder::new(body)
+        .read_to_end(&mut decoded)
+        .unwrap();
+    decoded
+}
+
+#[test]
+fn responses_are_compressed_with_the_preferred_coding() {
+    let response = get_encoded("/text", "gzip;q=0.8, deflate;q=0.5");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Encoding"), Some("gzip"));
+    assert_eq!(response.header("Vary"), Some("Accept-Encoding"));
+    assert_eq!(response.header("ETag"), Some("\"v1-gzip\""));
+    assert_eq!(gunzip(&response.body), text_body().as_bytes());
+
+    let response = get_encoded("/text", "gzip;q=0.5, deflate");
+    assert_eq!(response.header("Content-Encoding"), Some("deflate"));
+    assert_eq!(response.header("ETag"), Some("\"v1-deflate\""));
+}
+
+#[test]
+fn codings_weighted_zero_are_not_used() {
+    for accept_encoding in ["gzip;q=0", "gzip;q=0, deflate;q=0", "*;q=0", "identity"] {
+        let response = get_encoded("/text", accept_encoding);
+        assert_eq!(response.header("Content-Encoding"), None, "{}", accept_encoding);
+        // the representation still depends on the header
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding"), "{}", accept_encoding);
+        assert_eq!(response.header("ETag"), Some("\"v1\""), "{}", accept_encoding);
+        assert_eq!(response.body, text_body().as_bytes(), "{}", accept_encoding);
+    }
+}
+
+#[test]
+fn file_bodies_are_not_compressed() {
+    let response = get_encoded("/static/large.txt", "gzip");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.header("Content-Encoding"), None);
+    assert_eq!(response.body, text_body().as_bytes());
+
+    let response = request("GET", "/static/large.txt", "Accept-Encoding: gzip\r\nRange: bytes=0-7");
+    assert_eq!(response.status, 206);
+    assert_eq!(response.header("Content-Encoding"), None);
+    assert_eq!(response.body, b"compress");
+}
+
+fn post_encoded(coding: &str, body: &[u8]) -> Response {
+    let mut raw = format!(
+        "POST /echo HTTP/1.1\r\nHost: test\r\nContent-Encoding: {}\r\nContent-Length: {}\r\n\r\n",
+        coding,
+        body.len()
+    )
+    .into_bytes();
+    raw.extend_from_slice(body);
+    send(port(), &raw)
+}
+
+fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn compressed_request_bodies_reach_handlers_decoded() {
+    let response = post_encoded("gzip", &gzip(b"hello lolo"));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"hello lolo");
+}
+
+#[test]
+fn request_bodies_inflating_past_the_limit_are_too_large() {
+    // 16 MiB decoded when no body limit is set
+    let bomb = gzip(&vec![0; 17 * 1024 * 1024]);
+    assert!(bomb.len() < 100 * 1024);
+    assert_eq!(post_encoded("gzip", &bomb).status, 413);
+}
+
+#[test]
+fn unknown_request_codings_are_unsupported() {
+    let response = post_encoded("foo", b"hello");
+    assert_eq!(response.status, 415);
+    assert!(response.header("Accept-Encoding").unwrap().contains("gzip"));
+}
+
+#[test]
+fn truncated_compressed_request_bodies_are_bad_requests() {
+    let body = gzip(text_body().as_bytes());
+    assert_eq!(post_encoded("gzip", &body[..body.len() / 2]).status, 400);
+}
+
+/// Reads what the server sends until it ends with `end`.
+fn read_until(stream: &mut std::net::TcpStream, end: &[u8]) -> Vec<u8> {
+    let mut received = Vec::new();
+    let mut buf = [0u8; 256];
+    while !received.ends_with(end) {
+        let n = stream.read(&mut buf).unwrap();
+        assert!(n > 0, "{}", String::from_utf8_lossy(&received));
+        received.extend_from_slice(&buf[..n]);
+    }
+    received
+}
+
+#[test]
+fn continue_is_sent_before_the_body_is_read() {
+    let mut stream = connect(port());
+    stream
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(concat!(
+            "POST /echo HTTP/1.1\r\nHost: test\r\n",
+            "Expect: 100-continue\r\nContent-Length: 4\r\n\r\n"
+        ).as_bytes())
+        .unwrap();
+    let interim = read_until(&mut stream, b"\r\n\r\n");
+    assert_eq!(interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+
+    stream.write_all(b"lolo").unwrap();
+    let response = Response::read(&mut stream);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"lolo");
+}
+
+#[test]
+fn unsupported_expectations_fail() {
+    let response = send(
+        port(),
+        b"POST /echo HTTP/1.1\r\nHost: test\r\nExpect: teapot\r\nContent-Length: 4\r\n\r\nlolo",
+    );
+    assert_eq!(response.status, 417);
+}
+
+#[test]
+fn upgraded_connections_are_handed_over_with_pipelined_bytes() {
+    let mut stream = connect(port());
+    stream
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(concat!(
+            "GET /upgrade-echo HTTP/1.1\r\nHost: test\r\n",
+            "Connection: Upgrade\r\nUpgrade: echo\r\n\r\npipelined"
+        ).as_bytes())
+        .unwrap();
+    let received = read_until(&mut stream, b"pipelined");
+    let response = Response::parse(&received[..received.len() - b"pipelined".len()]);
+    assert_eq!(response.status, 101);
+    assert_eq!(response.header("Upgrade"), Some("echo"));
+    assert_eq!(response.header("Connection"), Some("Upgrade"));
+    assert!(response.body.is_empty());
+
+    stream.write_all(b"raw bytes").unwrap();
+    assert_eq!(read_until(&mut stream, b"raw bytes"), b"raw bytes");
+}
+
+/// The peer address, connection id and sequence number of a response of
+/// `/connection`.
+fn connection_info(body: &[u8]) -> (String, u64, u64) {
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    let parts: Vec<&str> = body.split(' ').collect();
+    (parts[0].to_string(), parts[1].parse().unwrap(), parts[2].parse().unwrap())
+}
+
+#[test]
+fn requests_know_their_connection() {
+    let mut first = connect(port());
+    let mut second = connect(port());
+    let first_addr = first.local_addr().unwrap().to_string();
+    let second_addr = second.local_addr().unwrap().to_string();
+    for stream in [&mut first, &mut second] {
+        stream
+            .write_all(b"GET /connection HTTP/1.1\r\nHost: test\r\n\r\n")
+            .unwrap();
+    }
+    let (remote, first_id, sequence_number) = connection_info(&Response::read(&mut first).body);
+    assert_eq!(remote, first_addr);
+    // HTTP/1 connections carry a single request
+    assert_eq!(sequence_number, 1);
+    let (remote, second_id, _) = connection_info(&Response::read(&mut second).body);
+    assert_eq!(remote, second_addr);
+    assert_ne!(first_id, second_id);
+}
+
+#[test]
+fn http2_streams_are_numbered_on_their_connection() {
+    let mut stream = connect(port());
+    stream
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(HTTP2_PREFACE).unwrap();
+    // GET http://test/connection
+    let block = b"\x82\x86\x04\x0b/connection\x01\x04test";
+    write_frame(&mut stream, 0x1, 0x5, 1, block);
+    write_frame(&mut stream, 0x1, 0x5, 3, block);
+
+    let mut bodies = [Vec::new(), Vec::new()];
+    while bodies.iter().any(|body| body.is_empty()) {
+        if let (0x0, id @ (1 | 3), data) = read_frame(&mut stream) {
+            bodies[id as usize / 2].extend_from_slice(&data);
+        }
+    }
+    let local_addr = stream.local_addr().unwrap().to_string();
+    let (remote, first_id, first_number) = connection_info(&bodies[0]);
+    assert_eq!(remote, local_addr);
+    let (remote, second_id, second_number) = connection_info(&bodies[1]);
+    assert_eq!(remote, local_addr);
+    assert_eq!(first_id, second_id);
+    assert_eq!((first_number, second_number), (1, 2));
+}
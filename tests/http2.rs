@@ -0,0 +1,127 @@
+//! HTTP/2 limits and flow control, over prior-knowledge connections.
+
+mod common;
+
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::*;
+use http_lolo::PollerKind;
+
+// fields of `POST http://test/echo`, literal without indexing
+const POST_ECHO: &[u8] = b"\x83\x86\x04\x05/echo\x01\x04test";
+
+fn port() -> u16 {
+    start(PollerKind::Epoll, false)
+}
+
+fn has_status(headers: &[u8], status: &[u8]) -> bool {
+    // three digit statuses are not shorter Huffman-coded, they are sent as is
+    headers.windows(3).any(|w| w == status)
+}
+
+#[test]
+fn declared_body_over_the_default_cap_is_refused() {
+    let mut stream = connect(port());
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(HTTP2_PREFACE).unwrap();
+    let mut block = POST_ECHO.to_vec();
+    // content-length: 17000000
+    block.extend_from_slice(b"\x0f\x0d\x0817000000");
+    write_frame(&mut stream, 0x1, 0x4, 1, &block);
+
+    let headers = read_headers_frame(&mut stream, 1);
+    assert!(has_status(&headers, b"413"), "{:?}", headers);
+}
+
+#[test]
+fn streamed_body_over_the_default_cap_is_refused() {
+    let mut stream = connect(port());
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(HTTP2_PREFACE).unwrap();
+    write_frame(&mut stream, 0x1, 0x4, 1, POST_ECHO);
+
+    // the send windows of the connection and of stream 1
+    let windows = Arc::new([AtomicI64::new(65_535), AtomicI64::new(65_535)]);
+    let answered = Arc::new(AtomicBool::new(false));
+    let reader = {
+        let mut stream = stream.try_clone().unwrap();
+        let (windows, answered) = (windows.clone(), answered.clone());
+        thread::spawn(move || loop {
+            match read_frame(&mut stream) {
+                (0x1, 1, headers) => {
+                    answered.store(true, Ordering::SeqCst);
+                    return headers;
+                }
+                (0x8, id, increment) if id <= 1 => {
+                    let increment = u32::from_be_bytes(increment[..4].try_into().unwrap());
+                    windows[id as usize].fetch_add(increment as i64, Ordering::SeqCst);
+                }
+                _ => {}
+            }
+        })
+    };
+
+    let chunk = vec![b'x'; 16 * 1024];
+    let mut sent = 0;
+    let mut stalled_since = Instant::now();
+    while !answered.load(Ordering::SeqCst) {
+        assert!(sent <= 32 * 1024 * 1024, "the body was never refused");
+        let room = windows[0].load(Ordering::SeqCst).min(windows[1].load(Ordering::SeqCst));
+        if room < chunk.len() as i64 {
+            // once refused, the stream gets no window back until answered
+            assert!(stalled_since.elapsed() < Duration::from_secs(5), "stalled at {}", sent);
+            thread::sleep(Duration::from_millis(1));
+            continue;
+        }
+        stalled_since = Instant::now();
+        for window in windows.iter() {
+            window.fetch_sub(chunk.len() as i64, Ordering::SeqCst);
+        }
+        write_frame(&mut stream, 0x0, 0x0, 1, &chunk);
+        sent += chunk.len();
+    }
+
+    let headers = reader.join().unwrap();
+    assert!(has_status(&headers, b"413"), "{:?}", headers);
+    assert!(sent > 16 * 1024 * 1024, "{}", sent);
+}
+
+#[test]
+fn header_list_size_is_advertised() {
+    let mut stream = connect(port());
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(HTTP2_PREFACE).unwrap();
+    let (kind, _, settings) = read_frame(&mut stream);
+    assert_eq!(kind, 0x4);
+    // SETTINGS_MAX_HEADER_LIST_SIZE: 65536
+    assert!(settings.chunks(6).any(|setting| setting == b"\x00\x06\x00\x01\x00\x00"));
+}
+
+#[test]
+fn header_lists_expanding_past_the_limit_are_refused() {
+    let mut stream = connect(port());
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(HTTP2_PREFACE).unwrap();
+    // GET http://test/, then x-big with a 4000 bytes value added to the table
+    let mut block = b"\x82\x86\x84\x01\x04test\x40\x05x-big\x7f\xa1\x1e".to_vec();
+    block.extend_from_slice(&[b'x'; 4000]);
+    // referenced again and again, each time 4037 bytes of the header list
+    block.extend_from_slice(&[0xbe; 20]);
+    write_frame(&mut stream, 0x1, 0x5, 1, &block);
+
+    loop {
+        match read_frame(&mut stream) {
+            (0x7, 0, payload) => {
+                // COMPRESSION_ERROR
+                assert_eq!(&payload[4..8], &[0, 0, 0, 0x9]);
+                break;
+            }
+            (0x1, _, headers) => panic!("answered: {:?}", headers),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,5 @@
+#![cfg(feature = "io-uring")]
+
+mod common;
+
+backend_tests!(http_lolo::PollerKind::IoUring, false);
@@ -0,0 +1,3 @@
+mod common;
+
+backend_tests!(http_lolo::PollerKind::Poll, false);
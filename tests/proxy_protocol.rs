@@ -0,0 +1,167 @@
+//! PROXY protocol headers in front of requests.
+
+mod common;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use http_lolo::{HttpServer, ProxyProtocol, Request, ResponseWriter};
+
+use common::*;
+
+const REQUEST: &[u8] = b"GET /addr HTTP/1.1\r\nHost: test\r\n\r\n";
+
+/// The ports of a server with an optional header and of one requiring it.
+fn ports() -> (u16, u16) {
+    static PORTS: OnceLock<(u16, u16)> = OnceLock::new();
+    *PORTS.get_or_init(|| {
+        let mut ports = Vec::new();
+        for mode in [ProxyProtocol::Optional, ProxyProtocol::Required] {
+            let server = HttpServer::new();
+            server.set_proxy_protocol(mode);
+            server.handle_route(
+                "/addr",
+                Box::new(|r: &mut Request, w: &mut ResponseWriter| {
+                    let source = r.proxy_addresses().map(|addresses| addresses.source);
+                    w.write_string(&format!(
+                        "{} {}",
+                        source.map_or("-".to_string(), |source| source.to_string()),
+                        r.remote_addr().unwrap()
+                    ));
+                }),
+            );
+            let port = free_port();
+            server.listen_on(&format!("127.0.0.1:{}", port));
+            ports.push(port);
+        }
+        thread::spawn(HttpServer::run_all);
+        (ports[0], ports[1])
+    })
+}
+
+/// Sends `parts` one after the other, returns the source the server got
+/// from the header and the address of the client.
+fn addresses(port: u16, parts: &[&[u8]]) -> (String, String) {
+    let mut stream = connect(port);
+    for part in parts {
+        stream.write_all(part).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    let local_addr = stream.local_addr().unwrap().to_string();
+    let response = Response::read(&mut stream);
+    assert_eq!(response.status, 200);
+    let body = String::from_utf8(response.body).unwrap();
+    let (source, remote) = body.split_once(' ').unwrap();
+    // the peer of the connection is never replaced
+    assert_eq!(remote, local_addr);
+    (source.to_string(), remote.to_string())
+}
+
+/// Asserts the server closes the connection without answering.
+fn closed_without_response(mut stream: TcpStream) {
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut received = Vec::new();
+    match stream.read_to_end(&mut received) {
+        Ok(_) => assert!(received.is_empty(), "{}", String::from_utf8_lossy(&received)),
+        // what the server didn't read resets the connection
+        Err(e) => assert_eq!(e.kind(), ErrorKind::ConnectionReset),
+    }
+}
+
+fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+    header.push(0x20 | command);
+    header.push(family);
+    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+    header.extend_from_slice(addresses);
+    header
+}
+
+#[test]
+fn v1_header() {
+    let (optional, required) = ports();
+    for port in [optional, required] {
+        let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+        assert_eq!(addresses(port, &[header, REQUEST]).0, "192.0.2.1:56324");
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(addresses(port, &[header, REQUEST]).0, "[2001:db8::1]:56324");
+    }
+}
+
+#[test]
+fn v2_header_with_addresses() {
+    let (_, required) = ports();
+    // TCP over IPv4: 192.0.2.1:56324 to 198.51.100.1:443
+    let header = v2_header(1, 0x11, b"\xc0\x00\x02\x01\xc6\x33\x64\x01\xdc\x04\x01\xbb");
+    assert_eq!(addresses(required, &[&header, REQUEST]).0, "192.0.2.1:56324");
+}
+
+#[test]
+fn v2_local_command_has_no_addresses() {
+    let (_, required) = ports();
+    let header = v2_header(0, 0x00, b"");
+    assert_eq!(addresses(required, &[&header, REQUEST]).0, "-");
+}
+
+#[test]
+fn header_split_across_reads() {
+    let (_, required) = ports();
+    let header = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+    let parts: [&[u8]; 4] = [&header[..3], &header[3..20], &header[20..], REQUEST];
+    assert_eq!(addresses(required, &parts).0, "192.0.2.1:56324");
+
+    let header = v2_header(1, 0x11, b"\xc0\x00\x02\x01\xc6\x33\x64\x01\xdc\x04\x01\xbb");
+    let parts: [&[u8]; 4] = [&header[..5], &header[5..14], &header[14..], REQUEST];
+    assert_eq!(addresses(required, &parts).0, "192.0.2.1:56324");
+}
+
+#[test]
+fn optional_header_may_be_left_out() {
+    let (optional, _) = ports();
+    assert_eq!(addresses(optional, &[REQUEST]).0, "-");
+}
+
+#[test]
+fn required_header_cannot_be_left_out() {
+    let (_, required) = ports();
+    let mut stream = connect(required);
+    stream.write_all(REQUEST).unwrap();
+    closed_without_response(stream);
+}
+
+#[test]
+fn malformed_headers_close_the_connection() {
+    let (optional, required) = ports();
+    for port in [optional, required] {
+        for header in [
+            &b"PROXY TCP4 not-an-address 198.51.100.1 56324 443\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 056324 443\r\n",
+            b"PROXY TCP4 192.0.2.1\r\n",
+            &v2_header(1, 0x11, b"\xc0\x00\x02\x01"),
+            &v2_header(3, 0x11, b"\xc0\x00\x02\x01\xc6\x33\x64\x01\xdc\x04\x01\xbb"),
+        ] {
+            let mut stream = connect(port);
+            stream.write_all(header).unwrap();
+            // never seen by the HTTP parser
+            stream.write_all(REQUEST).unwrap();
+            closed_without_response(stream);
+        }
+    }
+}
+
+#[test]
+fn truncated_headers_close_the_connection() {
+    let (optional, required) = ports();
+    let v2 = v2_header(1, 0x11, b"\xc0\x00\x02\x01\xc6\x33\x64\x01\xdc\x04\x01\xbb");
+    for port in [optional, required] {
+        for header in [&b"PROXY TCP4 192.0.2.1 198.51"[..], &v2[..20]] {
+            let mut stream = connect(port);
+            stream.write_all(header).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            closed_without_response(stream);
+        }
+    }
+}
@@ -0,0 +1,430 @@
+//! TLS termination, with certificates generated for the run.
+#![cfg(feature = "tls")]
+
+mod common;
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use http_lolo::{HttpServer, Request, ResponseWriter};
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    Issuer, KeyPair,
+};
+use rustls::client::WantsClientCert;
+use rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, ConfigBuilder, RootCertStore, StreamOwned};
+
+use common::*;
+
+/// A certificate and its key, written as PEM files.
+struct Identity {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    der: Vec<u8>,
+    key_der: Vec<u8>,
+}
+
+fn files_dir() -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"))
+        .join(format!("tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn write_identity(file: &str, cert: &rcgen::Certificate, key: &KeyPair) -> Identity {
+    let dir = files_dir();
+    let identity = Identity {
+        cert_path: dir.join(format!("{}.pem", file)),
+        key_path: dir.join(format!("{}.key", file)),
+        der: cert.der().to_vec(),
+        key_der: key.serialize_der(),
+    };
+    std::fs::write(&identity.cert_path, cert.pem()).unwrap();
+    std::fs::write(&identity.key_path, key.serialize_pem()).unwrap();
+    identity
+}
+
+/// A self-signed certificate for `names`, its files named after `file`.
+fn self_signed(file: &str, names: &[&str]) -> Identity {
+    let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+    let rcgen::CertifiedKey { cert, signing_key } =
+        rcgen::generate_simple_self_signed(names).unwrap();
+    write_identity(file, &cert, &signing_key)
+}
+
+/// An authority for client certificates.
+fn authority(file: &str) -> (Identity, Issuer<'static, KeyPair>) {
+    let mut params = CertificateParams::new(Vec::new()).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, "clients");
+    let key = KeyPair::generate().unwrap();
+    let cert = params.self_signed(&key).unwrap();
+    (write_identity(file, &cert, &key), Issuer::new(params, key))
+}
+
+/// A client certificate for `name` issued by `issuer`.
+fn client_identity(file: &str, name: &str, issuer: &Issuer<'static, KeyPair>) -> Identity {
+    let mut params = CertificateParams::new(vec![format!("{}.clients.test", name)]).unwrap();
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::OrganizationName, "Example");
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.extended_key_usages.push(ExtendedKeyUsagePurpose::ClientAuth);
+    let key = KeyPair::generate().unwrap();
+    let cert = params.signed_by(&key, issuer).unwrap();
+    write_identity(file, &cert, &key)
+}
+
+struct Setup {
+    server: HttpServer,
+    port: u16,
+    // also valid for `fallback.test` and 127.0.0.1
+    default: Identity,
+    a: Identity,
+    b: Identity,
+    wildcard: Identity,
+    // servers asking for a certificate from `clients`
+    mtls_required: u16,
+    mtls_optional: u16,
+    client: Identity,
+    // signed by another authority
+    stranger: Identity,
+}
+
+fn setup() -> &'static Setup {
+    static SETUP: OnceLock<Setup> = OnceLock::new();
+    SETUP.get_or_init(|| {
+        let default = self_signed("default", &["localhost", "fallback.test", "127.0.0.1"]);
+        let a = self_signed("a", &["a.test"]);
+        let b = self_signed("b", &["b.test"]);
+        let wildcard = self_signed("wildcard", &["*.wild.test"]);
+        let server = HttpServer::new();
+        server.handle_route(
+            "/",
+            Box::new(|_: &mut Request, w: &mut ResponseWriter| w.write_string("hello")),
+        );
+        server.handle_route(
+            "/upgrade",
+            Box::new(|_: &mut Request, w: &mut ResponseWriter| w.upgrade("echo", |_| {})),
+        );
+        let port = free_port();
+        server
+            .listen_tls(
+                &format!("127.0.0.1:{}", port),
+                &default.cert_path,
+                &default.key_path,
+            )
+            .unwrap();
+        server.add_certificate("a.test", &a.cert_path, &a.key_path).unwrap();
+        server.add_certificate("B.test.", &b.cert_path, &b.key_path).unwrap();
+        server
+            .add_certificate("*.wild.test", &wildcard.cert_path, &wildcard.key_path)
+            .unwrap();
+
+        let (clients, issuer) = authority("clients");
+        let client = client_identity("client", "billing", &issuer);
+        let (_, other_issuer) = authority("other-clients");
+        let stranger = client_identity("stranger", "billing", &other_issuer);
+        let mut mtls_ports = Vec::new();
+        for required in [true, false] {
+            let server = HttpServer::new();
+            server
+                .verify_client_certificates(&clients.cert_path, required)
+                .unwrap();
+            server.handle_route(
+                "/whoami",
+                Box::new(|r: &mut Request, w: &mut ResponseWriter| {
+                    let who = match r.client_certificate() {
+                        Some(cert) => format!("{} {:?}", cert.subject, cert.alt_names),
+                        None => "anonymous".to_string(),
+                    };
+                    w.write_string(&who);
+                }),
+            );
+            let port = free_port();
+            server
+                .listen_tls(
+                    &format!("127.0.0.1:{}", port),
+                    &default.cert_path,
+                    &default.key_path,
+                )
+                .unwrap();
+            mtls_ports.push(port);
+        }
+
+        thread::spawn(HttpServer::run_all);
+        Setup {
+            server,
+            port,
+            default,
+            a,
+            b,
+            wildcard,
+            mtls_required: mtls_ports[0],
+            mtls_optional: mtls_ports[1],
+            client,
+            stranger,
+        }
+    })
+}
+
+fn client_builder(trusted: &[&[u8]]) -> ConfigBuilder<ClientConfig, WantsClientCert> {
+    let mut roots = RootCertStore::empty();
+    for der in trusted {
+        roots.add(CertificateDer::from(der.to_vec())).unwrap();
+    }
+    ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+}
+
+fn client_config(trusted: &[&[u8]], alpn: &[&[u8]]) -> Arc<ClientConfig> {
+    let mut config = client_builder(trusted).with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    Arc::new(config)
+}
+
+/// Trusts the default certificate and authenticates as `identity`.
+fn mtls_config(setup: &Setup, identity: Option<&Identity>) -> Arc<ClientConfig> {
+    let builder = client_builder(&[&setup.default.der]);
+    let config = match identity {
+        Some(identity) => builder
+            .with_client_auth_cert(
+                vec![CertificateDer::from(identity.der.clone())],
+                PrivatePkcs8KeyDer::from(identity.key_der.clone()).into(),
+            )
+            .unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+    Arc::new(config)
+}
+
+type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Completes a handshake with `server_name` as SNI.
+fn handshake(port: u16, server_name: &str, config: Arc<ClientConfig>) -> io::Result<TlsStream> {
+    let socket = connect(port);
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let name = ServerName::try_from(server_name.to_string()).unwrap();
+    let conn = ClientConnection::new(config, name).map_err(io::Error::other)?;
+    let mut stream = StreamOwned::new(conn, socket);
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+    Ok(stream)
+}
+
+/// The certificate the server presented.
+fn served_certificate(stream: &TlsStream) -> Vec<u8> {
+    stream.conn.peer_certificates().unwrap()[0].to_vec()
+}
+
+/// Sends a GET on `stream`, failing when the server ends the session
+/// instead of answering.
+fn request(mut stream: TlsStream, path: &str) -> io::Result<Response> {
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)?;
+    let mut raw = Vec::new();
+    match stream.read_to_end(&mut raw) {
+        Ok(_) => {}
+        // the response is complete, only the close_notify is missing
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof && !raw.is_empty() => {}
+        Err(e) => return Err(e),
+    }
+    if raw.is_empty() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Response::parse(&raw))
+}
+
+fn get_over(stream: TlsStream, path: &str) -> Response {
+    request(stream, path).unwrap()
+}
+
+/// With TLS 1.3 a refused client certificate only shows once the client
+/// reads, so the whole request is tried.
+fn try_get(port: u16, config: Arc<ClientConfig>, path: &str) -> io::Result<Response> {
+    request(handshake(port, "localhost", config)?, path)
+}
+
+#[test]
+fn request_over_tls() {
+    let setup = setup();
+    let config = client_config(&[&setup.default.der], &[b"http/1.1"]);
+    let stream = handshake(setup.port, "localhost", config).unwrap();
+    assert_eq!(stream.conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+    let response = get_over(stream, "/");
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"hello");
+}
+
+#[test]
+fn tls_connections_are_not_upgraded() {
+    let setup = setup();
+    let config = client_config(&[&setup.default.der], &[b"http/1.1"]);
+    let response = get_over(handshake(setup.port, "localhost", config).unwrap(), "/upgrade");
+    assert_eq!(response.status, 501);
+    assert_eq!(response.header("Upgrade"), None);
+}
+
+#[test]
+fn h2_is_offered_through_alpn() {
+    let setup = setup();
+    let config = client_config(&[&setup.default.der], &[b"h2", b"http/1.1"]);
+    let stream = handshake(setup.port, "localhost", config).unwrap();
+    assert_eq!(stream.conn.alpn_protocol(), Some(&b"h2"[..]));
+}
+
+#[test]
+fn untrusted_certificate_fails_the_handshake() {
+    let setup = setup();
+    let other = self_signed("other", &["localhost"]);
+    let config = client_config(&[&other.der], &[]);
+    assert!(handshake(setup.port, "localhost", config).is_err());
+}
+
+#[test]
+fn listen_tls_reports_unusable_files() {
+    let setup = setup();
+    let server = HttpServer::new();
+    let addr = format!("127.0.0.1:{}", free_port());
+
+    let missing = files_dir().join("missing.pem");
+    let err = server
+        .listen_tls(&addr, &missing, &setup.default.key_path)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+    let garbage = files_dir().join("garbage.pem");
+    std::fs::write(&garbage, "not a certificate").unwrap();
+    let err = server
+        .listen_tls(&addr, &garbage, &setup.default.key_path)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+    // a key that doesn't go with the certificate
+    let other = self_signed("mismatch", &["localhost"]);
+    let err = server
+        .listen_tls(&addr, &setup.default.cert_path, &other.key_path)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn plaintext_on_a_tls_port_is_dropped() {
+    let setup = setup();
+    let mut socket = connect(setup.port);
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut received = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        match socket.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => received.extend_from_slice(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => break,
+            Err(e) => panic!("connection left open: {}", e),
+        }
+    }
+    // at most a TLS alert, never an HTTP response
+    assert!(!received.starts_with(b"HTTP/"), "{:?}", received);
+
+    let config = client_config(&[&setup.default.der], &[]);
+    let response = get_over(handshake(setup.port, "localhost", config).unwrap(), "/");
+    assert_eq!(response.status, 200);
+}
+
+#[test]
+fn sni_picks_the_certificate_of_the_name() {
+    let setup = setup();
+    let names = [
+        ("a.test", &setup.a),
+        // registered as `B.test.`
+        ("b.test", &setup.b),
+        ("x.wild.test", &setup.wildcard),
+    ];
+    for (name, identity) in names {
+        let config = client_config(&[&identity.der], &[]);
+        let stream = handshake(setup.port, name, config).unwrap();
+        assert_eq!(served_certificate(&stream), identity.der, "{}", name);
+        assert_eq!(get_over(stream, "/").status, 200);
+    }
+}
+
+#[test]
+fn unknown_or_missing_name_gets_the_default_certificate() {
+    let setup = setup();
+    let config = client_config(&[&setup.default.der], &[]);
+    // also not covered by the wildcard, which spans a single label
+    for name in ["fallback.test", "127.0.0.1"] {
+        let stream = handshake(setup.port, name, config.clone()).unwrap();
+        assert_eq!(served_certificate(&stream), setup.default.der, "{}", name);
+    }
+    let config = client_config(&[&setup.wildcard.der], &[]);
+    assert!(handshake(setup.port, "a.b.wild.test", config).is_err());
+}
+
+#[test]
+fn reload_serves_the_rewritten_certificate() {
+    let setup = setup();
+    let first = self_signed("reload", &["reload.test"]);
+    setup
+        .server
+        .add_certificate("reload.test", &first.cert_path, &first.key_path)
+        .unwrap();
+    let trusted = |identity: &Identity| client_config(&[&identity.der], &[]);
+    let stream = handshake(setup.port, "reload.test", trusted(&first)).unwrap();
+    assert_eq!(served_certificate(&stream), first.der);
+
+    // the same files with a new certificate, only served once reloaded
+    let second = self_signed("reload", &["reload.test"]);
+    let stream = handshake(setup.port, "reload.test", trusted(&first)).unwrap();
+    assert_eq!(served_certificate(&stream), first.der);
+    setup.server.reload_certificates().unwrap();
+    let stream = handshake(setup.port, "reload.test", trusted(&second)).unwrap();
+    assert_eq!(served_certificate(&stream), second.der);
+
+    // a broken file leaves the loaded certificates alone
+    std::fs::write(&second.cert_path, "not a certificate").unwrap();
+    assert!(setup.server.reload_certificates().is_err());
+    let stream = handshake(setup.port, "reload.test", trusted(&second)).unwrap();
+    assert_eq!(served_certificate(&stream), second.der);
+}
+
+#[test]
+fn client_certificate_is_accepted() {
+    let setup = setup();
+    for port in [setup.mtls_required, setup.mtls_optional] {
+        let response = try_get(port, mtls_config(setup, Some(&setup.client)), "/whoami").unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            "CN=billing,O=Example [Dns(\"billing.clients.test\")]"
+        );
+    }
+}
+
+#[test]
+fn certificate_of_another_authority_is_rejected() {
+    let setup = setup();
+    for port in [setup.mtls_required, setup.mtls_optional] {
+        let config = mtls_config(setup, Some(&setup.stranger));
+        assert!(try_get(port, config, "/whoami").is_err());
+    }
+}
+
+#[test]
+fn missing_client_certificate() {
+    let setup = setup();
+    assert!(try_get(setup.mtls_required, mtls_config(setup, None), "/whoami").is_err());
+
+    let response = try_get(setup.mtls_optional, mtls_config(setup, None), "/whoami").unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"anonymous");
+}